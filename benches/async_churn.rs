@@ -0,0 +1,82 @@
+// Counts the heap allocations made by many short-lived async acquisitions
+// that each have to wait once, and times them.
+//
+// Run with `cargo bench --bench async_churn`.
+//
+// Measured on one machine, five interleaved runs each: with a waker list
+// that was reallocated on every wakeup, with that list recycled through a
+// spare buffer behind its own lock, and with the intrusive list of waker
+// nodes pinned in the futures:
+//
+//   reallocated: 190-250 ns/iter, 1.00 allocations/iter
+//   recycled:    236-276 ns/iter, 0.00 allocations/iter
+//   intrusive:   204-241 ns/iter, 0.00 allocations/iter
+//
+// The intrusive list waits without allocating, like the recycled one, and
+// takes no lock but the semaphore's, which brings the latency back to where
+// it was before.
+
+extern crate multi_semaphore;
+
+use multi_semaphore::Semaphore;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1_000_000;
+
+/// The system allocator, counting every allocation.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn main() {
+    let sem = Semaphore::new(0);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut access = sem.access_async();
+        // SAFETY: `access` is shadowed, so it can't be moved again.
+        let mut access = unsafe { Pin::new_unchecked(&mut access) };
+        assert!(access.as_mut().poll(&mut cx).is_pending());
+        sem.release();
+        match access.as_mut().poll(&mut cx) {
+            Poll::Ready(guard) => guard.leak(),
+            Poll::Pending => panic!("the release should have let the acquisition through"),
+        };
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "waiting async acquisition {:>8.2} ns/iter {:>6.2} allocations/iter",
+        elapsed.as_nanos() as f64 / f64::from(ITERATIONS),
+        allocations as f64 / f64::from(ITERATIONS)
+    );
+}
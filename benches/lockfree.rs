@@ -0,0 +1,46 @@
+// Compares the uncontended cost of the default and lock-free semaphores.
+//
+// Run with `cargo bench --bench lockfree`.
+
+extern crate multi_semaphore;
+
+use multi_semaphore::Semaphore;
+use std::hint::black_box;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 5_000_000;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<36} {:>8.2} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+    );
+}
+
+fn main() {
+    let sem = Semaphore::new(1);
+    bench("Semaphore acquire/release", || {
+        black_box(&sem).acquire();
+        black_box(&sem).release();
+    });
+
+    let sem = Semaphore::new_lockfree(1);
+    bench("LockFreeSemaphore acquire/release", || {
+        black_box(&sem).acquire();
+        black_box(&sem).release();
+    });
+
+    let sem = Semaphore::new(1);
+    bench("Semaphore access", || drop(black_box(&sem).access()));
+
+    let sem = Semaphore::new_lockfree(1);
+    bench("LockFreeSemaphore access", || {
+        drop(black_box(&sem).access())
+    });
+}
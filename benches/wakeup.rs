@@ -0,0 +1,118 @@
+// Measures how long a release takes to reach the waiters it can satisfy
+// when many more are blocked, with budgeted wakeups and with every waiter
+// woken on each release, like a static `notify_all` policy. Each runs with
+// waiters that all want one resource and with a mix of single and larger
+// requests.
+//
+// With uniform waiters, budgeted wakeups wake one thread per resource
+// released, which is exactly a static `notify_one` policy, so that is what
+// the uniform budgeted numbers measure. A static `notify_one` can't be run on
+// the mixed workload: a wakeup given to a waiter wanting more than is
+// available is lost, and the waiters that could have proceeded are stranded.
+//
+// Run with `cargo bench --bench wakeup`.
+
+extern crate multi_semaphore;
+
+use multi_semaphore::{CondvarParker, Parker, Semaphore, SemaphoreState};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LockResult, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WAITERS: usize = 100;
+const RELEASE: usize = 10;
+const ROUNDS: u32 = 500;
+
+/// Wakes every parked thread even when asked for only a few, like a plain
+/// `notify_all` on every release.
+struct WakeAll(CondvarParker);
+
+impl Parker for WakeAll {
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        self.0.park(lock, guard, timeout)
+    }
+
+    fn unpark_all(&self) {
+        self.0.unpark_all();
+    }
+}
+
+/// Runs `WAITERS` threads acquiring `amount(i)` resources in a loop, the
+/// `i`th thread's amount, and times how long each release of `RELEASE`
+/// resources takes to be used up.
+fn bench(name: &str, sem: Semaphore, amount: fn(usize) -> usize) {
+    let sem = Arc::new(sem);
+    let done = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let threads: Vec<_> = (0..WAITERS)
+        .map(|i| {
+            let (sem, done, stop) = (sem.clone(), done.clone(), stop.clone());
+            let amount = amount(i);
+            thread::spawn(move || loop {
+                sem.acquire_many(amount as isize);
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                done.fetch_add(amount, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    // Let every thread block before the first round.
+    thread::sleep(Duration::from_millis(100));
+
+    // With single-resource waiters always blocked, every round's release is
+    // used up entirely.
+    let mut total = Duration::from_secs(0);
+    for round in 1..=ROUNDS {
+        let start = Instant::now();
+        sem.release_many(RELEASE as isize);
+        while done.load(Ordering::SeqCst) < round as usize * RELEASE {
+            thread::yield_now();
+        }
+        total += start.elapsed();
+    }
+    println!(
+        "{:<44} {:>8.2} us/release",
+        name,
+        total.as_secs_f64() * 1e6 / f64::from(ROUNDS)
+    );
+
+    stop.store(true, Ordering::SeqCst);
+    sem.release_many((WAITERS * 3) as isize);
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+fn uniform(_: usize) -> usize {
+    1
+}
+
+/// Every other waiter wants three resources.
+fn mixed(i: usize) -> usize {
+    match i % 2 {
+        0 => 1,
+        _ => 3,
+    }
+}
+
+fn main() {
+    for &(workload, amount) in &[("uniform", uniform as fn(usize) -> usize), ("mixed", mixed)] {
+        bench(
+            &format!("{}: budgeted wakeups", workload),
+            Semaphore::new(0),
+            amount,
+        );
+        bench(
+            &format!("{}: wake every waiter", workload),
+            Semaphore::with_parker(0, WakeAll(CondvarParker::new())),
+            amount,
+        );
+    }
+}
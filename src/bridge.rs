@@ -0,0 +1,96 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for programs that use both this crate and Tokio's semaphore, for
+//! example while migrating from one to the other.
+//!
+//! The two semaphores don't share state, so a limit has to live in one of
+//! them. During a migration, keep the limit in whichever semaphore most
+//! acquirers use:
+//!
+//! * If it's Tokio's, synchronous code can wait on it with
+//!   `blocking_acquire`, without a runtime.
+//! * If it's ours, async code can wait on it with `Semaphore::access_async`,
+//!   which works with any executor, Tokio included.
+//!
+//! Once the other side is gone, `Semaphore::from_tokio_permits` creates a
+//! semaphore starting with the permits a Tokio semaphore has left, to take
+//! over from it.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore as TokioSemaphore};
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Acquires `permits` permits of a Tokio semaphore, blocking the current
+/// thread until it can do so.
+///
+/// This doesn't need a Tokio runtime, so it's safe to call from synchronous
+/// code running alongside one. Like any blocking call, it must not be used
+/// from async code, where it would block a runtime worker.
+///
+/// Returns an error if the Tokio semaphore is closed.
+pub fn blocking_acquire(
+    sem: &Arc<TokioSemaphore>,
+    permits: u32,
+) -> Result<OwnedSemaphorePermit, AcquireError> {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut acquire = Box::pin(Arc::clone(sem).acquire_many_owned(permits));
+    loop {
+        if let Poll::Ready(result) = Pin::as_mut(&mut acquire).poll(&mut cx) {
+            return result;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use tokio::sync::Semaphore as TokioSemaphore;
+
+    use super::blocking_acquire;
+    use Semaphore;
+
+    #[test]
+    fn test_blocking_acquire_tokio() {
+        let tokio_sem = Arc::new(TokioSemaphore::new(1));
+        let permit = blocking_acquire(&tokio_sem, 1).unwrap();
+        let sem2 = tokio_sem.clone();
+        let t = thread::spawn(move || blocking_acquire(&sem2, 1).map(drop));
+        drop(permit);
+        t.join().unwrap().unwrap();
+
+        tokio_sem.close();
+        assert!(blocking_acquire(&tokio_sem, 1).is_err());
+    }
+
+    #[test]
+    fn test_from_tokio_permits() {
+        let tokio_sem = TokioSemaphore::new(3);
+        let _permit = tokio_sem.try_acquire().unwrap();
+        assert_eq!(
+            Semaphore::from_tokio_permits(&tokio_sem).available_permits(),
+            2
+        );
+    }
+}
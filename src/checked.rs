@@ -0,0 +1,205 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+use sync::MutexGuard;
+use {check_amount, Semaphore, SemaphoreState};
+
+/// A semaphore whose methods report a poisoned lock as an error instead of
+/// panicking.
+///
+/// The semaphore's lock is only poisoned when a thread panics while holding
+/// it, which this crate never does itself; it takes a panic in a closure
+/// that runs under the lock, such as one passed to `Semaphore::with_count`.
+/// `Semaphore` then panics on every later use of the lock, while these
+/// methods return `Err` so the caller can decide what to do.
+///
+/// This wraps the same state as the `Semaphore` it was made from, which
+/// keeps its panicking interface. There are no guards here, since dropping
+/// a guard has no way to report an error: release what was acquired with
+/// `release_many`. A thread already blocked in an acquisition when the lock
+/// is poisoned still panics once it wakes.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::CheckedSemaphore;
+///
+/// let sem = CheckedSemaphore::new(2);
+/// sem.acquire_many(2).unwrap();
+/// assert!(!sem.try_acquire().unwrap());
+/// sem.release_many(2).unwrap();
+/// ```
+pub struct CheckedSemaphore {
+    inner: Semaphore,
+}
+
+impl CheckedSemaphore {
+    /// Creates a new semaphore with the initial count specified, like
+    /// `Semaphore::new`.
+    pub fn new(count: isize) -> CheckedSemaphore {
+        CheckedSemaphore {
+            inner: Semaphore::new(count),
+        }
+    }
+
+    /// Returns the underlying semaphore, with its panicking interface.
+    pub fn as_semaphore(&self) -> &Semaphore {
+        &self.inner
+    }
+
+    /// Returns the underlying semaphore.
+    pub fn into_inner(self) -> Semaphore {
+        self.inner
+    }
+
+    /// Returns the number of resources currently available. This never takes
+    /// the lock, so it can't fail.
+    pub fn available_permits(&self) -> isize {
+        self.inner.available_permits()
+    }
+
+    /// Acquires a resource like `Semaphore::acquire`.
+    pub fn acquire(&self) -> Result<(), PoisonError<()>> {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `amount` resources like `Semaphore::acquire_many`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `Semaphore::acquire_many`.
+    pub fn acquire_many(&self, amount: isize) -> Result<(), PoisonError<()>> {
+        self.acquire_many_until(amount, None).map(|_| ())
+    }
+
+    /// Acquires `amount` resources like `Semaphore::acquire_many_timeout`,
+    /// returning whether they were acquired in time.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `Semaphore::acquire_many`.
+    pub fn acquire_many_timeout(
+        &self,
+        amount: isize,
+        timeout: Duration,
+    ) -> Result<bool, PoisonError<()>> {
+        let deadline = self.inner.inner.now().checked_add(timeout);
+        self.acquire_many_until(amount, deadline)
+    }
+
+    /// Acquires a resource if one is available, returning whether it was,
+    /// like `Semaphore::try_acquire`.
+    pub fn try_acquire(&self) -> Result<bool, PoisonError<()>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Acquires `amount` resources if they are available, returning whether
+    /// they were, like `Semaphore::try_acquire_many`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative.
+    pub fn try_acquire_many(&self, amount: isize) -> Result<bool, PoisonError<()>> {
+        check_amount(amount);
+        let mut state = self.lock()?;
+        if state.closed {
+            return Ok(false);
+        }
+        if amount == 0 {
+            return Ok(true);
+        }
+        if !state.may_take(amount, None) {
+            return Ok(false);
+        }
+        state.count -= amount;
+        self.inner.inner.unlock(state);
+        Ok(true)
+    }
+
+    /// Releases a resource like `Semaphore::release`.
+    pub fn release(&self) -> Result<(), PoisonError<()>> {
+        self.release_many(1)
+    }
+
+    /// Releases `amount` resources like `Semaphore::release_many`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count would overflow.
+    pub fn release_many(&self, amount: isize) -> Result<(), PoisonError<()>> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let state = self.lock()?;
+        self.inner.inner.release_locked(state, amount);
+        Ok(())
+    }
+
+    fn acquire_many_until(
+        &self,
+        amount: isize,
+        deadline: Option<Instant>,
+    ) -> Result<bool, PoisonError<()>> {
+        check_amount(amount);
+        if amount == 0 {
+            return Ok(true);
+        }
+        let state = self.inner.inner.check_max(self.lock()?, amount);
+        Ok(self
+            .inner
+            .inner
+            .take_or_wait(state, amount, deadline, |_| false)
+            .is_some())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SemaphoreState>, PoisonError<()>> {
+        self.inner
+            .inner
+            .lock
+            .lock()
+            .map_err(|_| PoisonError::new(()))
+    }
+}
+
+impl From<Semaphore> for CheckedSemaphore {
+    fn from(inner: Semaphore) -> CheckedSemaphore {
+        CheckedSemaphore { inner }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CheckedSemaphore;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_checked_poisoned() {
+        let sem = Arc::new(CheckedSemaphore::new(1));
+        assert!(sem.try_acquire().unwrap());
+        assert!(!sem
+            .acquire_many_timeout(1, Duration::from_millis(1))
+            .unwrap());
+        sem.release().unwrap();
+
+        let sem2 = sem.clone();
+        let failed = thread::spawn(move || {
+            sem2.as_semaphore()
+                .with_count(|_| panic!("panicked under the lock"))
+        });
+        assert!(failed.join().is_err());
+        assert!(sem.acquire().is_err());
+        assert!(sem.try_acquire_many(1).is_err());
+        assert!(sem.release().is_err());
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
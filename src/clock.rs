@@ -0,0 +1,146 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::LockResult;
+use std::time::{Duration, Instant};
+
+use sync::{Mutex, MutexGuard};
+use {Parker, SemaphoreState};
+
+/// The source of time for a semaphore's timeouts.
+///
+/// Timed acquisitions read the time with `now` to work out their deadline
+/// and how long is left, and wait with `park_timeout`. Every deadline a
+/// semaphore is given, including those of a `DeadlineScope`, is compared with
+/// its clock's time.
+///
+/// Semaphores use `SystemClock` unless created with `Semaphore::with_clock`.
+/// Replacing it is meant for tests: a mock clock can make a timeout expire as
+/// soon as it's waited for, so timeouts can be tested without real sleeps.
+///
+/// ```
+/// use multi_semaphore::{Clock, Parker, Semaphore, SemaphoreState};
+/// use std::sync::{LockResult, Mutex, MutexGuard};
+/// use std::time::{Duration, Instant};
+///
+/// /// A clock that skips ahead by however long anyone waits.
+/// struct Skipping(Mutex<Instant>);
+///
+/// impl Clock for Skipping {
+///     fn now(&self) -> Instant {
+///         *self.0.lock().unwrap()
+///     }
+///
+///     fn park_timeout<'a>(
+///         &self,
+///         _parker: &dyn Parker,
+///         lock: &'a Mutex<SemaphoreState>,
+///         guard: MutexGuard<'a, SemaphoreState>,
+///         timeout: Duration,
+///     ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+///         drop(guard);
+///         *self.0.lock().unwrap() += timeout;
+///         lock.lock()
+///     }
+/// }
+///
+/// let sem = Semaphore::with_clock(0, Skipping(Mutex::new(Instant::now())));
+/// // Returns straight away, an hour later by the semaphore's clock.
+/// assert!(!sem.acquire_timeout(Duration::from_secs(3600)));
+/// ```
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Parks the current thread with `parker` until it is unparked or
+    /// `timeout` has passed by this clock, like `Parker::park`.
+    ///
+    /// Returning early is always allowed: callers check `now` again and park
+    /// for whatever is left. The default parks for `timeout` of real time.
+    fn park_timeout<'a>(
+        &self,
+        parker: &dyn Parker,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Duration,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        parker.park(lock, guard, Some(timeout))
+    }
+}
+
+/// The real clock, read with `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, LockResult, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use {Parker, Semaphore, SemaphoreState};
+
+    /// A clock that only moves when a wait times out, jumping straight to the
+    /// end of it.
+    struct MockClock {
+        now: Mutex<Instant>,
+        waits: AtomicUsize,
+    }
+
+    impl Clock for Arc<MockClock> {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn park_timeout<'a>(
+            &self,
+            _parker: &dyn Parker,
+            lock: &'a Mutex<SemaphoreState>,
+            guard: MutexGuard<'a, SemaphoreState>,
+            timeout: Duration,
+        ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+            drop(guard);
+            self.waits.fetch_add(1, Ordering::SeqCst);
+            *self.now.lock().unwrap() += timeout;
+            lock.lock()
+        }
+    }
+
+    #[test]
+    fn test_mock_clock_timeouts() {
+        let start = Instant::now();
+        let clock = Arc::new(MockClock {
+            now: Mutex::new(start),
+            waits: AtomicUsize::new(0),
+        });
+        let s = Semaphore::with_clock(0, clock.clone());
+
+        let real = Instant::now();
+        assert!(!s.acquire_timeout(Duration::from_secs(3600)));
+        assert!(s.access_timeout(Duration::from_secs(60)).is_none());
+        assert!(real.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.now(), start + Duration::from_secs(3660));
+        assert_eq!(clock.waits.load(Ordering::SeqCst), 2);
+
+        // Waits without a timeout still park for real.
+        let s = Arc::new(s);
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        thread::sleep(Duration::from_millis(10));
+        s.release();
+        t.join().unwrap();
+        assert_eq!(clock.waits.load(Ordering::SeqCst), 2);
+    }
+}
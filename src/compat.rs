@@ -0,0 +1,238 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A semaphore with the interface of `tokio::sync::Semaphore`, so code
+//! written against Tokio's can switch to this crate by changing an import.
+//!
+//! Unlike the `tokio` feature, this doesn't depend on Tokio: it only maps
+//! Tokio's method names and types onto this crate's `Semaphore`. The
+//! semantics differ in a few places:
+//!
+//! * The count is an `isize` underneath and may be negative, for example
+//!   after `Semaphore::from` a semaphore created with a negative count.
+//!   `available_permits` reports a negative count as zero.
+//! * Closing doesn't stop blocking acquisitions through the underlying
+//!   semaphore, apart from `acquire_checked`.
+//! * Waiters are served in no particular order, unless the semaphore was
+//!   created with `Semaphore::new_fair` and converted with `From`.
+//! * `try_acquire` fails with this crate's `TryAcquireError`, whose
+//!   `WouldBlock` is Tokio's `NoPermits`.
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{error, fmt};
+
+use {Access, SemaphoreGuard, TryAcquireError};
+
+/// A counting semaphore with the methods of `tokio::sync::Semaphore`.
+pub struct Semaphore {
+    inner: ::Semaphore,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with `permits` permits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `permits` doesn't fit in an `isize`.
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            inner: ::Semaphore::new(to_count(permits)),
+        }
+    }
+
+    /// Returns the number of permits currently available, or zero if the
+    /// count is negative.
+    pub fn available_permits(&self) -> usize {
+        let count = self.inner.available_permits();
+        if count < 0 {
+            0
+        } else {
+            count as usize
+        }
+    }
+
+    /// Adds `n` permits to the semaphore.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count would overflow.
+    pub fn add_permits(&self, n: usize) {
+        // Tokio allows adding permits to a closed semaphore.
+        self.inner.inner.release_many(to_count(n));
+    }
+
+    /// Waits for a permit, resolving to it once acquired, or to an error
+    /// if the semaphore is closed.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits for `n` permits, resolving to them once acquired, or to an error
+    /// if the semaphore is closed.
+    pub fn acquire_many(&self, n: u32) -> Acquire<'_> {
+        Acquire {
+            sem: self,
+            access: self.inner.access_many_async(to_count(n as usize)),
+        }
+    }
+
+    /// Acquires a permit if one is available.
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Acquires `n` permits if that many are available.
+    pub fn try_acquire_many(&self, n: u32) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.inner
+            .try_access_many_checked(to_count(n as usize))
+            .map(SemaphorePermit::new)
+    }
+
+    /// Closes the semaphore, so acquisitions through this interface fail.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// Returns whether the semaphore has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Returns the underlying semaphore.
+    pub fn into_inner(self) -> ::Semaphore {
+        self.inner
+    }
+}
+
+impl From<::Semaphore> for Semaphore {
+    fn from(inner: ::Semaphore) -> Semaphore {
+        Semaphore { inner }
+    }
+}
+
+fn to_count(permits: usize) -> isize {
+    if permits > isize::MAX as usize {
+        panic!("{} permits don't fit in the semaphore count", permits);
+    }
+    permits as isize
+}
+
+/// Permits acquired from a `Semaphore`, released when dropped.
+#[must_use = "the permit is released immediately if it is not bound to a variable"]
+pub struct SemaphorePermit<'a> {
+    guard: SemaphoreGuard,
+    _sem: PhantomData<&'a Semaphore>,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    fn new(guard: SemaphoreGuard) -> SemaphorePermit<'a> {
+        SemaphorePermit {
+            guard,
+            _sem: PhantomData,
+        }
+    }
+
+    /// Returns how many permits this holds.
+    pub fn num_permits(&self) -> usize {
+        self.guard.amount as usize
+    }
+
+    /// Keeps the permits acquired for good instead of releasing them.
+    pub fn forget(self) {
+        self.guard.leak();
+    }
+}
+
+/// The future returned by `Semaphore::acquire` and `Semaphore::acquire_many`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Acquire<'a> {
+    sem: &'a Semaphore,
+    access: Access,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: `access` is pinned whenever `self` is: it's never moved out
+        // of, and `Acquire` has no `Drop` impl that could move it.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.sem.is_closed() {
+            return Poll::Ready(Err(AcquireError { _private: () }));
+        }
+        unsafe { Pin::new_unchecked(&mut this.access) }
+            .poll(cx)
+            .map(|guard| Ok(SemaphorePermit::new(guard)))
+    }
+}
+
+/// The error an `Acquire` resolves to when the semaphore is closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AcquireError {
+    _private: (),
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("semaphore closed")
+    }
+}
+
+impl error::Error for AcquireError {}
+
+#[cfg(test)]
+mod tests {
+    use super::Semaphore;
+    use future::tests::{block_on, poll_once};
+    use std::task::Poll;
+    use TryAcquireError;
+
+    #[test]
+    fn test_compat_permits() {
+        let sem = Semaphore::new(2);
+        let permit = block_on(sem.acquire()).unwrap();
+        assert_eq!(permit.num_permits(), 1);
+        assert_eq!(sem.available_permits(), 1);
+        assert_eq!(
+            sem.try_acquire_many(2).err(),
+            Some(TryAcquireError::WouldBlock)
+        );
+        drop(permit);
+        sem.try_acquire_many(2).unwrap().forget();
+        assert_eq!(sem.available_permits(), 0);
+        sem.add_permits(1);
+        assert!(sem.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_compat_closed() {
+        let sem = Semaphore::new(0);
+        let mut acquire = Box::pin(sem.acquire());
+        assert!(poll_once(&mut acquire).is_pending());
+        sem.close();
+        sem.add_permits(1);
+        match poll_once(&mut acquire) {
+            Poll::Ready(result) => assert!(result.is_err()),
+            Poll::Pending => panic!("acquire should fail once closed"),
+        }
+        drop(acquire);
+        assert_eq!(sem.available_permits(), 1);
+        assert_eq!(sem.try_acquire().err(), Some(TryAcquireError::Closed));
+    }
+
+    #[test]
+    fn test_compat_negative_count() {
+        let sem = Semaphore::from(::Semaphore::new(-1));
+        assert_eq!(sem.available_permits(), 0);
+        sem.add_permits(2);
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
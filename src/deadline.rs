@@ -0,0 +1,137 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::{Duration, Instant};
+
+use {DeadlineExpired, Semaphore, SemaphoreGuard};
+
+/// A deadline shared by every acquisition made through it, such as all those
+/// serving one request.
+///
+/// Each acquisition waits for at most the time left until the deadline, so
+/// together they can't take longer than the deadline allows, and once it has
+/// passed they fail straight away.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::{DeadlineScope, Semaphore};
+/// use std::time::Duration;
+///
+/// let (db, cache) = (Semaphore::new(1), Semaphore::new(1));
+/// let scope = DeadlineScope::after(Duration::from_secs(1));
+/// let _db = scope.acquire(&db).unwrap();
+/// // Waiting for the cache draws on what is left of the same second.
+/// let _cache = scope.acquire(&cache).unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeadlineScope {
+    /// `None` for a deadline too far off to represent, which is never
+    /// reached.
+    deadline: Option<Instant>,
+}
+
+impl DeadlineScope {
+    /// Creates a scope whose acquisitions must finish by `deadline`.
+    pub fn new(deadline: Instant) -> DeadlineScope {
+        DeadlineScope {
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Creates a scope whose acquisitions must finish within `timeout` from
+    /// now.
+    pub fn after(timeout: Duration) -> DeadlineScope {
+        // A timeout too large to represent is as good as no timeout at all.
+        DeadlineScope {
+            deadline: Instant::now().checked_add(timeout),
+        }
+    }
+
+    /// Returns the deadline, or `None` if it is too far off to represent.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the time left until the deadline, which is zero once it has
+    /// passed, or `None` if the deadline is too far off to represent.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Acquires a resource of `sem`, blocking for at most the time left until
+    /// the deadline, and returns an RAII guard to release it when dropped.
+    ///
+    /// Fails without trying if the deadline has already passed, even if a
+    /// resource is available.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire(&self, sem: &Semaphore) -> Result<SemaphoreGuard, DeadlineExpired> {
+        self.acquire_many(sem, 1)
+    }
+
+    /// Acquires `amount` resources of `sem` like `acquire`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `Semaphore::access_many`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_many(
+        &self,
+        sem: &Semaphore,
+        amount: isize,
+    ) -> Result<SemaphoreGuard, DeadlineExpired> {
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(DeadlineExpired);
+            }
+        }
+        if sem.inner.acquire_many_until(amount, self.deadline) {
+            Ok(SemaphoreGuard::new(&sem.inner, amount))
+        } else {
+            Err(DeadlineExpired)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeadlineScope;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use Semaphore;
+
+    #[test]
+    fn test_deadline_scope_shared_budget() {
+        let s = Semaphore::new(1);
+        let scope = DeadlineScope::after(Duration::from_millis(50));
+        let held = scope.acquire(&s).unwrap();
+
+        let start = Instant::now();
+        assert!(scope.acquire(&s).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        // The deadline has passed, so even an available resource is refused
+        // without waiting.
+        drop(held);
+        thread::sleep(scope.remaining().unwrap());
+        let start = Instant::now();
+        assert!(scope.acquire(&s).is_err());
+        assert!(start.elapsed() < Duration::from_millis(30));
+        assert_eq!(s.available_permits(), 1);
+        assert_eq!(scope.remaining(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_deadline_scope_huge_timeout() {
+        let s = Semaphore::new(1);
+        let scope = DeadlineScope::after(Duration::MAX);
+        assert_eq!(scope.remaining(), None);
+        assert!(scope.acquire(&s).is_ok());
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Diagnostics that are too expensive to leave on outside of debugging.
+
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+
+/// Records which thread created each live guard, and where.
+pub struct Registry {
+    inner: Mutex<Entries>,
+    /// Whether guards and waits on this semaphore are entered in the global
+    /// wait-for graph.
+    detect_deadlocks: AtomicBool,
+    /// The semaphore's name, for deadlock reports.
+    name: Option<&'static str>,
+}
+
+struct Entries {
+    next_id: u64,
+    held: HashMap<u64, Holder>,
+}
+
+struct Holder {
+    thread: ThreadId,
+    backtrace: Arc<Backtrace>,
+    /// Whether the guard was entered in the wait-for graph, which only
+    /// happens once deadlock detection is enabled.
+    in_graph: bool,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            inner: Mutex::new(Entries {
+                next_id: 0,
+                held: HashMap::new(),
+            }),
+            detect_deadlocks: AtomicBool::new(false),
+            name: None,
+        }
+    }
+
+    pub fn set_name(&mut self, name: &'static str) {
+        self.name = Some(name);
+    }
+
+    /// Identifies the semaphore in the wait-for graph. A semaphore can't be
+    /// freed, and its address reused, while it has guards or waiters.
+    fn key(&self) -> usize {
+        self as *const Registry as usize
+    }
+
+    /// Records the current thread and backtrace, returning the id to pass to
+    /// `unregister` when the guard is dropped.
+    pub fn register(&self) -> u64 {
+        let thread = thread::current().id();
+        let in_graph = self.detects_deadlocks();
+        if in_graph {
+            with_graph(|graph| graph.hold(self.key(), self.name, thread));
+        }
+        // Capture outside the lock; walking the stack is slow.
+        let entry = Holder {
+            thread,
+            backtrace: Arc::new(Backtrace::force_capture()),
+            in_graph,
+        };
+        let mut entries = self.inner.lock().unwrap();
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.held.insert(id, entry);
+        id
+    }
+
+    pub fn unregister(&self, id: u64) {
+        let entry = self.inner.lock().unwrap().held.remove(&id);
+        if let Some(Holder {
+            thread,
+            in_graph: true,
+            ..
+        }) = entry
+        {
+            with_graph(|graph| graph.release(self.key(), thread));
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(ThreadId, Arc<Backtrace>)> {
+        let entries = self.inner.lock().unwrap();
+        let mut held: Vec<_> = entries.held.iter().collect();
+        held.sort_by_key(|&(id, _)| *id);
+        held.into_iter()
+            .map(|(_, entry)| (entry.thread, entry.backtrace.clone()))
+            .collect()
+    }
+
+    /// Whether the current thread holds guards and no other thread holds any.
+    pub fn held_only_by_current(&self) -> bool {
+        let me = thread::current().id();
+        let entries = self.inner.lock().unwrap();
+        !entries.held.is_empty() && entries.held.values().all(|entry| entry.thread == me)
+    }
+
+    pub fn enable_deadlock_detection(&self) {
+        self.detect_deadlocks.store(true, Ordering::Relaxed);
+    }
+
+    pub fn detects_deadlocks(&self) -> bool {
+        self.detect_deadlocks.load(Ordering::Relaxed)
+    }
+
+    /// Records that the current thread is about to block on this semaphore
+    /// with no timeout, or returns a report of the cycle it would close.
+    ///
+    /// Must be called with the semaphore's lock held, and again after every
+    /// wakeup, since `woken` forgets the waiters.
+    pub fn wait(&self) -> Result<(), String> {
+        let me = thread::current().id();
+        let key = self.key();
+        let cycle = with_graph(|graph| {
+            graph.name(key, self.name);
+            let cycle = graph.find_cycle(me, key);
+            if cycle.is_none() {
+                graph.waiting.insert(me, key);
+            }
+            cycle.map(|cycle| {
+                let mut report = String::from("deadlock detected:");
+                let mut waiter = me;
+                for (sem, holder) in cycle {
+                    let sem = match graph.names.get(&sem) {
+                        Some(name) => format!("the {} semaphore ({:#x})", name, sem),
+                        None => format!("semaphore {:#x}", sem),
+                    };
+                    report += &format!("\n  {:?} waits on {}, held by {:?}", waiter, sem, holder);
+                    waiter = holder;
+                }
+                report
+            })
+        });
+        if let Some(report) = cycle {
+            return Err(report);
+        }
+        Ok(())
+    }
+
+    /// Records that the current thread is no longer blocked.
+    pub fn stop_waiting(&self) {
+        let me = thread::current().id();
+        with_graph(|graph| graph.waiting.remove(&me));
+    }
+
+    /// Forgets every thread waiting on this semaphore, because they're about
+    /// to be woken and may no longer be stuck. Must be called with the
+    /// semaphore's lock held, so they can't have re-registered yet.
+    pub fn woken(&self) {
+        if !self.detects_deadlocks() {
+            return;
+        }
+        let key = self.key();
+        with_graph(|graph| graph.waiting.retain(|_, &mut sem| sem != key));
+    }
+}
+
+/// Which semaphores every thread holds guards from and is blocked on, across
+/// all semaphores with deadlock detection enabled.
+struct WaitGraph {
+    /// The semaphore each blocked thread is waiting on.
+    waiting: HashMap<ThreadId, usize>,
+    /// How many guards each thread holds, by semaphore.
+    held: HashMap<usize, HashMap<ThreadId, usize>>,
+    /// The names of the semaphores in the graph that have one.
+    names: HashMap<usize, &'static str>,
+}
+
+static GRAPH: Mutex<Option<WaitGraph>> = Mutex::new(None);
+
+/// Runs `f` on the wait-for graph, creating it the first time.
+fn with_graph<R, F: FnOnce(&mut WaitGraph) -> R>(f: F) -> R {
+    // A panic reporting a deadlock never happens with this lock held.
+    let mut graph = GRAPH.lock().unwrap();
+    f(graph.get_or_insert_with(|| WaitGraph {
+        waiting: HashMap::new(),
+        held: HashMap::new(),
+        names: HashMap::new(),
+    }))
+}
+
+impl WaitGraph {
+    fn hold(&mut self, sem: usize, name: Option<&'static str>, thread: ThreadId) {
+        self.name(sem, name);
+        *self.held.entry(sem).or_default().entry(thread).or_insert(0) += 1;
+    }
+
+    fn release(&mut self, sem: usize, thread: ThreadId) {
+        let holders = self.held.get_mut(&sem).unwrap();
+        let count = holders.get_mut(&thread).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            holders.remove(&thread);
+            if holders.is_empty() {
+                self.held.remove(&sem);
+                self.names.remove(&sem);
+            }
+        }
+    }
+
+    /// Records the name of the semaphore at `sem`. Addresses are reused, so a
+    /// semaphore without a name has to clear whatever an earlier one left.
+    fn name(&mut self, sem: usize, name: Option<&'static str>) {
+        match name {
+            Some(name) => self.names.insert(sem, name),
+            None => self.names.remove(&sem),
+        };
+    }
+
+    /// Finds whether `me` blocking on `start` would leave no way forward:
+    /// every thread holding `start`, and transitively every thread holding
+    /// what those are blocked on, is blocked, and `me` is among them.
+    ///
+    /// A semaphore held by a thread that isn't blocked can still be released,
+    /// so that alone rules out a deadlock. Returns the cycle as the semaphores
+    /// along it, each with the thread holding it that the next one waits for.
+    fn find_cycle(&self, me: ThreadId, start: usize) -> Option<Vec<(usize, ThreadId)>> {
+        // For each semaphore reached, the one before it and the thread holding
+        // that one which waits on it.
+        let mut came_from = HashMap::new();
+        let mut seen = HashSet::new();
+        seen.insert(start);
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        let mut end = None;
+        while let Some(sem) = queue.pop_front() {
+            for &holder in self.held.get(&sem).into_iter().flat_map(|h| h.keys()) {
+                if holder == me {
+                    end.get_or_insert(sem);
+                    continue;
+                }
+                let next = *self.waiting.get(&holder)?;
+                if seen.insert(next) {
+                    came_from.insert(next, (sem, holder));
+                    queue.push_back(next);
+                }
+            }
+        }
+        let mut sem = end?;
+        let mut cycle = vec![(sem, me)];
+        while sem != start {
+            let (prev, holder) = came_from[&sem];
+            cycle.push((prev, holder));
+            sem = prev;
+        }
+        cycle.reverse();
+        Some(cycle)
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// The error returned by the checked acquisition methods such as
+/// `Semaphore::try_acquire_checked` and `Semaphore::acquire_checked`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TryAcquireError {
+    /// Not enough resources were available, so acquiring would have blocked.
+    WouldBlock,
+    /// The semaphore has been closed.
+    Closed,
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryAcquireError::WouldBlock => f.write_str("semaphore has too few resources available"),
+            TryAcquireError::Closed => f.write_str("semaphore is closed"),
+        }
+    }
+}
+
+impl Error for TryAcquireError {}
+
+/// `WouldBlock` converts to an error of kind `io::ErrorKind::WouldBlock`, and
+/// `Closed` to one of kind `io::ErrorKind::Other`.
+impl From<TryAcquireError> for io::Error {
+    fn from(err: TryAcquireError) -> io::Error {
+        let kind = match err {
+            TryAcquireError::WouldBlock => io::ErrorKind::WouldBlock,
+            TryAcquireError::Closed => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// The error returned by `Semaphore::acquire_admitted` when as many acquirers
+/// as the semaphore allows are already waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("too many acquirers are waiting on the semaphore")
+    }
+}
+
+impl Error for QueueFull {}
+
+/// A broken internal invariant, found by `Semaphore::check_invariants`.
+///
+/// None of these should ever be seen; each one points at a bug in this crate
+/// or memory corruption elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum InvariantError {
+    /// A bounded semaphore holds more resources than its maximum.
+    CountAboveMax { count: isize, max: isize },
+    /// Waiters are recorded as waiting for a non-positive amount, which
+    /// never has to wait.
+    NonPositiveWait { amount: isize },
+    /// An amount is recorded with no waiters left waiting for it.
+    EmptyWaitEntry { amount: isize },
+    /// More async tasks are registered to be woken than acquirers are
+    /// waiting.
+    UnaccountedWakers { wakers: usize, waiters: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantError::CountAboveMax { count, max } => write!(
+                f,
+                "semaphore count {} exceeds its maximum of {}",
+                count, max
+            ),
+            InvariantError::NonPositiveWait { amount } => {
+                write!(f, "acquirers are waiting for {} resources", amount)
+            }
+            InvariantError::EmptyWaitEntry { amount } => write!(
+                f,
+                "no acquirers are left waiting for {} resources, but the entry remains",
+                amount
+            ),
+            InvariantError::UnaccountedWakers { wakers, waiters } => write!(
+                f,
+                "{} wakers are registered but only {} acquirers are waiting",
+                wakers, waiters
+            ),
+        }
+    }
+}
+
+impl Error for InvariantError {}
+
+/// The error returned by `Semaphore::acquire_sla` when the resource can't be
+/// acquired within the time allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SlaExceeded {
+    /// The estimated wait that made the acquisition give up without queueing,
+    /// or `None` if it queued and ran out of time.
+    pub estimate: Option<Duration>,
+}
+
+impl fmt::Display for SlaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.estimate {
+            Some(estimate) => write!(
+                f,
+                "semaphore acquisition would take an estimated {:?}, exceeding its SLA",
+                estimate
+            ),
+            None => f.write_str("semaphore acquisition did not complete within its SLA"),
+        }
+    }
+}
+
+impl Error for SlaExceeded {}
+
+/// The error returned by `DeadlineScope::acquire` when its deadline passes
+/// before the resources can be acquired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeadlineExpired;
+
+impl fmt::Display for DeadlineExpired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("deadline expired before the semaphore could be acquired")
+    }
+}
+
+impl Error for DeadlineExpired {}
+
+/// The error that the future returned by `Semaphore::acquire_until` resolves
+/// to when it is cancelled before the resource can be acquired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("cancelled before the semaphore could be acquired")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// The error returned by `Semaphore::acquire_or_deadlock_err` when the
+/// calling thread would wait for resources only it can give back.
+#[cfg(feature = "debug")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WouldDeadlock;
+
+#[cfg(feature = "debug")]
+impl fmt::Display for WouldDeadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the semaphore is exhausted by guards the current thread holds")
+    }
+}
+
+#[cfg(feature = "debug")]
+impl Error for WouldDeadlock {}
+
+/// The error returned by `Semaphore::try_acquire_many_checked` for an amount
+/// that could never be acquired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AmountError {
+    /// The amount is negative.
+    Negative { amount: isize },
+    /// The amount is more than a bounded semaphore's maximum.
+    ExceedsMax { amount: isize, max: isize },
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AmountError::Negative { amount } => {
+                write!(
+                    f,
+                    "cannot acquire a negative number of resources ({})",
+                    amount
+                )
+            }
+            AmountError::ExceedsMax { amount, max } => write!(
+                f,
+                "cannot acquire {} resources from a semaphore with a maximum of {}",
+                amount, max
+            ),
+        }
+    }
+}
+
+impl Error for AmountError {}
+
+/// The error returned by `Semaphore::try_release_many` when releasing would
+/// push the count of a bounded semaphore past its maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CapacityExceeded {
+    /// The amount that was refused.
+    pub amount: isize,
+    /// The count at the time, left unchanged.
+    pub count: isize,
+    /// The semaphore's maximum.
+    pub max: isize,
+}
+
+impl fmt::Display for CapacityExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "releasing {} resources to a semaphore with a count of {} would exceed its maximum of {}",
+            self.amount, self.count, self.max
+        )
+    }
+}
+
+impl Error for CapacityExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::{AmountError, InvariantError, TryAcquireError};
+    use std::io;
+
+    #[test]
+    fn test_try_acquire_error_into_io() {
+        let err: io::Error = TryAcquireError::WouldBlock.into();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(err.to_string(), TryAcquireError::WouldBlock.to_string());
+        let err: io::Error = TryAcquireError::Closed.into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_amount_error_display() {
+        let err = AmountError::ExceedsMax { amount: 3, max: 2 };
+        assert_eq!(
+            err.to_string(),
+            "cannot acquire 3 resources from a semaphore with a maximum of 2"
+        );
+    }
+
+    #[test]
+    fn test_invariant_error_display() {
+        let err = InvariantError::CountAboveMax { count: 3, max: 2 };
+        assert_eq!(
+            err.to_string(),
+            "semaphore count 3 exceeds its maximum of 2"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::thread::{self, JoinHandle};
+
+use {Semaphore, SemaphoreGuard};
+
+/// Runs tasks on threads of their own, at most a fixed number at a time.
+///
+/// Each running task holds a resource of a semaphore, which its thread
+/// releases when the task finishes, even if it panics. Submitting a task when
+/// as many are running as allowed waits for one of them to finish.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::BoundedExecutor;
+///
+/// let executor = BoundedExecutor::new(2);
+/// let handles: Vec<_> = (0..4).map(|i| executor.submit(move || i * 2)).collect();
+/// let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+/// assert_eq!(results, [0, 2, 4, 6]);
+/// ```
+pub struct BoundedExecutor {
+    sem: Semaphore,
+}
+
+impl BoundedExecutor {
+    /// Creates an executor that runs at most `max_tasks` tasks at once.
+    pub fn new(max_tasks: isize) -> BoundedExecutor {
+        BoundedExecutor {
+            sem: Semaphore::bounded(max_tasks, max_tasks),
+        }
+    }
+
+    /// Returns how many more tasks could start right away.
+    pub fn available(&self) -> isize {
+        self.sem.available_permits()
+    }
+
+    /// Runs `f` on a new thread, first blocking the current thread until
+    /// fewer than the maximum number of tasks are running.
+    ///
+    /// Returns the task's thread, to join for its result.
+    pub fn submit<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let guard = self.sem.access();
+        Self::spawn(guard, f)
+    }
+
+    /// Runs `f` on a new thread if fewer than the maximum number of tasks are
+    /// running, or hands it back without blocking.
+    pub fn try_submit<F, T>(&self, f: F) -> Result<JoinHandle<T>, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.sem.try_access() {
+            Some(guard) => Ok(Self::spawn(guard, f)),
+            None => Err(f),
+        }
+    }
+
+    fn spawn<F, T>(guard: SemaphoreGuard, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        thread::spawn(move || {
+            // Released once the task returns or unwinds.
+            let _guard = guard;
+            f()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoundedExecutor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_executor_limits_concurrency() {
+        let executor = BoundedExecutor::new(3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..12)
+            .map(|_| {
+                let (running, most) = (running.clone(), most.clone());
+                executor.submit(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= 3);
+        assert_eq!(executor.available(), 3);
+    }
+
+    #[test]
+    fn test_executor_try_submit() {
+        let executor = BoundedExecutor::new(1);
+        let (tx, rx) = channel::<()>();
+        let busy = executor.submit(move || rx.recv().unwrap());
+        assert!(executor.try_submit(|| ()).is_err());
+        tx.send(()).unwrap();
+        busy.join().unwrap();
+
+        // A panicking task still gives its place back.
+        let _ = executor.submit(|| panic!("task failed")).join();
+        match executor.try_submit(|| ()) {
+            Ok(handle) => handle.join().unwrap(),
+            Err(_) => panic!("the failed task's place wasn't given back"),
+        }
+    }
+}
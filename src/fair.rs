@@ -0,0 +1,323 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The arrival-order queue behind fair semaphores.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::LockResult;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use sync::{Mutex, MutexGuard};
+use {AcquireDiag, Parker, SemaphoreState};
+
+/// An acquirer waiting for its turn.
+struct Queued {
+    ticket: u64,
+    amount: isize,
+    /// Whether this is a reservation, which is granted in its owner's absence
+    /// as soon as it reaches the head of the queue and can be satisfied.
+    reserved: bool,
+    /// The thread blocked waiting, to be unparked on its own once it can
+    /// proceed. Async tasks and reservations have none.
+    thread: Option<Thread>,
+    /// When the acquirer joined the queue.
+    since: Instant,
+    /// `Queue::served` when the acquirer joined the queue.
+    served_before: u64,
+}
+
+/// Parks a queued thread by itself rather than with the semaphore's other
+/// waiters, so a release can unpark exactly the head of the queue through
+/// the handle recorded with `Queue::set_thread`. `unpark_all` has nothing to
+/// wake.
+pub struct QueuedParker;
+
+impl Parker for QueuedParker {
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        // Our handle was recorded with the lock held, so an unpark can't come
+        // before the lock is released; if it comes before we park, `park`
+        // returns immediately.
+        drop(guard);
+        match timeout {
+            None => thread::park(),
+            Some(timeout) => thread::park_timeout(timeout),
+        }
+        lock.lock()
+    }
+
+    fn unpark_all(&self) {}
+}
+
+/// The acquirers of a fair semaphore that are waiting, oldest first. Only the
+/// oldest may take resources, and nobody may take them past a non-empty
+/// queue.
+pub struct Queue {
+    queued: VecDeque<Queued>,
+    next_ticket: u64,
+    /// When the current head of the queue got there.
+    head_since: Option<Instant>,
+    /// A moving average of how long acquirers spend at the head of the queue
+    /// before being served.
+    service_time: Option<Duration>,
+    /// Reservations that have been granted but not yet claimed.
+    granted: Vec<u64>,
+    /// How many acquirers may be queued before `Semaphore::acquire_admitted`
+    /// turns new ones away.
+    max_len: Option<usize>,
+    /// The total amount served from the queue so far, wrapping around.
+    served: u64,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue {
+            queued: VecDeque::new(),
+            next_ticket: 0,
+            head_since: None,
+            service_time: None,
+            granted: Vec::new(),
+            max_len: None,
+            served: 0,
+        }
+    }
+
+    /// Creates a queue that reports itself full once `max_len` acquirers are
+    /// waiting in it.
+    pub fn with_max_len(max_len: usize) -> Queue {
+        Queue {
+            max_len: Some(max_len),
+            ..Queue::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        match self.max_len {
+            Some(max_len) => self.queued.len() >= max_len,
+            None => false,
+        }
+    }
+
+    /// Adds an acquirer for `amount` to the back of the queue, returning its
+    /// ticket.
+    pub fn push(&mut self, amount: isize) -> u64 {
+        self.push_queued(amount, false)
+    }
+
+    /// Adds a reservation for `amount` to the back of the queue, returning its
+    /// ticket. See `grant`.
+    pub fn push_reservation(&mut self, amount: isize) -> u64 {
+        self.push_queued(amount, true)
+    }
+
+    fn push_queued(&mut self, amount: isize, reserved: bool) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        let now = Instant::now();
+        if self.queued.is_empty() {
+            self.head_since = Some(now);
+        }
+        self.queued.push_back(Queued {
+            ticket,
+            amount,
+            reserved,
+            thread: None,
+            since: now,
+            served_before: self.served,
+        });
+        ticket
+    }
+
+    /// Grants every reservation at the head of the queue that `count` can
+    /// satisfy, taking their resources from it. Returns whether any were
+    /// granted.
+    pub fn grant(&mut self, count: &mut isize) -> bool {
+        let mut granted = false;
+        while let Some(&Queued {
+            ticket,
+            amount,
+            reserved: true,
+            ..
+        }) = self.queued.front()
+        {
+            if *count < amount {
+                break;
+            }
+            *count -= amount;
+            self.remove(ticket, true);
+            self.granted.push(ticket);
+            granted = true;
+        }
+        granted
+    }
+
+    /// Whether the reservation `ticket` has been granted.
+    pub fn is_granted(&self, ticket: u64) -> bool {
+        self.granted.contains(&ticket)
+    }
+
+    /// Forgets the grant of `ticket`, returning whether it had been granted.
+    pub fn take_grant(&mut self, ticket: u64) -> bool {
+        let len = self.granted.len();
+        self.granted.retain(|&t| t != ticket);
+        self.granted.len() != len
+    }
+
+    /// Whether `ticket` is at the head of the queue.
+    pub fn is_first(&self, ticket: u64) -> bool {
+        self.queued.front().map(|q| q.ticket) == Some(ticket)
+    }
+
+    /// Records `thread` as the one blocked waiting with `ticket`.
+    pub fn set_thread(&mut self, ticket: u64, thread: Thread) {
+        if let Some(queued) = self.queued.iter_mut().find(|q| q.ticket == ticket) {
+            queued.thread = Some(thread);
+        }
+    }
+
+    /// The thread blocked at the head of the queue, if it could take its
+    /// resources from `count`.
+    pub fn first_thread(&self, count: isize) -> Option<Thread> {
+        match self.queued.front() {
+            Some(queued) if queued.amount <= count => queued.thread.clone(),
+            _ => None,
+        }
+    }
+
+    /// Every thread blocked in the queue.
+    pub fn threads(&self) -> Vec<Thread> {
+        self.queued
+            .iter()
+            .filter_map(|q| q.thread.clone())
+            .collect()
+    }
+
+    /// The amount the head of the queue is waiting for.
+    pub fn first_amount(&self) -> Option<isize> {
+        self.queued.front().map(|q| q.amount)
+    }
+
+    /// The total amount every queued acquirer is waiting for.
+    pub fn demand(&self) -> isize {
+        self.queued.iter().map(|q| q.amount).sum()
+    }
+
+    /// How `ticket`'s wait has gone up to `now`: how long it spent behind
+    /// others and at the head of the queue, and how much was served to those
+    /// ahead of it in the meantime.
+    pub fn diag(&self, ticket: u64, now: Instant) -> Option<AcquireDiag> {
+        let queued = self.queued.iter().find(|q| q.ticket == ticket)?;
+        let reached_head = match self.head_since {
+            Some(head_since) if self.is_first(ticket) => head_since,
+            _ => now,
+        };
+        Some(AcquireDiag {
+            queue_wait: reached_head.saturating_duration_since(queued.since),
+            head_wait: now.saturating_duration_since(reached_head),
+            permits_passed_while_waiting: self.served.wrapping_sub(queued.served_before),
+        })
+    }
+
+    /// Removes `ticket` from the queue. `served` says whether it is leaving
+    /// because it acquired its resources, rather than giving up.
+    pub fn remove(&mut self, ticket: u64, served: bool) {
+        let was_first = self.is_first(ticket);
+        let mut amount = 0;
+        self.queued.retain(|q| {
+            if q.ticket == ticket {
+                amount = q.amount;
+            }
+            q.ticket != ticket
+        });
+        if served {
+            self.served = self.served.wrapping_add(amount as u64);
+        }
+        if !was_first {
+            return;
+        }
+        let now = Instant::now();
+        if served {
+            if let Some(since) = self.head_since {
+                self.record_service(now - since);
+            }
+        }
+        self.head_since = if self.queued.is_empty() {
+            None
+        } else {
+            Some(now)
+        };
+    }
+
+    fn record_service(&mut self, sample: Duration) {
+        // Weigh each sample 1/8, so a burst of slow or fast service only
+        // shifts the estimate gradually.
+        self.service_time = Some(match self.service_time {
+            None => sample,
+            Some(average) => (average * 7 + sample) / 8,
+        });
+    }
+
+    /// Estimates how long an acquirer joining the queue now would wait, if
+    /// everyone is served at the recent average rate. Returns `None` before
+    /// anyone has been served from the queue.
+    pub fn estimate_wait(&self) -> Option<Duration> {
+        let average = self.service_time?;
+        let positions = u32::try_from(self.queued.len() + 1).unwrap_or(u32::MAX);
+        Some(average.checked_mul(positions).unwrap_or(Duration::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Queue;
+    use std::time::Instant;
+
+    #[test]
+    fn test_queue_order() {
+        let mut q = Queue::new();
+        let a = q.push(2);
+        let b = q.push(1);
+        assert!(q.is_first(a));
+        assert_eq!((q.first_amount(), q.demand()), (Some(2), 3));
+        assert_eq!(q.estimate_wait(), None);
+
+        q.remove(a, true);
+        let diag = q.diag(b, Instant::now()).unwrap();
+        assert_eq!(diag.permits_passed_while_waiting, 2);
+        q.remove(b, false);
+        assert!(q.is_empty());
+        assert!(q.estimate_wait().is_some());
+    }
+
+    #[test]
+    fn test_queue_grants_reservations_in_order() {
+        let mut q = Queue::new();
+        let a = q.push_reservation(1);
+        let b = q.push(1);
+        let c = q.push_reservation(1);
+        let mut count = 3;
+        assert!(q.grant(&mut count));
+        // The reservation behind a regular acquirer waits its turn.
+        assert_eq!(count, 2);
+        assert!(q.is_granted(a) && !q.is_granted(c));
+        q.remove(b, true);
+        assert!(q.grant(&mut count));
+        assert!(q.take_grant(c));
+        assert!(!q.take_grant(c));
+    }
+}
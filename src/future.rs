@@ -0,0 +1,199 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::PoisonError;
+use std::task::{Context, Poll};
+
+use sync::Arc;
+use wait_list::Node;
+use {check_amount, Cancelled, RawSemaphore, SemaphoreGuard};
+
+/// A future which acquires resources from a semaphore without blocking the
+/// thread, resolving to a guard for them.
+///
+/// Created by `Semaphore::access_async` and `Semaphore::access_many_async`.
+/// Async and blocking acquirers wait on the same semaphore side by side: a
+/// release wakes parked threads and pending tasks alike.
+///
+/// Dropping the future before it completes gives up its place without
+/// acquiring anything.
+///
+/// While it waits, the future is linked into the semaphore's list of
+/// waiters in place, so waiting doesn't allocate, and the future is
+/// `!Unpin`: it has to be pinned, with `Box::pin` for example, before it can
+/// be polled.
+#[must_use = "futures do nothing unless polled"]
+pub struct Access {
+    sem: Arc<RawSemaphore>,
+    amount: isize,
+    /// Whether we're counted among the semaphore's waiters, once we've had
+    /// to wait.
+    waiting: bool,
+    /// Our place in a fair semaphore's queue, once we've had to wait.
+    ticket: Option<u64>,
+    /// Our entry in the semaphore's waker list, linked while we wait for a
+    /// wakeup.
+    node: Node,
+}
+
+impl Access {
+    pub(crate) fn new(sem: &Arc<RawSemaphore>, amount: isize) -> Access {
+        check_amount(amount);
+        Access {
+            sem: Arc::clone(sem),
+            amount,
+            waiting: false,
+            ticket: None,
+            node: Node::new(),
+        }
+    }
+}
+
+impl Future for Access {
+    type Output = SemaphoreGuard;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphoreGuard> {
+        // SAFETY: nothing is moved out of `self`, so `node` stays pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.sem.lock.lock().unwrap();
+        if this.amount == 0 || state.may_take(this.amount, this.ticket) {
+            if this.waiting {
+                this.waiting = false;
+                // SAFETY: `node` is only ever linked into this semaphore's
+                // list.
+                unsafe { state.wakers.remove(&this.node) };
+                state.remove_waiter(this.amount);
+            }
+            state.leave_queue(this.ticket.take(), true);
+            state.count -= this.amount;
+            this.sem.unlock(state);
+            return Poll::Ready(SemaphoreGuard::new(&this.sem, this.amount));
+        }
+        // SAFETY: `node` is pinned along with `self`, and `drop` removes it
+        // from the list. A release that woke us has unlinked it, so this
+        // links it again.
+        unsafe { state.wakers.register(&this.node, cx.waker()) };
+        if !this.waiting {
+            this.waiting = true;
+            state.add_waiter(this.amount);
+            this.ticket = state.join_queue(this.amount);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Access {
+    fn drop(&mut self) {
+        if self.waiting {
+            // `node` must be unlinked before it goes away, poisoned or not.
+            let mut state = self.sem.lock.lock().unwrap_or_else(PoisonError::into_inner);
+            // SAFETY: `node` is only ever linked into this semaphore's list.
+            unsafe { state.wakers.remove(&self.node) };
+            state.remove_waiter(self.amount);
+            state.leave_queue(self.ticket, false);
+            // Let whoever was queued behind us have their turn.
+            self.sem.unlock(state);
+        }
+    }
+}
+
+/// A future which acquires a resource like `Access` unless another future
+/// completes first, resolving to `Err(Cancelled)` in that case.
+///
+/// Created by `Semaphore::acquire_until`. The cancelling future is checked
+/// first on every poll, so once it is ready the acquisition is cancelled even
+/// if a resource is available too. A cancelled acquisition gives up its place
+/// right away and never consumes a resource.
+#[must_use = "futures do nothing unless polled"]
+pub struct AccessUntil<C> {
+    /// The acquisition, until it is cancelled.
+    access: Option<Access>,
+    cancel: Pin<Box<C>>,
+}
+
+impl<C: Future> AccessUntil<C> {
+    pub(crate) fn new(access: Access, cancel: C) -> AccessUntil<C> {
+        AccessUntil {
+            access: Some(access),
+            cancel: Box::pin(cancel),
+        }
+    }
+}
+
+impl<C: Future> Future for AccessUntil<C> {
+    type Output = Result<SemaphoreGuard, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: `access` is pinned whenever `self` is. It is never moved
+        // out of, only dropped in place by setting it to `None`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let access = match this.access {
+            Some(ref mut access) => unsafe { Pin::new_unchecked(access) },
+            None => panic!("`AccessUntil` polled after completion"),
+        };
+        if this.cancel.as_mut().poll(cx).is_ready() {
+            // Leave the semaphore's queue now rather than when we're dropped.
+            this.access = None;
+            return Poll::Ready(Err(Cancelled));
+        }
+        match access.poll(cx) {
+            Poll::Ready(guard) => {
+                this.access = None;
+                Poll::Ready(Ok(guard))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    pub fn noop_waker() -> Waker {
+        Waker::from(Arc::new(NoopWaker))
+    }
+
+    /// Polls a future once with a waker that does nothing.
+    pub fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(&noop_waker()))
+    }
+
+    /// Runs a future to completion on the current thread.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+}
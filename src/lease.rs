@@ -0,0 +1,226 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Guards which give their resource back on their own once held too long.
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sync::Arc;
+use RawSemaphore;
+
+/// The outstanding leases of a semaphore, and whether a reaper thread is
+/// watching them.
+pub struct Leases {
+    inner: Mutex<Active>,
+    /// Notified when a lease is added, in case it expires before the reaper
+    /// was going to wake up.
+    added: Condvar,
+}
+
+struct Active {
+    next_id: u64,
+    /// The leases by id. A lease is removed by whichever of its guard's drop
+    /// or the reaper gets to it first, and only that one releases its
+    /// resource.
+    leases: HashMap<u64, Lease>,
+    reaping: bool,
+}
+
+struct Lease {
+    /// When the lease expires, or `None` for a lease too long to represent.
+    deadline: Option<Instant>,
+    /// The semaphore's `resets` when the lease was taken, so that a lease
+    /// invalidated by `Semaphore::set_count` releases nothing.
+    resets: u64,
+}
+
+impl Leases {
+    pub fn new() -> Leases {
+        Leases {
+            inner: Mutex::new(Active {
+                next_id: 0,
+                leases: HashMap::new(),
+                reaping: false,
+            }),
+            added: Condvar::new(),
+        }
+    }
+}
+
+/// An RAII guard for a resource that is released when the guard is dropped
+/// or when its lease runs out, whichever comes first.
+///
+/// Created by `Semaphore::access_leased`. Once the lease has run out the
+/// resource may be acquired by someone else, and dropping the guard does
+/// nothing.
+pub struct LeasedGuard {
+    sem: Arc<RawSemaphore>,
+    id: u64,
+}
+
+/// Leases a resource already acquired from `sem` for `lease`, starting a
+/// reaper thread if none is running.
+pub fn new(sem: &Arc<RawSemaphore>, lease: Duration) -> LeasedGuard {
+    let deadline = Instant::now().checked_add(lease);
+    let mut active = sem.leases.inner.lock().unwrap();
+    let id = active.next_id;
+    active.next_id += 1;
+    let resets = sem.resets.load(Ordering::Relaxed);
+    active.leases.insert(id, Lease { deadline, resets });
+    let start_reaper = !active.reaping && deadline.is_some();
+    if start_reaper {
+        active.reaping = true;
+    }
+    drop(active);
+    if start_reaper {
+        let sem = Arc::clone(sem);
+        thread::spawn(move || reap(&sem));
+    } else {
+        sem.leases.added.notify_one();
+    }
+    LeasedGuard {
+        sem: Arc::clone(sem),
+        id,
+    }
+}
+
+/// Releases the resource of every lease as it expires, until no lease with
+/// a deadline is left.
+fn reap(sem: &RawSemaphore) {
+    let mut active = sem.leases.inner.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        active.leases.retain(|_, lease| match lease.deadline {
+            Some(deadline) if deadline <= now => {
+                expired.push(lease.resets);
+                false
+            }
+            _ => true,
+        });
+        if !expired.is_empty() {
+            drop(active);
+            for resets in expired {
+                sem.release_stamped(resets, 1);
+            }
+            active = sem.leases.inner.lock().unwrap();
+            continue;
+        }
+        match active
+            .leases
+            .values()
+            .filter_map(|lease| lease.deadline)
+            .min()
+        {
+            Some(next) => {
+                active = sem.leases.added.wait_timeout(active, next - now).unwrap().0;
+            }
+            None => {
+                active.reaping = false;
+                return;
+            }
+        }
+    }
+}
+
+impl LeasedGuard {
+    /// Returns whether the lease has run out and the resource was taken
+    /// back.
+    pub fn is_expired(&self) -> bool {
+        let active = self.sem.leases.inner.lock().unwrap();
+        !active.leases.contains_key(&self.id)
+    }
+}
+
+impl Drop for LeasedGuard {
+    fn drop(&mut self) {
+        let held = self
+            .sem
+            .leases
+            .inner
+            .lock()
+            .unwrap()
+            .leases
+            .remove(&self.id);
+        if let Some(lease) = held {
+            self.sem.release_stamped(lease.resets, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use Semaphore;
+
+    fn wait_for_count(s: &Semaphore, count: isize) {
+        let start = Instant::now();
+        while s.available_permits() != count {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "lease never expired"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_lease_reclaims_leaked_guard() {
+        let s = Semaphore::new(1);
+        mem::forget(s.access_leased(Duration::from_millis(10)));
+        assert_eq!(s.available_permits(), 0);
+        wait_for_count(&s, 1);
+    }
+
+    #[test]
+    fn test_lease_expired_drop_is_noop() {
+        let s = Semaphore::new(1);
+        let guard = s.access_leased(Duration::from_millis(10));
+        wait_for_count(&s, 1);
+        assert!(guard.is_expired());
+        drop(guard);
+        assert_eq!(s.available_permits(), 1);
+
+        // A guard dropped in time isn't reaped afterwards.
+        let guard = s.access_leased(Duration::from_millis(50));
+        assert!(!guard.is_expired());
+        drop(guard);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_lease_invalidated_by_set_count() {
+        let s = Semaphore::new(2);
+        let guard = s.access_leased(Duration::from_secs(60));
+        s.set_count(5);
+        drop(guard);
+        assert_eq!(s.available_permits(), 5);
+
+        // Nor does the reaper release an invalidated lease.
+        mem::forget(s.access_leased(Duration::from_millis(10)));
+        s.set_count(5);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(s.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_lease_shorter_than_running_reaper() {
+        let s = Semaphore::new(2);
+        let _long = s.access_leased(Duration::from_secs(3600));
+        mem::forget(s.access_leased(Duration::from_millis(10)));
+        wait_for_count(&s, 1);
+    }
+}
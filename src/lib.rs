@@ -8,12 +8,485 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::ops::Drop;
-use std::sync::{Arc, Condvar, Mutex};
+#[cfg(loom)]
+extern crate loom;
+#[cfg(feature = "parking_lot")]
+extern crate parking_lot_core;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+
+#[cfg(feature = "debug")]
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::hint;
+use std::mem;
+use std::ops::{Deref, DerefMut, Drop};
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{LockResult, PoisonError};
+use std::thread;
+#[cfg(feature = "debug")]
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use sync::{Arc, Mutex, MutexGuard};
+
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "tokio")]
+pub mod bridge;
+mod checked;
+mod clock;
+#[cfg(feature = "tokio-compat")]
+pub mod compat;
+mod deadline;
+#[cfg(feature = "debug")]
+mod debug;
+mod error;
+mod executor;
+mod fair;
+mod future;
+mod lease;
+mod link;
+mod lockfree;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multi;
+mod parker;
+mod pending;
+mod pool;
+mod priority;
+mod round;
+mod scaled;
+#[cfg(feature = "stats")]
+mod stats;
+mod sub;
+mod sync;
+#[cfg(feature = "tower")]
+mod tower;
+mod wait_group;
+mod wait_list;
+mod warm;
+mod weighted;
+
+pub use checked::CheckedSemaphore;
+pub use clock::{Clock, SystemClock};
+pub use deadline::DeadlineScope;
+#[cfg(feature = "debug")]
+pub use error::WouldDeadlock;
+pub use error::{
+    AmountError, Cancelled, CapacityExceeded, DeadlineExpired, InvariantError, QueueFull,
+    SlaExceeded, TryAcquireError,
+};
+pub use executor::BoundedExecutor;
+pub use future::{Access, AccessUntil};
+pub use lease::LeasedGuard;
+pub use link::{LinkOwner, LinkToken};
+pub use lockfree::{LockFreeGuard, LockFreeSemaphore};
+pub use multi::{MultiGuard, MultiSemaphore};
+#[cfg(feature = "parking_lot")]
+pub use parker::ParkingLotParker;
+pub use parker::{Backend, CondvarParker, Parker, SpinParker, ThreadParker};
+pub use pending::PendingPermit;
+pub use pool::{PooledObject, SemaphorePool};
+pub use priority::{PriorityGuard, PrioritySemaphore};
+pub use round::RoundSemaphore;
+pub use scaled::ScaledSemaphore;
+#[cfg(feature = "stats")]
+pub use stats::WaitStats;
+pub use sub::SubSemaphore;
+#[cfg(feature = "tower")]
+pub use tower::{ConcurrencyLimit, ResponseFuture};
+pub use wait_group::{WaitGroup, WaitGroupGuard};
+pub use warm::{SlotGuard, WarmSemaphore};
+pub use weighted::{WeightedGuard, WeightedSemaphore};
+
+/// The state protected by a semaphore's mutex.
+///
+/// Its contents are private; it is only public so that `Parker`
+/// implementations can name the lock they are handed.
+pub struct SemaphoreState {
+    count: isize,
+    /// What the count would be with nothing out, for
+    /// `Semaphore::wait_outstanding`. It starts as the initial count, and is
+    /// moved along by everything that changes the count without acquiring or
+    /// releasing.
+    baseline: isize,
+    /// The total number of resources, for bounded semaphores.
+    max: Option<isize>,
+    closed: bool,
+    /// Set by `Semaphore::pause` to hold back every acquisition.
+    paused: bool,
+    /// Cleared by `Semaphore::set_online` to hold back every acquisition.
+    online: bool,
+    /// The number of blocked acquirers waiting for each amount.
+    waiting: BTreeMap<isize, usize>,
+    /// The number of threads waiting for any change to the count.
+    watchers: usize,
+    /// How many times a changed count has been published by `unlock` or
+    /// `wake_all`, for `CountWatcher`.
+    version: u64,
+    /// How many acquisitions through `RawSemaphore::take_or_wait` have taken
+    /// resources so far.
+    grants: u64,
+    /// How many parked threads have been picked to wake by `budget_wakeups`
+    /// but haven't run yet. It may fall short of the true number, which only
+    /// costs extra wakeups, but never exceeds it.
+    notified: usize,
+    /// Wakers for pending async acquisitions, linked in from their futures.
+    /// They are counted in `waiting` too.
+    wakers: wait_list::List,
+    /// The queue of waiting acquirers, for fair semaphores.
+    fair: Option<fair::Queue>,
+    /// The amount each blocking acquirer of a semaphore that isn't fair is
+    /// waiting for, oldest first, for `Semaphore::release_handoff`.
+    arrivals: BTreeMap<u64, isize>,
+    next_arrival: u64,
+    /// Resources handed off to the oldest of `arrivals`, kept out of `count`
+    /// so nobody else can take them.
+    handed_off: isize,
+    /// The number of live `ReleaseScope`s, which hold back wakeups until the
+    /// last of them is dropped.
+    deferring: usize,
+    /// Whether a wakeup has been held back for a `ReleaseScope`.
+    deferred: bool,
+    soft_limit: Option<SoftLimit>,
+    /// The most a guard may grow to by expanding, if capped.
+    max_guard: Option<isize>,
+    /// How long each blocking acquisition waited.
+    #[cfg(feature = "stats")]
+    waits: stats::Histogram,
+    #[cfg(test)]
+    wakeups: usize,
+}
+
+type SoftLimitCallback = Box<dyn Fn(isize) + Send>;
+
+struct SoftLimit {
+    threshold: isize,
+    /// Whether the count has been above the threshold since the callback was
+    /// last called.
+    armed: bool,
+}
+
+impl SemaphoreState {
+    fn new(count: isize) -> SemaphoreState {
+        SemaphoreState {
+            count,
+            baseline: count,
+            max: None,
+            closed: false,
+            paused: false,
+            online: true,
+            waiting: BTreeMap::new(),
+            watchers: 0,
+            version: 0,
+            grants: 0,
+            notified: 0,
+            wakers: wait_list::List::new(),
+            fair: None,
+            arrivals: BTreeMap::new(),
+            next_arrival: 0,
+            handed_off: 0,
+            deferring: 0,
+            deferred: false,
+            soft_limit: None,
+            max_guard: None,
+            #[cfg(feature = "stats")]
+            waits: stats::Histogram::new(),
+            #[cfg(test)]
+            wakeups: 0,
+        }
+    }
+
+    /// Returns how many resources are out, if that fits in an `isize`.
+    fn outstanding(&self) -> Option<isize> {
+        self.baseline.checked_sub(self.count)
+    }
+
+    fn add_waiter(&mut self, amount: isize) {
+        *self.waiting.entry(amount).or_insert(0) += 1;
+    }
+
+    fn remove_waiter(&mut self, amount: isize) {
+        let waiters = self.waiting.get_mut(&amount).unwrap();
+        *waiters -= 1;
+        if *waiters == 0 {
+            self.waiting.remove(&amount);
+        }
+    }
+
+    /// Updates the soft limit's trigger for the current count, returning the
+    /// count if it just dropped to the threshold or below.
+    fn cross_soft_limit(&mut self) -> Option<isize> {
+        let count = self.count;
+        let limit = self.soft_limit.as_mut()?;
+        if count > limit.threshold {
+            limit.armed = true;
+            None
+        } else if limit.armed {
+            limit.armed = false;
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    fn check_invariants(&self) -> Result<(), InvariantError> {
+        if let Some(max) = self.max {
+            if self.count > max {
+                return Err(InvariantError::CountAboveMax {
+                    count: self.count,
+                    max,
+                });
+            }
+        }
+        let mut waiters = 0;
+        for (&amount, &n) in &self.waiting {
+            if amount <= 0 {
+                return Err(InvariantError::NonPositiveWait { amount });
+            }
+            if n == 0 {
+                return Err(InvariantError::EmptyWaitEntry { amount });
+            }
+            waiters += n;
+        }
+        // Tasks stay counted as waiting after a release takes their waker.
+        if self.wakers.len() > waiters {
+            return Err(InvariantError::UnaccountedWakers {
+                wakers: self.wakers.len(),
+                waiters,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether an acquirer may take `amount` resources now. `ticket` is its
+    /// place in a fair semaphore's queue, if it has joined it; in a fair
+    /// semaphore only the head of the queue may take resources, and acquirers
+    /// that haven't queued may only take them while nobody is queued.
+    fn may_take(&self, amount: isize, ticket: Option<u64>) -> bool {
+        if self.held_back() || self.count < amount {
+            return false;
+        }
+        match (&self.fair, ticket) {
+            (None, _) => true,
+            (Some(queue), Some(ticket)) => queue.is_first(ticket),
+            (Some(queue), None) => queue.is_empty(),
+        }
+    }
+
+    /// Records a blocking acquirer of a semaphore that isn't fair starting to
+    /// wait, returning its place to pass to `may_take_arrived`, `take` and
+    /// `depart`.
+    fn arrive(&mut self, amount: isize) -> Option<u64> {
+        if self.fair.is_some() {
+            return None;
+        }
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
+        self.arrivals.insert(arrival, amount);
+        Some(arrival)
+    }
+
+    /// Whether `arrival` is the oldest acquirer, which may take handed off
+    /// resources.
+    fn is_oldest(&self, arrival: Option<u64>) -> bool {
+        arrival.is_some() && self.arrivals.keys().next() == arrival.as_ref()
+    }
+
+    /// Like `may_take`, but also counting handed off resources if `arrival`
+    /// is the oldest acquirer.
+    fn may_take_arrived(&self, amount: isize, ticket: Option<u64>, arrival: Option<u64>) -> bool {
+        if self.handed_off > 0 && self.is_oldest(arrival) {
+            return !self.held_back() && self.count + self.handed_off >= amount;
+        }
+        self.may_take(amount, ticket)
+    }
+
+    /// Takes `amount` resources, starting with those handed off if `arrival`
+    /// is the oldest acquirer.
+    fn take(&mut self, amount: isize, arrival: Option<u64>) {
+        let mut amount = amount;
+        if self.is_oldest(arrival) {
+            let handed = cmp::min(self.handed_off, amount);
+            self.handed_off -= handed;
+            amount -= handed;
+        }
+        self.count -= amount;
+    }
+
+    /// Records `arrival` no longer waiting. Once nobody is left to hand off
+    /// to, handed off resources go back to the count.
+    fn depart(&mut self, arrival: Option<u64>) {
+        if let Some(arrival) = arrival {
+            self.arrivals.remove(&arrival);
+        }
+        if self.arrivals.is_empty() {
+            self.count += mem::replace(&mut self.handed_off, 0);
+        }
+    }
+
+    /// Whether acquisitions are held back whatever the count, because the
+    /// semaphore is paused or offline.
+    fn held_back(&self) -> bool {
+        self.paused || !self.online
+    }
+
+    /// Joins the queue of a fair semaphore, returning the ticket to pass to
+    /// `may_take` and `leave_queue`.
+    fn join_queue(&mut self, amount: isize) -> Option<u64> {
+        self.fair.as_mut().map(|queue| queue.push(amount))
+    }
+
+    /// Records the current thread as the one blocked waiting with `ticket`,
+    /// so it alone is unparked when its turn comes.
+    fn queue_thread(&mut self, ticket: Option<u64>) {
+        if let (Some(queue), Some(ticket)) = (self.fair.as_mut(), ticket) {
+            queue.set_thread(ticket, thread::current());
+        }
+    }
+
+    /// The thread at the head of a fair semaphore's queue, if the count can
+    /// now satisfy it.
+    fn head_thread(&self) -> Option<thread::Thread> {
+        if self.held_back() {
+            return None;
+        }
+        self.fair
+            .as_ref()
+            .and_then(|queue| queue.first_thread(self.count))
+    }
+
+    /// Leaves the queue of a fair semaphore, either with the resources
+    /// (`served`) or after giving up.
+    fn leave_queue(&mut self, ticket: Option<u64>, served: bool) {
+        if let (Some(queue), Some(ticket)) = (self.fair.as_mut(), ticket) {
+            queue.remove(ticket, served);
+        }
+    }
+
+    /// Grants the reservations at the head of a fair semaphore's queue that
+    /// can be satisfied, returning whether there were any.
+    fn grant_reservations(&mut self) -> bool {
+        if self.held_back() {
+            return false;
+        }
+        match self.fair {
+            Some(ref mut queue) => queue.grant(&mut self.count),
+            None => false,
+        }
+    }
+
+    /// Works out how many parked threads to wake so that every waiter the
+    /// count can now satisfy gets a chance, and counts them as notified.
+    /// Returns `None` when everyone has to be woken, since a watcher can't be
+    /// picked out by `unpark_some`. The threads queued in a fair semaphore
+    /// don't park with the parker; `unlock` wakes its head on its own.
+    ///
+    /// Waiters are assumed to take the smallest amounts first, which gives
+    /// the most that can proceed at once. Threads already notified are taken
+    /// to cover part of that. If the parker picks a waiter that can't
+    /// proceed after all, it passes the wakeup on, and every waiter that
+    /// does proceed calls `unlock` again, so nobody is left behind.
+    fn budget_wakeups(&mut self) -> Option<usize> {
+        if self.watchers > 0 || self.handed_off > 0 {
+            return None;
+        }
+        if self.held_back() || self.fair.is_some() {
+            return Some(0);
+        }
+        let mut available = self.count;
+        let mut servable = 0;
+        let mut total = 0;
+        for (&amount, &waiters) in &self.waiting {
+            total += waiters;
+            if available < amount {
+                continue;
+            }
+            let served = cmp::min(waiters, (available / amount) as usize);
+            servable += served;
+            available -= served as isize * amount;
+        }
+        // Threads that stopped waiting without being notified leave the
+        // count too high; it can't be more than are waiting.
+        self.notified = cmp::min(self.notified, total);
+        let wake = servable.saturating_sub(self.notified);
+        self.notified += wake;
+        Some(wake)
+    }
+
+    /// Whether a parked thread may be able to proceed after the count changed:
+    /// either someone is watching every change, or the smallest waiting
+    /// request can now be satisfied. In a fair semaphore, only the head of the
+    /// queue's request matters.
+    fn can_wake(&self) -> bool {
+        if self.watchers > 0 {
+            return true;
+        }
+        if self.held_back() {
+            return false;
+        }
+        if let Some(amount) = self.fair.as_ref().and_then(|queue| queue.first_amount()) {
+            return self.count >= amount;
+        }
+        if self.handed_off > 0 {
+            if let Some(&amount) = self.arrivals.values().next() {
+                if self.count + self.handed_off >= amount {
+                    return true;
+                }
+            }
+        }
+        match self.waiting.keys().next() {
+            Some(&amount) => self.count >= amount,
+            None => false,
+        }
+    }
+}
 
 pub struct RawSemaphore {
-    lock: Mutex<isize>,
-    cvar: Condvar,
+    lock: Mutex<SemaphoreState>,
+    parker: Box<dyn Parker>,
+    /// A copy of the count for reading without the lock. It's only written
+    /// with the lock held, on the way out of `unlock`.
+    count_hint: AtomicIsize,
+    /// Bumped, with the lock held, whenever the semaphore is reconfigured.
+    generation: AtomicU64,
+    /// Bumped, with the lock held, whenever `Semaphore::bump_generation`
+    /// invalidates every outstanding guard. Guards stamped with an older value
+    /// release nothing.
+    resets: AtomicU64,
+    /// Called by `unlock` when the count reaches the soft limit. It lives
+    /// outside the state so it can be called without the state locked.
+    soft_limit_callback: Mutex<Option<SoftLimitCallback>>,
+    /// The clock for timeouts, or `None` for `SystemClock`, which is read
+    /// directly rather than through the trait object.
+    clock: Option<Box<dyn Clock>>,
+    /// Guards from `Semaphore::access_leased` that haven't been returned.
+    leases: lease::Leases,
+    /// The label given to `Semaphore::with_name`, for diagnostics.
+    name: Option<&'static str>,
+    /// The count the semaphore was created with.
+    initial: isize,
+    #[cfg(feature = "debug")]
+    acquirers: debug::Registry,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Counters,
+}
+
+/// Panics if `amount` can't be acquired. A guard for a negative amount would
+/// take resources when dropped instead of returning them.
+fn check_amount(amount: isize) {
+    if amount < 0 {
+        panic!("cannot acquire a negative number of resources ({})", amount);
+    }
 }
 
 impl RawSemaphore {
@@ -21,8 +494,474 @@ impl RawSemaphore {
         if amount == 0 {
             return;
         }
-        *self.lock.lock().unwrap() += amount;
-        self.cvar.notify_all();
+        let state = self.lock.lock().unwrap();
+        self.release_locked(state, amount);
+    }
+
+    /// Releases `amount` resources explicitly, rather than from a guard. In
+    /// debug builds this panics if the semaphore is closed, since resources
+    /// given back by hand after a shutdown began usually mean the shutdown
+    /// happens in the wrong order. Guards are expected to outlive a close and
+    /// are exempt.
+    fn release_explicit(&self, amount: isize) {
+        if amount == 0 {
+            return;
+        }
+        let state = self.check_open(self.lock.lock().unwrap(), amount);
+        self.release_locked(state, amount);
+    }
+
+    /// Panics in debug builds if the semaphore is closed, before `amount`
+    /// resources are released explicitly; see `release_explicit`.
+    fn check_open<'a>(
+        &self,
+        state: MutexGuard<'a, SemaphoreState>,
+        amount: isize,
+    ) -> MutexGuard<'a, SemaphoreState> {
+        if cfg!(debug_assertions) && state.closed {
+            // Don't poison the lock over a usage error.
+            drop(state);
+            panic!("released {} resources into a closed semaphore", amount);
+        }
+        state
+    }
+
+    /// Releases `amount` resources held by a guard stamped with `resets`,
+    /// unless the guard has been invalidated since.
+    fn release_stamped(&self, resets: u64, amount: isize) {
+        if amount == 0 {
+            return;
+        }
+        let state = self.lock.lock().unwrap();
+        if self.resets.load(Ordering::Relaxed) != resets {
+            return;
+        }
+        self.release_locked(state, amount);
+    }
+
+    fn release_locked(&self, mut state: MutexGuard<'_, SemaphoreState>, amount: isize) {
+        state.count = match state.count.checked_add(amount) {
+            Some(count) => count,
+            None => {
+                // Don't poison the lock: the count is still intact.
+                drop(state);
+                panic!(
+                    "releasing {} resources would overflow the semaphore count",
+                    amount
+                );
+            }
+        };
+        self.unlock(state);
+    }
+
+    /// Publishes the count to `count_hint`, counting a new version if it
+    /// changed.
+    fn publish(&self, state: &mut SemaphoreState) {
+        if self.count_hint.swap(state.count, Ordering::Relaxed) != state.count {
+            state.version += 1;
+        }
+    }
+
+    /// Unlocks `state` after the count changed, waking parked threads if one of
+    /// them may now proceed.
+    ///
+    /// Waking waiters that would only go back to sleep is wasted work for
+    /// every one of them, so this doesn't notify unless someone can proceed.
+    fn unlock(&self, mut state: MutexGuard<SemaphoreState>) {
+        // Claimers of granted reservations have to be woken too.
+        let granted = state.grant_reservations();
+        self.publish(&mut state);
+        let soft_limit_hit = state.cross_soft_limit();
+        if state.deferring > 0 {
+            // Whoever we'd wake is woken along with everyone else once the
+            // scopes are gone.
+            if granted || state.can_wake() {
+                state.deferred = true;
+            }
+            drop(state);
+        } else if granted || state.can_wake() {
+            // Claimers of granted reservations aren't told apart from other
+            // waiters, so they all have to be woken.
+            let threads = if granted {
+                None
+            } else {
+                state.budget_wakeups()
+            };
+            let head = state.head_thread();
+            // Woken tasks re-register if they still can't proceed when polled.
+            let mut wakers = wait_list::WakeList::new();
+            state.wakers.take_wakers(&mut wakers);
+            #[cfg(feature = "debug")]
+            self.acquirers.woken();
+            drop(state);
+            match threads {
+                None => self.parker.unpark_all(),
+                Some(0) => {}
+                Some(n) => self.parker.unpark_some(n),
+            }
+            if let Some(head) = head {
+                head.unpark();
+            }
+            self.wake_tasks(&mut wakers);
+        } else {
+            drop(state);
+        }
+        if let Some(count) = soft_limit_hit {
+            if let Some(ref callback) = *self.soft_limit_callback.lock().unwrap() {
+                callback(count);
+            }
+        }
+    }
+
+    /// Unlocks `state` and wakes every parked thread and pending task, for
+    /// changes other than to the count that waiters have to notice.
+    fn wake_all(&self, mut state: MutexGuard<SemaphoreState>) {
+        self.publish(&mut state);
+        let queued = match state.fair {
+            Some(ref queue) => queue.threads(),
+            None => Vec::new(),
+        };
+        let mut wakers = wait_list::WakeList::new();
+        state.wakers.take_wakers(&mut wakers);
+        #[cfg(feature = "debug")]
+        self.acquirers.woken();
+        drop(state);
+        self.parker.unpark_all();
+        for thread in queued {
+            thread.unpark();
+        }
+        self.wake_tasks(&mut wakers);
+    }
+
+    /// Wakes the tasks whose wakers were taken from the state into `wakers`,
+    /// relocking for the next batch as long as there may be more.
+    fn wake_tasks(&self, wakers: &mut wait_list::WakeList) {
+        loop {
+            let full = wakers.is_full();
+            wakers.wake_all();
+            if !full {
+                return;
+            }
+            // Anyone who registered since is woken too, which they can take
+            // as a spurious wakeup.
+            let mut state = self.lock.lock().unwrap();
+            state.wakers.take_wakers(wakers);
+        }
+    }
+
+    /// Parks until `ready` holds for the state or `deadline` passes, returning
+    /// the relocked state and whether `ready` held.
+    fn wait_until<'a, F>(
+        &'a self,
+        state: MutexGuard<'a, SemaphoreState>,
+        deadline: Option<Instant>,
+        ready: F,
+    ) -> (MutexGuard<'a, SemaphoreState>, bool)
+    where
+        F: FnMut(&SemaphoreState) -> bool,
+    {
+        self.wait_with(&*self.parker, state, deadline, ready)
+    }
+
+    /// Like `wait_until`, but parks with `parker` rather than the semaphore's.
+    fn wait_with<'a, F>(
+        &'a self,
+        parker: &dyn Parker,
+        state: MutexGuard<'a, SemaphoreState>,
+        deadline: Option<Instant>,
+        ready: F,
+    ) -> (MutexGuard<'a, SemaphoreState>, bool)
+    where
+        F: FnMut(&SemaphoreState) -> bool,
+    {
+        self.wait_with_checked(parker, state, deadline, ready)
+            .unwrap()
+    }
+
+    /// Like `wait_with`, but stops waiting and fails if the lock turns out to
+    /// be poisoned when it is taken again after parking. The error carries
+    /// the relocked state, with `false` for `ready` not having held.
+    fn wait_with_checked<'a, F>(
+        &'a self,
+        parker: &dyn Parker,
+        mut state: MutexGuard<'a, SemaphoreState>,
+        deadline: Option<Instant>,
+        mut ready: F,
+    ) -> LockResult<(MutexGuard<'a, SemaphoreState>, bool)>
+    where
+        F: FnMut(&SemaphoreState) -> bool,
+    {
+        let mut parked = false;
+        while !ready(&state) {
+            if parked {
+                // We may have been woken in place of a waiter that can
+                // proceed, so make sure one of them is woken instead.
+                if let Some(n) = state.budget_wakeups().filter(|&n| n > 0) {
+                    drop(state);
+                    self.parker.unpark_some(n);
+                    state = match self.lock.lock() {
+                        Ok(state) => state,
+                        Err(poison) => return Err(PoisonError::new((poison.into_inner(), false))),
+                    };
+                    parked = false;
+                    continue;
+                }
+            }
+            // Recompute the remaining time on every wakeup so spurious and
+            // unrelated wakeups don't extend the total wait.
+            let timeout = match deadline {
+                None => None,
+                Some(deadline) => {
+                    let now = self.now();
+                    if now >= deadline {
+                        return Ok((state, false));
+                    }
+                    Some(deadline - now)
+                }
+            };
+            let parked_result = match (&self.clock, timeout) {
+                (Some(clock), Some(timeout)) => {
+                    clock.park_timeout(parker, &self.lock, state, timeout)
+                }
+                _ => parker.park(&self.lock, state, timeout),
+            };
+            state = match parked_result {
+                Ok(state) => state,
+                Err(poison) => return Err(PoisonError::new((poison.into_inner(), false))),
+            };
+            parked = true;
+            state.notified = state.notified.saturating_sub(1);
+            #[cfg(test)]
+            {
+                state.wakeups += 1;
+            }
+        }
+        Ok((state, true))
+    }
+
+    /// Returns the current time by the semaphore's clock.
+    fn now(&self) -> Instant {
+        match self.clock {
+            Some(ref clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+
+    fn acquire_many(&self, amount: isize) {
+        self.acquire_many_until(amount, None);
+    }
+
+    fn acquire_many_timeout(&self, amount: isize, timeout: Duration) -> bool {
+        // A timeout too large to represent is as good as no timeout at all.
+        self.acquire_many_until(amount, self.now().checked_add(timeout))
+    }
+
+    /// Blocks until `amount` resources are acquired, or returns `false` once
+    /// `deadline` passes.
+    fn acquire_many_until(&self, amount: isize, deadline: Option<Instant>) -> bool {
+        self.acquire_many_unless(amount, deadline, |_| false)
+    }
+
+    /// Like `acquire_many_until`, but also gives up without acquiring anything
+    /// as soon as `aborted` holds for the state.
+    ///
+    /// `aborted` is only checked with the lock held, so whatever makes it true
+    /// must do so under the lock and then unpark everyone for a parked
+    /// acquirer to notice.
+    fn acquire_many_unless<F>(&self, amount: isize, deadline: Option<Instant>, aborted: F) -> bool
+    where
+        F: Fn(&SemaphoreState) -> bool,
+    {
+        check_amount(amount);
+        if amount == 0 {
+            return true;
+        }
+        let state = self.lock_to_acquire(amount);
+        if aborted(&state) {
+            return false;
+        }
+        self.take_or_wait(state, amount, deadline, aborted)
+            .is_some()
+    }
+
+    /// Locks the state to acquire `amount` resources, panicking if that could
+    /// never succeed because it exceeds the maximum.
+    fn lock_to_acquire(&self, amount: isize) -> MutexGuard<'_, SemaphoreState> {
+        self.check_max(self.lock.lock().unwrap(), amount)
+    }
+
+    /// Panics, unlocking `state` first, if `amount` is more than a bounded
+    /// semaphore could ever have.
+    fn check_max<'a>(
+        &self,
+        state: MutexGuard<'a, SemaphoreState>,
+        amount: isize,
+    ) -> MutexGuard<'a, SemaphoreState> {
+        if let Some(max) = state.max {
+            if amount > max {
+                drop(state);
+                match self.name {
+                    Some(name) => panic!(
+                        "cannot acquire {} resources from the {} semaphore, which has a \
+                         maximum of {}",
+                        amount, name, max
+                    ),
+                    None => panic!(
+                        "cannot acquire {} resources from a semaphore with a maximum of {}",
+                        amount, max
+                    ),
+                }
+            }
+        }
+        state
+    }
+
+    /// Takes `amount` resources with `state` locked, first waiting for them
+    /// like `acquire_many_unless` if there aren't enough. Returns the
+    /// acquisition's place in `SemaphoreState::grants`, or `None` if it gave
+    /// up.
+    fn take_or_wait<'a, F>(
+        &'a self,
+        state: MutexGuard<'a, SemaphoreState>,
+        amount: isize,
+        deadline: Option<Instant>,
+        aborted: F,
+    ) -> Option<u64>
+    where
+        F: Fn(&SemaphoreState) -> bool,
+    {
+        self.take_or_wait_measured(state, amount, deadline, aborted, None)
+            .unwrap()
+    }
+
+    /// Like `take_or_wait`, also describing the wait in `diag` if the
+    /// resources are acquired. `diag` is left alone if they were available
+    /// right away.
+    ///
+    /// Fails, having given up without acquiring anything, if the lock was
+    /// poisoned while waiting.
+    fn take_or_wait_measured<'a, F>(
+        &'a self,
+        mut state: MutexGuard<'a, SemaphoreState>,
+        amount: isize,
+        deadline: Option<Instant>,
+        aborted: F,
+        diag: Option<&mut AcquireDiag>,
+    ) -> Result<Option<u64>, PoisonError<()>>
+    where
+        F: Fn(&SemaphoreState) -> bool,
+    {
+        if !state.may_take(amount, None) {
+            let since = diag.as_ref().map(|_| Instant::now());
+            #[cfg(feature = "metrics")]
+            self.metrics.fall();
+            #[cfg(feature = "stats")]
+            let start = Instant::now();
+            state.add_waiter(amount);
+            let ticket = state.join_queue(amount);
+            state.queue_thread(ticket);
+            let arrival = state.arrive(amount);
+            // Only a wait that can never time out is a candidate for deadlock.
+            #[cfg(feature = "debug")]
+            let detecting = deadline.is_none() && self.acquirers.detects_deadlocks();
+            #[cfg(feature = "debug")]
+            let mut deadlock = None;
+            // A queued thread is woken on its own when it reaches the head of
+            // the queue, rather than with everyone else.
+            let parker: &dyn Parker = match ticket {
+                Some(_) => &fair::QueuedParker,
+                None => &*self.parker,
+            };
+            let waited = self.wait_with_checked(parker, state, deadline, |state| {
+                if state.may_take_arrived(amount, ticket, arrival) || aborted(state) {
+                    return true;
+                }
+                // Check again on every wakeup, since a wakeup drops us from
+                // the wait-for graph.
+                #[cfg(feature = "debug")]
+                if detecting {
+                    if let Err(report) = self.acquirers.wait() {
+                        deadlock = Some(report);
+                        return true;
+                    }
+                }
+                false
+            });
+            // A poisoned lock still guards intact bookkeeping, which we have
+            // to undo before giving up.
+            let (poisoned, (relocked, woken)) = match waited {
+                Ok(waited) => (false, waited),
+                Err(poison) => (true, poison.into_inner()),
+            };
+            state = relocked;
+            state.remove_waiter(amount);
+            #[cfg(feature = "debug")]
+            if detecting {
+                self.acquirers.stop_waiting();
+            }
+            #[cfg(feature = "debug")]
+            if let Some(report) = deadlock {
+                state.leave_queue(ticket, false);
+                state.depart(arrival);
+                self.unlock(state);
+                panic!("{}", report);
+            }
+            if poisoned || !woken || aborted(&state) {
+                state.leave_queue(ticket, false);
+                state.depart(arrival);
+                // We may have been at the head of a fair queue, holding up
+                // whoever is behind us.
+                self.unlock(state);
+                if poisoned {
+                    return Err(PoisonError::new(()));
+                }
+                return Ok(None);
+            }
+            if let (Some(diag), Some(since)) = (diag, since) {
+                let now = Instant::now();
+                let queued = match (state.fair.as_ref(), ticket) {
+                    (Some(queue), Some(ticket)) => queue.diag(ticket, now),
+                    _ => None,
+                };
+                // Without a queue, there was nobody ahead to wait behind.
+                *diag = queued.unwrap_or(AcquireDiag {
+                    head_wait: now - since,
+                    ..AcquireDiag::default()
+                });
+            }
+            state.leave_queue(ticket, true);
+            state.take(amount, arrival);
+            state.depart(arrival);
+            #[cfg(feature = "stats")]
+            state.waits.record(start.elapsed());
+        } else {
+            #[cfg(feature = "metrics")]
+            self.metrics.hit();
+            #[cfg(feature = "stats")]
+            state.waits.record(Duration::from_secs(0));
+            state.count -= amount;
+        }
+        let grant = state.grants;
+        state.grants += 1;
+        self.unlock(state);
+        Ok(Some(grant))
+    }
+
+    fn try_acquire_many(&self, amount: isize) -> Result<(), TryAcquireError> {
+        check_amount(amount);
+        let mut state = self.lock.lock().unwrap();
+        if state.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        if amount == 0 {
+            return Ok(());
+        }
+        if !state.may_take(amount, None) {
+            return Err(TryAcquireError::WouldBlock);
+        }
+        state.count -= amount;
+        self.unlock(state);
+        Ok(())
     }
 }
 
@@ -35,7 +974,6 @@ impl RawSemaphore {
 /// greater than 1. If a thread acquires 4 resources, the thread will block until
 /// the counter is 4 or greater.  Each release will increment the counter and
 /// unblock any threads if necessary.
-
 ///
 /// # Examples
 ///
@@ -54,7 +992,7 @@ impl RawSemaphore {
 ///     let _guard = sem.access();
 ///     // ...
 /// } // resource is released here
-
+///
 /// {
 ///     let _guard = sem.access_many(3);
 ///     // ...
@@ -75,6 +1013,11 @@ pub struct Semaphore {
 pub struct SemaphoreGuard {
     sem: Arc<RawSemaphore>,
     amount: isize,
+    generation: u64,
+    /// `RawSemaphore::resets` when the guard was created.
+    resets: u64,
+    #[cfg(feature = "debug")]
+    acquirer: u64,
 }
 
 impl Semaphore {
@@ -84,10 +1027,175 @@ impl Semaphore {
     /// call to `acquire` or `access` will block until at least one resource is
     /// available. It is valid to initialize a semaphore with a negative count.
     pub fn new(count: isize) -> Semaphore {
+        Semaphore::from_state(SemaphoreState::new(count))
+    }
+
+    /// Creates a new semaphore with the initial count specified, labeled
+    /// `name` in its diagnostics.
+    ///
+    /// The name shows in the semaphore's `Debug` output and in the panics
+    /// that report acquisitions which could never succeed, including the
+    /// reports of the `debug` feature's deadlock detection. It has no other
+    /// effect.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::with_name(4, "DB_POOL");
+    /// assert_eq!(sem.name(), Some("DB_POOL"));
+    /// assert_eq!(format!("{:?}", sem), r#"Semaphore { name: "DB_POOL", count: 4 }"#);
+    /// ```
+    pub fn with_name(count: isize, name: &'static str) -> Semaphore {
+        let mut sem = Semaphore::new(count);
+        let raw = Arc::get_mut(&mut sem.inner).expect("a new semaphore isn't shared");
+        raw.name = Some(name);
+        #[cfg(feature = "debug")]
+        raw.acquirers.set_name(name);
+        sem
+    }
+
+    /// Returns the name the semaphore was created with by `with_name`, if
+    /// any.
+    pub fn name(&self) -> Option<&'static str> {
+        self.inner.name
+    }
+
+    /// Creates a new fair semaphore with the initial count specified.
+    ///
+    /// A fair semaphore serves acquirers strictly in the order they started
+    /// waiting: while anyone is waiting, only the longest waiter may take
+    /// resources, even if there are enough for a later, smaller request, and
+    /// non-blocking attempts fail. This prevents large requests from being
+    /// starved by a stream of small ones, at the cost of throughput.
+    ///
+    /// A release wakes only the thread at the head of the queue, and only once
+    /// its request can be satisfied; the threads behind it sleep until their
+    /// own turn.
+    pub fn new_fair(count: isize) -> Semaphore {
+        let mut state = SemaphoreState::new(count);
+        state.fair = Some(fair::Queue::new());
+        Semaphore::from_state(state)
+    }
+
+    /// Creates a new fair semaphore with the initial count specified, which
+    /// turns acquirers away rather than queue more than `max_waiters` of them.
+    ///
+    /// This sheds load once the queue is full: `acquire_admitted` fails with
+    /// `QueueFull` instead of waiting. Other ways of acquiring still join the
+    /// queue regardless, and count towards its length. See `new_fair`.
+    pub fn new_fair_with_max_waiters(count: isize, max_waiters: usize) -> Semaphore {
+        let mut state = SemaphoreState::new(count);
+        state.fair = Some(fair::Queue::with_max_len(max_waiters));
+        Semaphore::from_state(state)
+    }
+
+    /// Creates a new `LockFreeSemaphore` with the initial count specified.
+    ///
+    /// This is a separate, explicitly chosen implementation for workloads that
+    /// are almost never contended: it avoids the mutex and condition variable
+    /// entirely, but waiting threads spin and yield rather than sleep. See
+    /// `LockFreeSemaphore` for details.
+    pub fn new_lockfree(count: isize) -> LockFreeSemaphore {
+        LockFreeSemaphore::new(count)
+    }
+
+    /// Creates a new semaphore with the initial count specified and a total
+    /// capacity of `max` resources.
+    ///
+    /// Knowing the capacity lets the semaphore catch requests that could never
+    /// be satisfied: blocking acquisitions of more than `max` resources panic
+    /// instead of waiting forever. Semaphores created with `new` have no
+    /// capacity, so the same mistake just blocks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is greater than `max`.
+    pub fn bounded(count: isize, max: isize) -> Semaphore {
+        assert!(
+            count <= max,
+            "initial count {} exceeds the maximum of {}",
+            count,
+            max
+        );
+        let mut state = SemaphoreState::new(count);
+        state.max = Some(max);
+        Semaphore::from_state(state)
+    }
+
+    /// Creates a new semaphore with the initial count specified, which blocks
+    /// and wakes threads using `parker` rather than the default condition
+    /// variable.
+    ///
+    /// This is for integrating with custom scheduling machinery; see `Parker`.
+    /// `ThreadParker` is provided as an alternative to the default
+    /// `CondvarParker`.
+    pub fn with_parker<P: Parker + 'static>(count: isize, parker: P) -> Semaphore {
+        Semaphore::build(SemaphoreState::new(count), Box::new(parker))
+    }
+
+    /// Creates a new semaphore with the initial count specified, which times
+    /// its timeouts by `clock` rather than the system clock.
+    ///
+    /// This is for testing timeouts deterministically; see `Clock`.
+    pub fn with_clock<C: Clock + 'static>(count: isize, clock: C) -> Semaphore {
+        Semaphore::build_with_clock(
+            SemaphoreState::new(count),
+            Box::new(CondvarParker::new()),
+            Some(Box::new(clock)),
+        )
+    }
+
+    /// Creates a new semaphore with the initial count specified, which waits
+    /// using `backend`.
+    ///
+    /// This picks one of the provided `Parker`s at runtime, so one binary can
+    /// be configured to use whichever suits the deployment.
+    pub fn with_backend(count: isize, backend: Backend) -> Semaphore {
+        Semaphore::build(SemaphoreState::new(count), backend.parker())
+    }
+
+    /// Creates a new semaphore starting with as many resources as `sem` has
+    /// available, to take over from a Tokio semaphore.
+    ///
+    /// Only available with the `tokio` feature. See the `bridge` module for
+    /// ways to use both kinds of semaphore at once.
+    #[cfg(feature = "tokio")]
+    pub fn from_tokio_permits(sem: &tokio::sync::Semaphore) -> Semaphore {
+        // Tokio caps its permits far below `isize::MAX`.
+        Semaphore::new(sem.available_permits() as isize)
+    }
+
+    fn from_state(state: SemaphoreState) -> Semaphore {
+        Semaphore::build(state, Box::new(CondvarParker::new()))
+    }
+
+    fn build(state: SemaphoreState, parker: Box<dyn Parker>) -> Semaphore {
+        Semaphore::build_with_clock(state, parker, None)
+    }
+
+    fn build_with_clock(
+        state: SemaphoreState,
+        parker: Box<dyn Parker>,
+        clock: Option<Box<dyn Clock>>,
+    ) -> Semaphore {
         Semaphore {
             inner: Arc::new(RawSemaphore {
-                lock: Mutex::new(count),
-                cvar: Condvar::new(),
+                count_hint: AtomicIsize::new(state.count),
+                generation: AtomicU64::new(0),
+                resets: AtomicU64::new(0),
+                soft_limit_callback: Mutex::new(None),
+                clock,
+                leases: lease::Leases::new(),
+                name: None,
+                initial: state.count,
+                lock: Mutex::new(state),
+                parker,
+                #[cfg(feature = "debug")]
+                acquirers: debug::Registry::new(),
+                #[cfg(feature = "metrics")]
+                metrics: metrics::Counters::new(),
             }),
         }
     }
@@ -98,11 +1206,77 @@ impl Semaphore {
     /// This method will block until the internal count of the semaphore is at
     /// least 1.
     pub fn acquire(&self) {
-        let mut count = self.inner.lock.lock().unwrap();
-        while *count <= 0 {
-            count = self.inner.cvar.wait(count).unwrap();
+        self.inner.acquire_many(1);
+    }
+
+    /// Acquires a resource of this semaphore like `acquire`, returning its
+    /// place in the order in which the semaphore's blocking acquisitions were
+    /// granted.
+    ///
+    /// Acquisitions that block the current thread, through `acquire`, `access`
+    /// and their variants, each take the next number, starting from zero;
+    /// non-blocking and async ones don't. Comparing the numbers seen by
+    /// different threads shows the order they were served in, which for a
+    /// semaphore from `new_fair` should match the order they started waiting.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new_fair(2);
+    /// assert_eq!(sem.acquire_seq(), 0);
+    /// sem.acquire();
+    /// sem.release_many(2);
+    /// assert_eq!(sem.acquire_seq(), 2);
+    /// ```
+    pub fn acquire_seq(&self) -> u64 {
+        let state = self.inner.lock_to_acquire(1);
+        self.inner.take_or_wait(state, 1, None, |_| false).unwrap()
+    }
+
+    /// Acquires a resource of this semaphore like `acquire`, unless the
+    /// semaphore is closed before one is available.
+    ///
+    /// Returns `TryAcquireError::Closed`, without acquiring anything, if the
+    /// semaphore was already closed or is closed while waiting. It never
+    /// returns `TryAcquireError::WouldBlock`.
+    pub fn acquire_checked(&self) -> Result<(), TryAcquireError> {
+        if self
+            .inner
+            .acquire_many_unless(1, None, |state| state.closed)
+        {
+            Ok(())
+        } else {
+            Err(TryAcquireError::Closed)
         }
-        *count -= 1;
+    }
+
+    /// Acquires a resource of this semaphore like `acquire`, unless its lock
+    /// has been poisoned.
+    ///
+    /// The lock is poisoned when a thread panics while holding it, such as
+    /// inside a `Parker` that panics. Every other method then panics too,
+    /// which is a poor fit for a semaphore used as a latch: the state behind
+    /// the lock is only a count, so consumers can usually carry on or give up
+    /// gracefully instead. This returns an error, without acquiring anything,
+    /// if the lock was poisoned when called or becomes poisoned while the
+    /// current thread is waiting.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let latch = Semaphore::new(0);
+    /// latch.release();
+    /// assert!(latch.wait_checked().is_ok());
+    /// ```
+    pub fn wait_checked(&self) -> Result<(), PoisonError<()>> {
+        let state = match self.inner.lock.lock() {
+            Ok(state) => state,
+            Err(_) => return Err(PoisonError::new(())),
+        };
+        let state = self.inner.check_max(state, 1);
+        self.inner
+            .take_or_wait_measured(state, 1, None, |_| false, None)
+            .map(|_| ())
     }
 
     /// Acquires one or more resources of this semaphore, blocking the current thread until
@@ -110,160 +1284,4650 @@ impl Semaphore {
     ///
     /// This method will block until the internal count of the semaphore is at
     /// least `amount`.
+    ///
+    /// The resources are taken all at once. While waiting, none of them are
+    /// held, so two threads each acquiring more than half of the count can't
+    /// deadlock each other the way repeated calls to `acquire` can, where each
+    /// ends up holding part of what the other needs. See `acquire_each` for the
+    /// piecemeal behaviour.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative, or if this is a bounded semaphore and
+    /// `amount` exceeds its maximum, since the call could never return. The
+    /// timed acquisition methods panic in the same cases, and the other
+    /// acquisition methods panic on negative amounts too.
+    ///
+    /// Acquiring zero resources returns at once without taking the lock, so
+    /// it synchronizes with nothing; use `checkpoint` for that.
     pub fn acquire_many(&self, amount: isize) {
-        if amount == 0 {
-            return;
-        }
-        let mut count = self.inner.lock.lock().unwrap();
-        while *count < amount {
-            count = self.inner.cvar.wait(count).unwrap();
-        }
-        *count -= amount;
+        self.inner.acquire_many(amount);
     }
 
-    /// Release a resource from this semaphore.
+    /// Takes and releases the semaphore's lock without changing anything.
     ///
-    /// This will increment the number of resources in this semaphore by 1 and
-    /// will notify any pending waiters in `acquire` or `access` if necessary.
-    pub fn release(&self) {
-        *self.inner.lock.lock().unwrap() += 1;
-        self.inner.cvar.notify_all();
+    /// Everything another thread did before its last acquisition or release
+    /// that took the lock happens before whatever follows the checkpoint, and
+    /// everything before the checkpoint happens before the lock's next
+    /// holder continues. This never blocks on the count, and wakes nobody.
+    pub fn checkpoint(&self) {
+        drop(self.inner.lock.lock().unwrap());
     }
 
-    /// Release one or more resources from this semaphore.
-    ///
-    /// This will increment the number of resources in this semaphore by 1 and
-    /// will notify any pending waiters in `acquire` or `access` if necessary.
-    pub fn release_many(&self, amount: isize) {
-        self.inner.release_many(amount);
+    /// Like `checkpoint`, then yields the current thread's time slice, so
+    /// threads that were waiting to run get a chance before it continues.
+    pub fn checkpoint_and_yield(&self) {
+        self.checkpoint();
+        thread::yield_now();
     }
 
-    /// Acquires a resource of this semaphore, returning an RAII guard to
-    /// release the semaphore when dropped.
+    /// Yields the current thread while holding the semaphore's lock, then
+    /// releases it, as a scheduling point for stress tests of code built on
+    /// the semaphore.
     ///
-    /// This function is semantically equivalent to an `acquire` followed by a
-    /// `release` when the guard returned is dropped.
-    pub fn access(&self) -> SemaphoreGuard {
-        self.acquire();
-        SemaphoreGuard {
-            sem: Arc::clone(&self.inner),
-            amount: 1,
-        }
+    /// Yielding with the lock held lets other threads run into it and block
+    /// on it, which makes interleavings where they contend for the lock more
+    /// likely. Under loom, the yield is loom's, so the model explores the
+    /// other threads running at that point. The count is left alone and
+    /// nobody is woken. Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn yield_through_lock(&self) {
+        let state = self.inner.lock.lock().unwrap();
+        sync::yield_now();
+        drop(state);
     }
 
-    /// Acquires one or more resources of this semaphore, returning an RAII guard to
-    /// release the semaphore when dropped.
+    /// Acquires a resource of this semaphore, blocking the current thread until
+    /// it can do so or until `timeout` has elapsed.
     ///
-    /// This function is semantically equivalent to an `acquire_many(n)` followed by a
-    /// `release_many(n)` when the guard returned is dropped.
-    pub fn access_many(&self, amount: isize) -> SemaphoreGuard {
-        self.acquire_many(amount);
-        SemaphoreGuard {
-            sem: Arc::clone(&self.inner),
-            amount: amount,
-        }
+    /// Returns `true` if the resource was acquired.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.inner.acquire_many_timeout(1, timeout)
     }
-}
 
-impl Drop for SemaphoreGuard {
-    fn drop(&mut self) {
-        if self.amount == 0 {
+    /// Acquires a resource like `acquire`, but panics if that blocks for
+    /// longer than `max_block`.
+    ///
+    /// This is an assertion for tests and staging builds, where a wait that
+    /// long should never happen: it turns a hang into a failure whose message
+    /// names the semaphore, if it has a name, and shows the count and how
+    /// many others were waiting. Production code that can tolerate slow
+    /// acquisitions should use `acquire_timeout` and handle the timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no resource could be acquired within `max_block`.
+    pub fn acquire_asserting(&self, max_block: Duration) {
+        if self.acquire_timeout(max_block) {
             return;
         }
-        self.sem.release_many(self.amount);
+        let state = self.inner.lock.lock().unwrap();
+        let (count, waiting) = (state.count, state.waiting.values().sum::<usize>());
+        drop(state);
+        let sem = match self.inner.name {
+            Some(name) => format!("the {} semaphore", name),
+            None => String::from("a semaphore"),
+        };
+        panic!(
+            "acquiring from {} blocked for more than {:?}: the count is {} and {} others \
+             are waiting",
+            sem, max_block, count, waiting
+        );
     }
-}
 
-#[cfg(test)]
+    /// Acquires a resource, blocking the current thread until one is
+    /// available or a message arrives on `rx`, whichever comes first.
+    ///
+    /// Returns `Either::Left(())` with the resource acquired, or
+    /// `Either::Right` with the message, having acquired nothing. This suits
+    /// workers that are told to shut down over a channel.
+    ///
+    /// A semaphore can't wait on a channel, so this waits on the semaphore in
+    /// slices of `RECV_POLL_INTERVAL`, checking for a message in between. A
+    /// message can therefore take up to that long to be noticed, while a
+    /// released resource is taken straight away. Once every sender is gone,
+    /// this just waits for the resource.
+    pub fn acquire_or_recv<T>(&self, rx: &Receiver<T>) -> Either<(), T> {
+        loop {
+            match rx.try_recv() {
+                Ok(message) => return Either::Right(message),
+                Err(TryRecvError::Disconnected) => {
+                    self.acquire();
+                    return Either::Left(());
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+            if self.acquire_timeout(RECV_POLL_INTERVAL) {
+                return Either::Left(());
+            }
+        }
+    }
+
+    /// Acquires one or more resources of this semaphore, blocking the current
+    /// thread until it can do so or until `timeout` has elapsed.
+    ///
+    /// Returns `true` if the resources were acquired. Resources are never
+    /// partially acquired: on timeout the count is left untouched.
+    pub fn acquire_many_timeout(&self, amount: isize, timeout: Duration) -> bool {
+        self.inner.acquire_many_timeout(amount, timeout)
+    }
+
+    /// Blocks the current thread until `pred` returns `true` for the count of
+    /// this semaphore, or until `timeout` has elapsed, without acquiring
+    /// anything.
+    ///
+    /// Note that, unlike `Condvar::wait_timeout_while`, this waits *until* the
+    /// predicate holds. `pred` is re-evaluated under the semaphore's lock each
+    /// time the count changes. Returns whether the predicate was satisfied.
+    ///
+    /// This is the loop behind the timed acquisition methods, exposed for
+    /// building other kinds of waits:
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    /// use std::time::Duration;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// // Wait for the semaphore to drain, which never happens here.
+    /// assert!(!sem.wait_timeout_while(Duration::from_millis(10), |count| count <= 0));
+    /// assert!(sem.wait_timeout_while(Duration::from_millis(10), |count| count == 1));
+    /// ```
+    pub fn wait_timeout_while(&self, timeout: Duration, pred: impl Fn(isize) -> bool) -> bool {
+        self.wait_count_until(self.inner.now().checked_add(timeout), pred)
+    }
+
+    /// Blocks the current thread until exactly `k` resources are out.
+    ///
+    /// Starting from `initial()`, what counts as out follows the semaphore
+    /// through `resize`, which leaves acquired resources out, and through
+    /// `set_count` and `bump_generation`, after which nothing is. `restore`
+    /// puts back how many were out at the capture along with the count.
+    ///
+    /// The condition is checked again every time the count changes, so it
+    /// returns only if the count is exactly right at one of those moments.
+    /// A count that passes over the right value between two checks is
+    /// missed, and then this waits for it to come back. This is for test and
+    /// startup sequencing, such as waiting until the expected number of
+    /// workers have each acquired a resource.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let sem = Arc::new(Semaphore::new(4));
+    /// let workers: Vec<_> = (0..3)
+    ///     .map(|_| {
+    ///         let sem = sem.clone();
+    ///         thread::spawn(move || sem.acquire())
+    ///     })
+    ///     .collect();
+    /// sem.wait_outstanding(3);
+    /// assert_eq!(sem.available_permits(), 1);
+    /// # for worker in workers { worker.join().unwrap(); }
+    /// ```
+    pub fn wait_outstanding(&self, k: isize) {
+        self.wait_state_until(None, |state| state.outstanding() == Some(k));
+    }
+
+    /// Blocks the current thread like `wait_outstanding` until exactly `k`
+    /// resources are out, or until `timeout` has elapsed. Returns whether
+    /// exactly `k` were out.
+    pub fn wait_outstanding_timeout(&self, k: isize, timeout: Duration) -> bool {
+        self.wait_state_until(self.inner.now().checked_add(timeout), |state| {
+            state.outstanding() == Some(k)
+        })
+    }
+
+    /// Blocks until `pred` holds for the count, or `deadline` passes, without
+    /// acquiring anything. Returns whether the predicate was satisfied.
+    fn wait_count_until(&self, deadline: Option<Instant>, pred: impl Fn(isize) -> bool) -> bool {
+        self.wait_state_until(deadline, |state| pred(state.count))
+    }
+
+    /// Like `wait_count_until`, with `pred` given the whole state.
+    fn wait_state_until(
+        &self,
+        deadline: Option<Instant>,
+        pred: impl FnMut(&SemaphoreState) -> bool,
+    ) -> bool {
+        let mut state = self.inner.lock.lock().unwrap();
+        state.watchers += 1;
+        let (mut state, satisfied) = self.inner.wait_until(state, deadline, pred);
+        state.watchers -= 1;
+        satisfied
+    }
+
+    /// Returns a watcher for changes to the count, which can block until the
+    /// next one without polling.
+    ///
+    /// Like a watch channel, the watcher sees every change made after it was
+    /// created or last woke, but if several happen in between it only
+    /// reports the latest count. See `CountWatcher::wait_change`.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(0);
+    /// let watcher = sem.subscribe();
+    /// sem.release_many(2);
+    /// // The change was made before waiting, so this returns at once.
+    /// assert_eq!(watcher.wait_change(), 2);
+    /// ```
+    pub fn subscribe(&self) -> CountWatcher<'_> {
+        let version = self.inner.lock.lock().unwrap().version;
+        CountWatcher {
+            sem: self,
+            seen: Cell::new(version),
+        }
+    }
+
+    /// Acquires a resource of this semaphore like `acquire`, unless `link` is
+    /// broken first.
+    ///
+    /// Returns `false` without acquiring anything if `link` is already broken
+    /// or breaks while this call is blocked, which wakes it immediately. This
+    /// ties a wait to the lifetime of some other resource, such as the sender
+    /// half of a channel the permit was going to be used with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `link` was created by a different semaphore.
+    pub fn acquire_linked(&self, link: &LinkToken) -> bool {
+        assert!(
+            link.belongs_to(&self.inner),
+            "link token used with a semaphore other than the one that created it"
+        );
+        self.inner
+            .acquire_many_unless(1, None, |_| link.is_broken())
+    }
+
+    /// Creates a link for `acquire_linked`: an owner which breaks the link when
+    /// dropped, and a token to hand to acquirers.
+    pub fn link(&self) -> (LinkOwner, LinkToken) {
+        link::new(&self.inner)
+    }
+
+    /// Attempts to acquire a resource of this semaphore without blocking.
+    ///
+    /// Returns `true` if the resource was acquired, or `false` if the count is
+    /// not positive or the semaphore has been closed.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_checked().is_ok()
+    }
+
+    /// Attempts to acquire a resource of this semaphore up to `retries + 1`
+    /// times, spinning briefly between attempts, without ever parking.
+    ///
+    /// This is for when a resource is expected to be released imminently,
+    /// so parking and being woken would cost more than the wait. With zero
+    /// retries it is the same as `try_acquire`. A closed semaphore fails
+    /// straight away.
+    pub fn try_acquire_spin(&self, retries: u32) -> bool {
+        for _ in 0..retries {
+            match self.try_acquire_checked() {
+                Ok(()) => return true,
+                Err(TryAcquireError::Closed) => return false,
+                Err(TryAcquireError::WouldBlock) => hint::spin_loop(),
+            }
+        }
+        self.try_acquire()
+    }
+
+    /// Attempts to acquire one or more resources of this semaphore without blocking.
+    ///
+    /// Returns `true` if the resources were acquired, or `false` if the count is
+    /// less than `amount` or the semaphore has been closed.
+    pub fn try_acquire_many(&self, amount: isize) -> bool {
+        self.inner.try_acquire_many(amount).is_ok()
+    }
+
+    /// Attempts to acquire a resource of this semaphore without blocking,
+    /// reporting why the attempt failed.
+    ///
+    /// Returns `TryAcquireError::WouldBlock` if the count is not positive, or
+    /// `TryAcquireError::Closed` if the semaphore has been closed.
+    pub fn try_acquire_checked(&self) -> Result<(), TryAcquireError> {
+        self.inner.try_acquire_many(1)
+    }
+
+    /// Attempts to acquire `amount` resources like `try_acquire_many`, but
+    /// rejects amounts that could never be acquired instead of panicking.
+    ///
+    /// This is for amounts that come from users. A negative amount fails with
+    /// `AmountError::Negative`, and one above a bounded semaphore's maximum
+    /// with `AmountError::ExceedsMax`. Any other amount returns whether it
+    /// was acquired.
+    pub fn try_acquire_many_checked(&self, amount: isize) -> Result<bool, AmountError> {
+        if amount < 0 {
+            return Err(AmountError::Negative { amount });
+        }
+        let mut state = self.inner.lock.lock().unwrap();
+        if let Some(max) = state.max {
+            if amount > max {
+                return Err(AmountError::ExceedsMax { amount, max });
+            }
+        }
+        if state.closed {
+            return Ok(false);
+        }
+        if amount == 0 {
+            return Ok(true);
+        }
+        if !state.may_take(amount, None) {
+            return Ok(false);
+        }
+        state.count -= amount;
+        self.inner.unlock(state);
+        Ok(true)
+    }
+
+    /// Attempts to acquire `amount` resources without blocking, reporting how
+    /// many were missing if it fails.
+    ///
+    /// Returns `Err(deficit)`, where `deficit` is `amount` minus the count at
+    /// the time of the attempt, so a caller can decide whether retrying with
+    /// a smaller amount could succeed. The deficit exceeds `amount` when the
+    /// count is negative, and in a fair semaphore it includes whatever the
+    /// acquirers queued ahead are waiting for. A closed or paused semaphore
+    /// reports all of `amount` as missing, since nothing can be acquired from
+    /// it.
+    pub fn try_acquire_many_status(&self, amount: isize) -> Result<(), isize> {
+        check_amount(amount);
+        let mut state = self.inner.lock.lock().unwrap();
+        if state.closed || state.held_back() {
+            return Err(amount);
+        }
+        if !state.may_take(amount, None) {
+            // In a fair semaphore, everyone queued has to be served first.
+            let queued = state.fair.as_ref().map_or(0, |queue| queue.demand());
+            return Err(amount + queued - state.count);
+        }
+        state.count -= amount;
+        self.inner.unlock(state);
+        Ok(())
+    }
+
+    /// Release a resource from this semaphore.
+    ///
+    /// This will increment the number of resources in this semaphore by 1 and
+    /// will notify any pending waiters in `acquire` or `access` if necessary.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if the semaphore is closed; see `release_many`.
+    pub fn release(&self) {
+        self.inner.release_explicit(1);
+    }
+
+    /// Holds back the wakeups of every release while the returned scope is
+    /// alive, then wakes every waiter at once when it is dropped.
+    ///
+    /// Each release that lets a waiter proceed normally notifies waiters
+    /// straight away. A burst of releases, such as returning a batch of
+    /// resources one at a time, would then notify once per release; inside a
+    /// scope it only notifies once. The count still changes immediately, so
+    /// `try_acquire` and acquirers that don't have to wait see released
+    /// resources as usual.
+    ///
+    /// The scope applies to releases from every thread, and while any scope is
+    /// alive, parked waiters aren't woken at all, so keep scopes short.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(0);
+    /// {
+    ///     let _scope = sem.defer_notifies();
+    ///     for _ in 0..4 {
+    ///         sem.release();
+    ///     }
+    /// }
+    /// assert_eq!(sem.available_permits(), 4);
+    /// ```
+    pub fn defer_notifies(&self) -> ReleaseScope<'_> {
+        self.inner.lock.lock().unwrap().deferring += 1;
+        ReleaseScope { sem: self }
+    }
+
+    /// Releases a resource straight to the thread that has been blocked
+    /// acquiring the longest, so that no newcomer can take it first.
+    ///
+    /// A plain `release` adds the resource to the count, where whoever gets
+    /// to it first may take it. A stream of new acquirers can then keep beating
+    /// a waiting thread to every resource. Here, the resource is set aside
+    /// for the oldest waiter instead, without showing up in
+    /// `available_permits`. If that waiter wants more than has been handed
+    /// off, it may also take from the count; if it gives up, its handed off
+    /// resources pass to the next oldest waiter, or to the count once nobody
+    /// is waiting.
+    ///
+    /// Only threads blocked in a blocking acquisition count as waiting. With
+    /// none of them, or in a fair semaphore, which never lets anyone barge
+    /// in ahead of its queue anyway, this is the same as `release`.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `release_many` if the count would overflow, or, in debug
+    /// builds, if the semaphore is closed.
+    pub fn release_handoff(&self) {
+        let mut state = self.inner.check_open(self.inner.lock.lock().unwrap(), 1);
+        if state.arrivals.is_empty() {
+            self.inner.release_locked(state, 1);
+            return;
+        }
+        state.handed_off = match state.handed_off.checked_add(1) {
+            Some(handed_off) => handed_off,
+            None => {
+                drop(state);
+                panic!("releasing 1 resource would overflow the semaphore count");
+            }
+        };
+        self.inner.unlock(state);
+    }
+
+    /// Release one or more resources from this semaphore.
+    ///
+    /// This will increment the number of resources in this semaphore by 1 and
+    /// will notify any pending waiters in `acquire` or `access` if necessary.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count would overflow, leaving the count unchanged. The
+    /// same check applies when guards release their resources, so a guard can
+    /// only fail this way if extra resources were released while it was held.
+    ///
+    /// In debug builds, also panics if the semaphore is closed, as handing
+    /// resources back to a semaphore that is shutting down is almost always a
+    /// shutdown-ordering bug. Guards dropped after `close` release their
+    /// resources without complaint. Release builds skip the check.
+    pub fn release_many(&self, amount: isize) {
+        self.inner.release_explicit(amount);
+    }
+
+    /// Runs `f` to gather a burst of releases, then releases them all at
+    /// once, taking the lock and waking waiters only one time.
+    ///
+    /// Releases made through the `BatchReleaser` passed to `f` only add up:
+    /// the count doesn't change until `f` returns, so nobody can acquire the
+    /// resources before then. Compared to calling `release_many` for each,
+    /// this saves a lock acquisition and a round of wakeups per release. If
+    /// `f` panics, nothing is released.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(0);
+    /// sem.release_batch(|releaser| {
+    ///     for _ in 0..4 {
+    ///         releaser.release(1);
+    ///     }
+    ///     assert_eq!(sem.available_permits(), 0);
+    /// });
+    /// assert_eq!(sem.available_permits(), 4);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the releases would overflow the count.
+    pub fn release_batch<F: FnOnce(&mut BatchReleaser)>(&self, f: F) {
+        let mut releaser = BatchReleaser { amount: 0 };
+        f(&mut releaser);
+        self.inner.release_many(releaser.amount);
+    }
+
+    /// Releases `amount` resources like `release_many`, unless that would push
+    /// the count of a bounded semaphore past its maximum.
+    ///
+    /// Releasing more than was acquired usually means a resource was given
+    /// back twice. Rather than let the count creep above the capacity, this
+    /// refuses the release, leaving the count unchanged, and reports it so
+    /// the caller can propagate the error. Semaphores without a maximum
+    /// accept any release that fits in the count.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::bounded(1, 2);
+    /// assert!(sem.try_release_many(1).is_ok());
+    /// assert!(sem.try_release_many(1).is_err());
+    /// assert_eq!(sem.available_permits(), 2);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count would overflow, like `release_many`.
+    pub fn try_release_many(&self, amount: isize) -> Result<(), CapacityExceeded> {
+        let state = self.inner.lock.lock().unwrap();
+        if let Some(max) = state.max {
+            let exceeds = match state.count.checked_add(amount) {
+                Some(count) => count > max,
+                None => true,
+            };
+            if exceeds {
+                return Err(CapacityExceeded {
+                    amount,
+                    count: state.count,
+                    max,
+                });
+            }
+        }
+        if amount == 0 {
+            return Ok(());
+        }
+        self.inner.release_locked(state, amount);
+        Ok(())
+    }
+
+    /// Takes `amount` resources from this semaphore without blocking, pushing
+    /// the count below zero if there aren't that many available.
+    ///
+    /// This represents committing to more work than the semaphore allows,
+    /// to be paid back later with `repay`. It ignores the queue of a fair
+    /// semaphore and works on a closed or paused one. Until the count is paid
+    /// back above zero nobody else can acquire anything, so blocked acquirers
+    /// may wait longer than they otherwise would.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// sem.overcommit(3);
+    /// assert_eq!(sem.available_permits(), -2);
+    /// assert!(!sem.try_acquire());
+    /// sem.repay(3);
+    /// assert_eq!(sem.available_permits(), 1);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative or the count would overflow, leaving the
+    /// count unchanged.
+    pub fn overcommit(&self, amount: isize) {
+        if amount < 0 {
+            panic!(
+                "cannot overcommit a negative number of resources ({})",
+                amount
+            );
+        }
+        let mut state = self.inner.lock.lock().unwrap();
+        state.count = match state.count.checked_sub(amount) {
+            Some(count) => count,
+            None => {
+                drop(state);
+                panic!(
+                    "overcommitting {} resources would overflow the semaphore count",
+                    amount
+                );
+            }
+        };
+        self.inner.unlock(state);
+    }
+
+    /// Gives back `amount` resources taken with `overcommit`, waking waiters
+    /// who may now proceed.
+    ///
+    /// This is the same as `release_many`, named to pair with `overcommit`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative or the count would overflow, leaving the
+    /// count unchanged.
+    pub fn repay(&self, amount: isize) {
+        if amount < 0 {
+            panic!("cannot repay a negative number of resources ({})", amount);
+        }
+        self.inner.release_many(amount);
+    }
+
+    /// Returns the total capacity of this semaphore, or `None` if it was not
+    /// created with `bounded`.
+    pub fn max(&self) -> Option<isize> {
+        self.inner.lock.lock().unwrap().max
+    }
+
+    /// Changes the total capacity of a bounded semaphore to `new_max`, starting
+    /// a new generation.
+    ///
+    /// The count moves by the same amount as the capacity, so resources that
+    /// are currently acquired stay accounted for: outstanding guards still
+    /// release correctly afterwards, and once everything is released the count
+    /// equals `new_max`. Shrinking may leave the count negative until enough
+    /// resources come back. Waiters are woken if the count grew enough for
+    /// one of them to proceed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this semaphore was not created with `bounded`.
+    pub fn resize(&self, new_max: isize) {
+        let mut state = self.inner.lock.lock().unwrap();
+        let old_max = match state.max {
+            Some(max) => max,
+            None => {
+                drop(state);
+                panic!("resize called on a semaphore without a maximum");
+            }
+        };
+        let moved = new_max.checked_sub(old_max).and_then(|delta| {
+            Some((
+                state.count.checked_add(delta)?,
+                state.baseline.checked_add(delta)?,
+            ))
+        });
+        match moved {
+            Some((count, baseline)) => {
+                state.count = count;
+                state.baseline = baseline;
+            }
+            None => {
+                drop(state);
+                panic!("resizing to {} would overflow the semaphore count", new_max);
+            }
+        }
+        state.max = Some(new_max);
+        self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        self.inner.unlock(state);
+    }
+
+    /// Starts a new generation and invalidates every outstanding guard, for a
+    /// hard reset.
+    ///
+    /// Unlike after `resize`, guards from earlier generations release nothing
+    /// when dropped, since the resources they hold are no longer accounted
+    /// for in the count. Whoever resets the semaphore is responsible for
+    /// setting the count to match, or use `set_count` to do both at once.
+    ///
+    /// A guard acquired while another thread bumps the generation may be
+    /// tagged with either generation, so only reset a semaphore while nothing
+    /// is being acquired if the count has to stay exact.
+    pub fn bump_generation(&self) {
+        let mut state = self.inner.lock.lock().unwrap();
+        // The resources the guards hold are written off.
+        state.baseline = state.count;
+        self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        self.inner.resets.fetch_add(1, Ordering::Relaxed);
+        drop(state);
+    }
+
+    /// Resets the count to `count` and invalidates every outstanding guard
+    /// like `bump_generation`, so their releases can't push the new count
+    /// past what it was set to.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(2);
+    /// let stale = sem.access();
+    /// sem.set_count(5);
+    /// drop(stale);
+    /// assert_eq!(sem.available_permits(), 5);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is greater than the maximum of a semaphore created
+    /// with `bounded`, leaving the count and the guards unchanged.
+    pub fn set_count(&self, count: isize) {
+        let mut state = self.inner.lock.lock().unwrap();
+        if let Some(max) = state.max {
+            if count > max {
+                // Don't poison the lock: nothing has changed yet.
+                drop(state);
+                panic!("count {} exceeds the maximum of {}", count, max);
+            }
+        }
+        state.count = count;
+        state.baseline = count;
+        self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        self.inner.resets.fetch_add(1, Ordering::Relaxed);
+        self.inner.unlock(state);
+    }
+
+    /// Captures the semaphore's count, to be put back later with `restore`.
+    ///
+    /// This is for checkpointing and rolling back a deterministic simulation.
+    /// Only the count, and how many resources are out, are captured: who is
+    /// waiting, and the rest of the configuration, are not.
+    pub fn capture(&self) -> StateCapture {
+        let state = self.inner.lock.lock().unwrap();
+        StateCapture {
+            count: state.count,
+            baseline: state.baseline,
+        }
+    }
+
+    /// Sets the count back to what it was when `capture` was called, waking
+    /// waiters that can then proceed.
+    ///
+    /// Unlike `set_count`, this doesn't invalidate outstanding guards: guards
+    /// created before the restore still release their resources when
+    /// dropped, which can push the count above anything it was before. It is
+    /// meant for simulations where guard lifetimes are under control, such as
+    /// restoring only once every guard since the capture has been dropped.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(3);
+    /// let checkpoint = sem.capture();
+    /// sem.acquire_many(2);
+    /// sem.restore(checkpoint);
+    /// assert_eq!(sem.available_permits(), 3);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// The maximum isn't part of the capture, so a `resize` since then stays
+    /// in effect. Panics, leaving the count unchanged, if the captured count
+    /// is greater than the current maximum of a semaphore created with
+    /// `bounded`.
+    pub fn restore(&self, cap: StateCapture) {
+        let mut state = self.inner.lock.lock().unwrap();
+        if let Some(max) = state.max {
+            if cap.count > max {
+                drop(state);
+                panic!(
+                    "captured count {} exceeds the current maximum of {}",
+                    cap.count, max
+                );
+            }
+        }
+        state.count = cap.count;
+        state.baseline = cap.baseline;
+        self.inner.unlock(state);
+    }
+
+    /// Returns the semaphore's current generation, which starts at 0 and is
+    /// bumped every time it is reconfigured with `resize`, `set_count` or
+    /// `bump_generation`.
+    ///
+    /// Guards record the generation they were created in; see
+    /// `SemaphoreGuard::is_current`.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current count of this semaphore.
+    ///
+    /// The count may be negative if more resources have been acquired (or
+    /// released with a negative amount) than were available.
+    ///
+    /// This is a relaxed atomic read of a copy of the count, which doesn't
+    /// lock the semaphore, making it cheap enough for monitoring and for
+    /// deciding whether to attempt an acquisition at all. Treat it as a hint:
+    /// another thread may change the count as soon as it's read, and unless
+    /// something else (like joining the thread that released) orders the
+    /// read after a change, the value may be older than the latest one.
+    /// Acquisition always consults the authoritative count under the lock.
+    pub fn available_permits(&self) -> isize {
+        self.inner.count_hint.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count the semaphore was created with.
+    ///
+    /// Nothing changes it afterwards, so until the semaphore is resized or
+    /// its count is reset, `initial() - available_permits()` is how many
+    /// resources are out, or, if negative, how many more have been released
+    /// than were acquired. That works for semaphores without a maximum too.
+    /// `wait_outstanding` keeps track of what is out across those changes.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(5);
+    /// let _guard = sem.access_many(2);
+    /// assert_eq!(sem.initial() - sem.available_permits(), 2);
+    /// ```
+    pub fn initial(&self) -> isize {
+        self.inner.initial
+    }
+
+    /// Calls `f` with the semaphore locked, passing it a handle that reads the
+    /// count and takes resources within the same critical section.
+    ///
+    /// Nobody else can change the count while `f` runs, so a decision based on
+    /// what `f` reads still holds when it acts on it. This is the building
+    /// block for acquisition policies the semaphore doesn't provide, such as
+    /// taking whatever is available up to some limit. The lock is released
+    /// once `f` returns, waking anyone who needs to know.
+    ///
+    /// `f` must not use this semaphore in any other way, or it will deadlock.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(3);
+    /// // Take up to 5 resources, however many are available.
+    /// let taken = sem.with_count(|mut handle| {
+    ///     let n = handle.count().min(5);
+    ///     assert!(handle.try_take(n));
+    ///     n
+    /// });
+    /// assert_eq!((taken, sem.available_permits()), (3, 0));
+    /// sem.release_many(taken);
+    /// ```
+    pub fn with_count<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(CountHandle) -> R,
+    {
+        let mut state = self.inner.lock.lock().unwrap();
+        let result = f(CountHandle { state: &mut state });
+        self.inner.unlock(state);
+        result
+    }
+
+    /// Returns the current count together with the generation it belongs to.
+    ///
+    /// Unlike reading `available_permits` and `generation` separately, both
+    /// are read under the semaphore's lock, so a reconfiguration can't happen
+    /// in between. A monitor can compare the generation with the one it
+    /// expects before acting on the count, rather than act on a count that a
+    /// `resize` has already invalidated.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::bounded(4, 4);
+    /// sem.resize(2);
+    /// let snapshot = sem.available_permits_atomic_snapshot();
+    /// assert_eq!((snapshot.count, snapshot.generation), (2, 1));
+    /// ```
+    pub fn available_permits_atomic_snapshot(&self) -> PermitSnapshot {
+        let state = self.inner.lock.lock().unwrap();
+        PermitSnapshot {
+            count: state.count,
+            generation: self.inner.generation.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Caps how large a single guard may grow by expanding it, or removes the
+    /// cap with `None`.
+    ///
+    /// This guards against a runaway loop that keeps growing a guard: once a
+    /// guard reaches the cap, `SemaphoreGuard::try_expand` and
+    /// `SemaphoreGuard::expand_timeout` fail and `SemaphoreGuard::acquire_more`
+    /// panics. The cap is per guard and independent of the semaphore's
+    /// maximum; it limits neither how much a guard is created with nor how
+    /// much all guards hold together.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(10);
+    /// sem.set_max_guard_amount(Some(3));
+    /// let mut guard = sem.access_many(2);
+    /// assert!(guard.try_expand(1));
+    /// assert!(!guard.try_expand(1));
+    /// assert_eq!(guard.amount(), 3);
+    /// ```
+    pub fn set_max_guard_amount(&self, max: Option<isize>) {
+        self.inner.lock.lock().unwrap().max_guard = max;
+    }
+
+    /// Returns the cap set by `set_max_guard_amount`.
+    pub fn max_guard_amount(&self) -> Option<isize> {
+        self.inner.lock.lock().unwrap().max_guard
+    }
+
+    /// Calls `callback` whenever the count drops to `threshold` or below,
+    /// as an early warning before the semaphore runs out.
+    ///
+    /// The callback is edge-triggered: it's called with the new count the
+    /// first time it is at or below `threshold` after having been above it,
+    /// and then not again until the count has risen above `threshold` once
+    /// more. If the count is at or below `threshold` already, the callback
+    /// waits for the next crossing.
+    ///
+    /// The callback runs on whichever thread changed the count, after the
+    /// semaphore is unlocked, so it may use the semaphore. It must not call
+    /// `set_soft_limit` itself, which would deadlock. Setting a new soft limit
+    /// replaces the old one.
+    pub fn set_soft_limit<F>(&self, threshold: isize, callback: F)
+    where
+        F: Fn(isize) + Send + 'static,
+    {
+        *self.inner.soft_limit_callback.lock().unwrap() = Some(Box::new(callback));
+        let mut state = self.inner.lock.lock().unwrap();
+        state.soft_limit = Some(SoftLimit {
+            threshold,
+            armed: state.count > threshold,
+        });
+    }
+
+    /// Checks the semaphore's internal bookkeeping for consistency, such as a
+    /// bounded semaphore's count staying within its maximum.
+    ///
+    /// This is a defensive check for health endpoints and the like: an error
+    /// means a bug in this crate rather than misuse, and describes what was
+    /// found to be wrong.
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        self.inner.lock.lock().unwrap().check_invariants()
+    }
+
+    /// Closes this semaphore.
+    ///
+    /// After closing, the checked acquisition methods fail with
+    /// `TryAcquireError::Closed` and `try_acquire` returns `false`, and
+    /// threads blocked in `acquire_checked` are woken to fail the same way.
+    /// Dropping outstanding guards still releases their resources as usual,
+    /// but in debug builds `release` and `release_many` panic, since a
+    /// release by hand after closing is usually a shutdown-ordering bug.
+    pub fn close(&self) {
+        let mut state = self.inner.lock.lock().unwrap();
+        state.closed = true;
+        self.inner.wake_all(state);
+    }
+
+    /// Closes this semaphore like `close` and takes every resource that is
+    /// available, returning how many there were.
+    ///
+    /// This is a one-call shutdown: nothing more is granted, not even to the
+    /// unchecked acquisition methods, whose waiters keep waiting. Everyone
+    /// blocked in `acquire_checked` is woken and fails with
+    /// `TryAcquireError::Closed`, as does every later checked acquisition.
+    /// Resources released afterwards, for example by outstanding guards, can
+    /// still be acquired by the unchecked methods. A negative count is left
+    /// as it is, and 0 is returned.
+    ///
+    /// ```
+    /// use multi_semaphore::{Semaphore, TryAcquireError};
+    ///
+    /// let sem = Semaphore::new(3);
+    /// let _guard = sem.access();
+    /// assert_eq!(sem.drain_and_close(), 2);
+    /// assert_eq!(sem.acquire_checked(), Err(TryAcquireError::Closed));
+    /// ```
+    pub fn drain_and_close(&self) -> isize {
+        let mut state = self.inner.lock.lock().unwrap();
+        state.closed = true;
+        let drained = cmp::max(state.count, 0);
+        state.count -= drained;
+        self.inner.wake_all(state);
+        drained
+    }
+
+    /// Pauses this semaphore: until `resume` is called, no acquisition
+    /// succeeds, whatever the count. Blocking acquisitions wait and
+    /// non-blocking ones fail.
+    ///
+    /// This freezes the concurrency of whatever the semaphore guards, say
+    /// during an investigation, without touching the count. Releases still
+    /// work while paused, and are there to be acquired on resuming.
+    pub fn pause(&self) {
+        self.inner.lock.lock().unwrap().paused = true;
+    }
+
+    /// Resumes a semaphore paused with `pause`, waking waiters that can now
+    /// proceed.
+    pub fn resume(&self) {
+        let mut state = self.inner.lock.lock().unwrap();
+        state.paused = false;
+        self.inner.unlock(state);
+    }
+
+    /// Takes the semaphore offline or brings it back online, for resources
+    /// that go away for a while, such as a backend that is down.
+    ///
+    /// While offline, no acquisition succeeds whatever the count, like while
+    /// paused: blocking acquisitions wait and non-blocking ones fail. Work in
+    /// flight can still finish and release its resources, which count as
+    /// usual. Going back online wakes every waiter, and those the count can
+    /// satisfy proceed. Unlike `close`, this is temporary, and unlike `pause`,
+    /// it is meant for outages rather than debugging; the two are
+    /// independent.
+    pub fn set_online(&self, online: bool) {
+        let mut state = self.inner.lock.lock().unwrap();
+        if state.online == online {
+            return;
+        }
+        state.online = online;
+        if online {
+            self.inner.wake_all(state);
+        }
+    }
+
+    /// Returns `false` while the semaphore is offline; see `set_online`.
+    pub fn is_online(&self) -> bool {
+        self.inner.lock.lock().unwrap().online
+    }
+
+    /// Returns `true` if `close` has been called on this semaphore.
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock.lock().unwrap().closed
+    }
+
+    /// Acquires a resource of this semaphore like `acquire`, returning a
+    /// permit that has to be given back explicitly.
+    ///
+    /// This suits code that wants every resource's return to be spelled out:
+    /// dropping the permit instead of calling `Permit::give_back` panics.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// let permit = sem.take_permit();
+    /// assert_eq!(sem.available_permits(), 0);
+    /// permit.give_back(&sem);
+    /// assert_eq!(sem.available_permits(), 1);
+    /// ```
+    pub fn take_permit(&self) -> Permit {
+        self.inner.acquire_many(1);
+        Permit {
+            sem: Arc::clone(&self.inner),
+            given_back: false,
+        }
+    }
+
+    /// Carves a sub-pool of `n` resources out of this semaphore, blocking
+    /// until they are available, for budgeting a share of them to one
+    /// subsystem.
+    ///
+    /// The sub-pool is a semaphore of its own with a capacity of `n`: others
+    /// can't take its resources, and it can't take more of them. Once it is
+    /// dropped, and everything acquired from it has been released, all `n`
+    /// go back to this semaphore. See `SubSemaphore`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn sub_pool(&self, n: isize) -> SubSemaphore<'_> {
+        SubSemaphore::new(self, n)
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard to
+    /// release the semaphore when dropped.
+    ///
+    /// This function is semantically equivalent to an `acquire` followed by a
+    /// `release` when the guard returned is dropped.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access(&self) -> SemaphoreGuard {
+        self.acquire();
+        SemaphoreGuard::new(&self.inner, 1)
+    }
+
+    /// Acquires one or more resources of this semaphore, returning an RAII guard to
+    /// release the semaphore when dropped.
+    ///
+    /// This function is semantically equivalent to an `acquire_many(n)` followed by a
+    /// `release_many(n)` when the guard returned is dropped. Acquiring zero
+    /// resources returns a guard which does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `acquire_many`, so that no guard
+    /// holds a negative amount.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_many(&self, amount: isize) -> SemaphoreGuard {
+        self.acquire_many(amount);
+        SemaphoreGuard::new(&self.inner, amount)
+    }
+
+    /// Acquires a resource from this semaphore if one is available right away,
+    /// or else from `fallback`, blocking until it has one. The `bool` says
+    /// whether the resource came from `fallback`.
+    ///
+    /// This models primary and overflow capacity: work uses the primary pool
+    /// while it has room and spills over to the fallback otherwise. A guard
+    /// always releases to the semaphore it was acquired from, so dropping it
+    /// gives the resource back to whichever pool lent it.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let primary = Semaphore::new(1);
+    /// let overflow = Semaphore::new(2);
+    /// let (spilled, _a) = primary.acquire_with_fallback(&overflow);
+    /// assert!(!spilled);
+    /// let (spilled, _b) = primary.acquire_with_fallback(&overflow);
+    /// assert!(spilled);
+    /// assert_eq!(overflow.available_permits(), 1);
+    /// ```
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_with_fallback(&self, fallback: &Semaphore) -> (bool, SemaphoreGuard) {
+        match self.try_access() {
+            Some(guard) => (false, guard),
+            None => (true, fallback.access()),
+        }
+    }
+
+    /// Returns an iterator over `count` guards for one resource each,
+    /// acquiring them in chunks as it goes.
+    ///
+    /// Whenever the iterator has run out of acquired resources, it takes as
+    /// many of the remaining ones as are available at once, under a single
+    /// lock, and blocks for one only when none are. Each guard releases its
+    /// own resource, and the iterator yields exactly `count` guards before
+    /// ending. This saves a producer that acquires resources one at a time in
+    /// a tight loop from taking the lock for each of them. Resources acquired
+    /// in a chunk but not yet yielded are released when the iterator is
+    /// dropped.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(5);
+    /// let mut stream = sem.acquire_stream(3);
+    /// let first = stream.next().unwrap();
+    /// // The whole chunk was taken at once.
+    /// assert_eq!(sem.available_permits(), 2);
+    /// let rest: Vec<_> = stream.collect();
+    /// assert_eq!(rest.len(), 2);
+    /// drop(first);
+    /// assert_eq!(sem.available_permits(), 3);
+    /// ```
+    pub fn acquire_stream(&self, count: usize) -> AcquireStream<'_> {
+        AcquireStream {
+            sem: self,
+            remaining: count,
+            ready: 0,
+            generation: 0,
+            resets: 0,
+        }
+    }
+
+    /// Acquires a resource like `access`, also returning how the wait for it
+    /// went.
+    ///
+    /// This is for telling apart the reasons a slow acquisition was slow. On
+    /// a fair semaphore, the wait divides into the time spent behind others
+    /// in the queue and the time spent at its head waiting for resources to
+    /// be released, and the resources served to those ahead show how long
+    /// the queue was. On other semaphores nobody is ahead, so the whole wait
+    /// is time at the head and nothing is counted as passed. An acquisition
+    /// that doesn't wait reports zero for everything.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new_fair(1);
+    /// let (_guard, diag) = sem.access_diagnostic();
+    /// assert_eq!(diag.permits_passed_while_waiting, 0);
+    /// ```
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_diagnostic(&self) -> (SemaphoreGuard, AcquireDiag) {
+        let mut diag = AcquireDiag::default();
+        let state = self.inner.lock_to_acquire(1);
+        self.inner
+            .take_or_wait_measured(state, 1, None, |_| false, Some(&mut diag))
+            .unwrap();
+        (SemaphoreGuard::new(&self.inner, 1), diag)
+    }
+
+    /// Attempts to acquire a resource of this semaphore without blocking,
+    /// returning an RAII guard to release it when dropped.
+    ///
+    /// Returns `None` under the same conditions as `try_acquire`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn try_access(&self) -> Option<SemaphoreGuard> {
+        self.try_access_many(1)
+    }
+
+    /// Attempts to acquire one or more resources of this semaphore without
+    /// blocking, returning an RAII guard to release them when dropped.
+    ///
+    /// Returns `None` under the same conditions as `try_acquire_many`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn try_access_many(&self, amount: isize) -> Option<SemaphoreGuard> {
+        self.try_access_many_checked(amount).ok()
+    }
+
+    /// Acquires a resource of this semaphore, blocking for at most `timeout`,
+    /// and returns an RAII guard to release it when dropped.
+    ///
+    /// Returns `None` if the timeout elapsed first.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_timeout(&self, timeout: Duration) -> Option<SemaphoreGuard> {
+        self.access_many_timeout(1, timeout)
+    }
+
+    /// Acquires one or more resources of this semaphore, blocking for at most
+    /// `timeout`, and returns an RAII guard to release them when dropped.
+    ///
+    /// Returns `None` if the timeout elapsed first.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_many_timeout(&self, amount: isize, timeout: Duration) -> Option<SemaphoreGuard> {
+        if self.acquire_many_timeout(amount, timeout) {
+            Some(SemaphoreGuard::new(&self.inner, amount))
+        } else {
+            None
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard that
+    /// won't release it until at least `min` has passed since acquisition.
+    ///
+    /// This smooths out rapid acquire/release churn for resources that are
+    /// expensive to hand over. Note that dropping the returned guard early
+    /// blocks the dropping thread; see `MinHoldGuard`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_min_hold(&self, min: Duration) -> MinHoldGuard {
+        let guard = self.access();
+        MinHoldGuard {
+            _guard: guard,
+            release_at: Instant::now() + min,
+        }
+    }
+
+    /// Acquires each of `amounts` in turn, returning a separate guard for each
+    /// so they can be released independently.
+    ///
+    /// Unlike a single `access_many` for the total, this isn't atomic: earlier
+    /// amounts stay held while later ones are waited for, other threads may
+    /// take resources in between, and two threads doing this at once can
+    /// deadlock each other. Use it when the pieces are genuinely independent
+    /// and holding some of them while waiting is acceptable.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(3);
+    /// let mut guards = sem.acquire_each(&[1, 2]);
+    /// drop(guards.pop());
+    /// assert_eq!(sem.available_permits(), 2);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `acquire_many`, for any of the
+    /// amounts, releasing whatever was already acquired.
+    #[must_use = "the permits are released immediately if the guards are not bound to a variable"]
+    pub fn acquire_each(&self, amounts: &[isize]) -> Vec<SemaphoreGuard> {
+        amounts
+            .iter()
+            .map(|&amount| self.access_many(amount))
+            .collect()
+    }
+
+    /// Acquires `total` resources of this semaphore at once, returning a guard
+    /// that gives them back one at a time as work completes.
+    ///
+    /// This suits a producer that needs one resource per item but would rather
+    /// not acquire them individually. See `BatchGuard`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_batch(&self, total: isize) -> BatchGuard {
+        BatchGuard {
+            guard: self.access_many(total),
+        }
+    }
+
+    /// Acquires `amount` resources of this semaphore at once, returning a
+    /// ledger that gives them back in portions as the stages of a workload
+    /// complete. See `PermitLedger`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_ledger(&self, amount: isize) -> PermitLedger {
+        PermitLedger {
+            guard: self.access_many(amount),
+        }
+    }
+
+    /// Acquires `amount` resources of this semaphore, returning a guard which
+    /// can be split into pieces that are released independently.
+    ///
+    /// Each piece owns its own reference to the semaphore, so the pieces can
+    /// be handed to different threads or tasks once the whole amount has been
+    /// acquired. See `OwnedSemaphoreGuard::split`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_many_owned(self: &std::sync::Arc<Self>, amount: isize) -> OwnedSemaphoreGuard {
+        OwnedSemaphoreGuard {
+            guard: self.access_many(amount),
+        }
+    }
+
+    /// Acquires `estimate` resources of this semaphore, blocking the current
+    /// thread until it can do so, and returns a reservation to be settled once
+    /// the amount actually needed is known.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `estimate` is negative, or under the same conditions as
+    /// `acquire_many`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn reserve(&self, estimate: isize) -> Reservation {
+        check_amount(estimate);
+        Reservation {
+            guard: self.access_many(estimate),
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning a guard which releases
+    /// it when dropped or once `lease` has passed, whichever comes first.
+    ///
+    /// This guards against capacity being lost for good to a holder that never
+    /// drops its guard, such as a detached thread that gets stuck. A reaper
+    /// thread takes the resource back when the lease runs out, after which the
+    /// guard's own drop does nothing. Holders must be prepared for that: once
+    /// the lease has run out, someone else may acquire the resource while the
+    /// guard still exists. See `LeasedGuard`.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    /// use std::time::Duration;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// std::mem::forget(sem.access_leased(Duration::from_millis(1)));
+    /// sem.acquire();
+    /// ```
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_leased(&self, lease: Duration) -> LeasedGuard {
+        self.acquire();
+        lease::new(&self.inner, lease)
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard which
+    /// records how long the acquisition waited and releases the resource when
+    /// dropped.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn timed_access(&self) -> TimedGuard {
+        let start = Instant::now();
+        let guard = self.access();
+        TimedGuard {
+            _guard: guard,
+            wait: start.elapsed(),
+        }
+    }
+
+    /// Returns a future which acquires a resource of this semaphore without
+    /// blocking the thread, resolving to an RAII guard which releases it when
+    /// dropped.
+    ///
+    /// The future may wait alongside threads blocked in `acquire`; releases
+    /// wake both kinds of waiters. It does not borrow the semaphore, so it can
+    /// be stored or spawned freely.
+    pub fn access_async(&self) -> Access {
+        self.access_many_async(1)
+    }
+
+    /// Returns a future which acquires one or more resources of this
+    /// semaphore without blocking the thread, resolving to an RAII guard which
+    /// releases them when dropped.
+    pub fn access_many_async(&self, amount: isize) -> Access {
+        Access::new(&self.inner, amount)
+    }
+
+    /// Returns a future which acquires a resource like `access_async`, or
+    /// resolves to `Err(Cancelled)` if `cancel` completes first.
+    ///
+    /// This bundles the usual race between an acquisition and a shutdown
+    /// signal or timer. A cancelled acquisition consumes no resource, and
+    /// gives up its place in a fair semaphore's queue as soon as it is
+    /// cancelled.
+    pub fn acquire_until<C: Future>(&self, cancel: C) -> AccessUntil<C> {
+        AccessUntil::new(self.access_async(), cancel)
+    }
+
+    /// Acquires a resource of this semaphore like `access_timeout`, failing if
+    /// it can't be done within `sla`.
+    ///
+    /// In a fair semaphore, an acquisition that would have to queue first
+    /// estimates how long it would wait, and fails right away rather than
+    /// queueing hopelessly if that is longer than `sla`. The estimate assumes
+    /// each acquirer ahead is served as quickly as recent ones were: the
+    /// semaphore keeps a moving average of how long acquirers spend at the
+    /// head of its queue before being served, and multiplies it by the
+    /// number of queue positions to get through, counting our own. When no
+    /// queued acquirer has been served yet, there's nothing to go on and the
+    /// acquisition simply queues.
+    ///
+    /// Otherwise, or when the estimate is within `sla`, this waits for at
+    /// most `sla`. The error's `estimate` tells the two failures apart.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_sla(&self, sla: Duration) -> Result<SemaphoreGuard, SlaExceeded> {
+        {
+            let state = self.inner.lock.lock().unwrap();
+            if !state.may_take(1, None) {
+                let estimate = state.fair.as_ref().and_then(|queue| queue.estimate_wait());
+                if let Some(estimate) = estimate {
+                    if estimate > sla {
+                        return Err(SlaExceeded {
+                            estimate: Some(estimate),
+                        });
+                    }
+                }
+            }
+        }
+        self.access_timeout(sla)
+            .ok_or(SlaExceeded { estimate: None })
+    }
+
+    /// Acquires a resource of this semaphore like `access`, unless it would
+    /// have to wait in a queue that is already full.
+    ///
+    /// Only semaphores created with `new_fair_with_max_waiters` have a limit
+    /// on their queue; for any other this is the same as `access`.
+    ///
+    /// ```
+    /// use multi_semaphore::{QueueFull, Semaphore};
+    ///
+    /// let sem = Semaphore::new_fair_with_max_waiters(0, 0);
+    /// assert_eq!(sem.acquire_admitted().err(), Some(QueueFull));
+    /// ```
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_admitted(&self) -> Result<SemaphoreGuard, QueueFull> {
+        let state = self.inner.lock_to_acquire(1);
+        let full = match state.fair {
+            Some(ref queue) => queue.is_full(),
+            None => false,
+        };
+        if full && !state.may_take(1, None) {
+            return Err(QueueFull);
+        }
+        // Join the queue under the same lock, so it can't fill up meanwhile.
+        self.inner.take_or_wait(state, 1, None, |_| false);
+        Ok(SemaphoreGuard::new(&self.inner, 1))
+    }
+
+    /// Gets in line for a resource of this semaphore without waiting for it,
+    /// returning a handle to claim it with later.
+    ///
+    /// This lets a caller queue early and do its setup while waiting its turn
+    /// in a fair semaphore. See `PendingPermit`.
+    #[must_use = "the reservation is cancelled immediately if it is not bound to a variable"]
+    pub fn enqueue(&self) -> PendingPermit {
+        PendingPermit::new(&self.inner)
+    }
+
+    /// Acquires a resource of this semaphore, then spawns a thread in `scope`
+    /// which runs `f` while holding it.
+    ///
+    /// The resource is released when `f` returns or panics. Since the thread is
+    /// scoped, the semaphore can simply be borrowed rather than shared through
+    /// an `Arc`:
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    /// use std::thread;
+    ///
+    /// let sem = Semaphore::new(2);
+    /// let mut results = vec![0; 4];
+    /// thread::scope(|scope| {
+    ///     for (i, result) in results.iter_mut().enumerate() {
+    ///         // At most two of these threads run at a time.
+    ///         sem.spawn_scoped_guarded(scope, move || *result = i * 10);
+    ///     }
+    /// });
+    /// assert_eq!(results, [0, 10, 20, 30]);
+    /// ```
+    pub fn spawn_scoped_guarded<'scope, 'env, F, T>(
+        &self,
+        scope: &'scope thread::Scope<'scope, 'env>,
+        f: F,
+    ) -> thread::ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let guard = self.access();
+        scope.spawn(move || {
+            let _guard = guard;
+            f()
+        })
+    }
+
+    /// Attempts to acquire a resource of this semaphore without blocking,
+    /// returning an RAII guard to release it when dropped.
+    ///
+    /// Fails with the same errors as `try_acquire_checked`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn try_access_checked(&self) -> Result<SemaphoreGuard, TryAcquireError> {
+        self.try_access_many_checked(1)
+    }
+
+    /// Attempts to acquire one or more resources of this semaphore without
+    /// blocking, returning an RAII guard to release them when dropped.
+    ///
+    /// Returns `TryAcquireError::WouldBlock` if the count is less than
+    /// `amount`, or `TryAcquireError::Closed` if the semaphore has been closed.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn try_access_many_checked(
+        &self,
+        amount: isize,
+    ) -> Result<SemaphoreGuard, TryAcquireError> {
+        self.inner.try_acquire_many(amount)?;
+        Ok(SemaphoreGuard::new(&self.inner, amount))
+    }
+
+    /// Returns the thread and acquisition backtrace of every guard currently
+    /// holding resources from this semaphore.
+    ///
+    /// Only guards are tracked; resources taken with `acquire` and friends are
+    /// not attributed to anyone. This is meant for a watchdog to dump who is
+    /// holding permits when the semaphore appears stuck, and is only available
+    /// with the `debug` feature since capturing a backtrace on every access is
+    /// expensive.
+    #[cfg(feature = "debug")]
+    pub fn outstanding_acquirers(&self) -> Vec<(ThreadId, Arc<Backtrace>)> {
+        self.inner.acquirers.snapshot()
+    }
+
+    /// Makes blocking acquisitions from this semaphore panic, rather than
+    /// hang, when they would complete a deadlock.
+    ///
+    /// Every semaphore with detection enabled records which threads hold its
+    /// guards and which are blocked on it, in a wait-for graph shared by all
+    /// of them. An acquisition without a timeout that has to wait panics with
+    /// a report of the cycle if every thread holding a guard from the
+    /// semaphore, and transitively every holder of whatever those are blocked
+    /// on, is itself blocked waiting on the current thread.
+    ///
+    /// Guards are attributed to the thread that acquired them, and resources
+    /// taken without a guard or waited for by futures aren't tracked, so only
+    /// deadlocks between guards held by blocked threads are caught. Guards
+    /// created before detection was enabled are never counted. Only
+    /// available with the `debug` feature, since every guard and wait takes
+    /// a global lock.
+    ///
+    /// ```should_panic
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// sem.enable_deadlock_detection();
+    /// let _guard = sem.access();
+    /// let _again = sem.access(); // panics: "deadlock detected"
+    /// ```
+    #[cfg(feature = "debug")]
+    pub fn enable_deadlock_detection(&self) {
+        self.inner.acquirers.enable_deadlock_detection();
+    }
+
+    /// Acquires a resource like `access`, but fails with `WouldDeadlock`
+    /// instead of blocking forever when the current thread would be waiting
+    /// on itself: nothing is available and every guard from this semaphore
+    /// is held by the current thread.
+    ///
+    /// This is for code that may acquire recursively. It is best effort:
+    /// like `outstanding_acquirers`, it only sees guards, so resources taken
+    /// without one, or released by a thread holding none, aren't accounted
+    /// for. Only available with the `debug` feature.
+    ///
+    /// ```
+    /// use multi_semaphore::{Semaphore, WouldDeadlock};
+    ///
+    /// let sem = Semaphore::new(1);
+    /// let _guard = sem.acquire_or_deadlock_err().unwrap();
+    /// assert_eq!(sem.acquire_or_deadlock_err().err(), Some(WouldDeadlock));
+    /// ```
+    #[cfg(feature = "debug")]
+    pub fn acquire_or_deadlock_err(&self) -> Result<SemaphoreGuard, WouldDeadlock> {
+        let state = self.inner.lock_to_acquire(1);
+        if state.count < 1 && self.inner.acquirers.held_only_by_current() {
+            return Err(WouldDeadlock);
+        }
+        self.inner.take_or_wait(state, 1, None, |_| false);
+        Ok(SemaphoreGuard::new(&self.inner, 1))
+    }
+
+    /// Returns how many blocking acquisitions found enough resources on the
+    /// first try, without parking.
+    ///
+    /// Together with `slow_path_falls` this shows how contended the semaphore
+    /// is under a real workload. Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn fast_path_hits(&self) -> usize {
+        self.inner.metrics.fast_path_hits()
+    }
+
+    /// Returns percentiles of how long successful blocking acquisitions
+    /// waited, including those that didn't have to wait at all.
+    ///
+    /// Only available with the `stats` feature, which records every wait in a
+    /// fixed-size histogram while the lock is already held.
+    #[cfg(feature = "stats")]
+    pub fn wait_histogram(&self) -> WaitStats {
+        self.inner.lock.lock().unwrap().waits.snapshot()
+    }
+
+    /// Returns how many blocking acquisitions had to park waiting for
+    /// resources, whether or not they eventually got them.
+    #[cfg(feature = "metrics")]
+    pub fn slow_path_falls(&self) -> usize {
+        self.inner.metrics.slow_path_falls()
+    }
+
+    /// Returns `fast_path_hits` and `slow_path_falls` together, as of the same
+    /// moment.
+    ///
+    /// Both counters are only bumped with the lock held, so reading them under
+    /// it gives a consistent pair for working out the fast-path ratio. A low
+    /// ratio suggests the semaphore has too few resources for its workload.
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn contention_stats(&self) -> (u64, u64) {
+        let _state = self.inner.lock.lock().unwrap();
+        let metrics = &self.inner.metrics;
+        (
+            metrics.fast_path_hits() as u64,
+            metrics.slow_path_falls() as u64,
+        )
+    }
+}
+
+/// Releases `amount` resources to `release_to` and acquires `amount` from
+/// `acquire_from`, returning a guard for the acquired resources.
+///
+/// This converts credits of one semaphore into credits of another, such as
+/// handing a finished unit from one pipeline stage to the next. When
+/// `acquire_from` has enough resources, both halves happen atomically: no
+/// other thread sees the released resources without the acquired ones gone.
+/// Both locks are taken in a fixed order, so concurrent rotations in opposite
+/// directions can't deadlock.
+///
+/// When `acquire_from` doesn't have enough resources, the release still
+/// happens right away, and then this blocks like `Semaphore::access_many`
+/// until the acquisition can be made. Releasing first means a rotation never
+/// holds on to credits it is waiting to trade, which could otherwise starve
+/// the stage that would produce the resources it is waiting for.
+///
+/// Rotating within a single semaphore just returns a guard for resources
+/// that were already held.
+///
+/// # Panics
+///
+/// Panics without releasing anything if `amount` is negative, if it exceeds
+/// the maximum of a bounded `acquire_from`, or if releasing it would
+/// overflow `release_to`'s count.
+pub fn rotate(release_to: &Semaphore, acquire_from: &Semaphore, amount: isize) -> SemaphoreGuard {
+    check_amount(amount);
+    let (to, from) = (&release_to.inner, &acquire_from.inner);
+    if Arc::ptr_eq(to, from) {
+        return SemaphoreGuard::new(from, amount);
+    }
+    let (mut to_state, mut from_state) = if (&**to as *const RawSemaphore) < &**from {
+        let to_state = to.lock.lock().unwrap();
+        (to_state, from.lock.lock().unwrap())
+    } else {
+        let from_state = from.lock.lock().unwrap();
+        (to.lock.lock().unwrap(), from_state)
+    };
+    if let Some(max) = from_state.max {
+        if amount > max {
+            drop((to_state, from_state));
+            panic!(
+                "cannot acquire {} resources from a semaphore with a maximum of {}",
+                amount, max
+            );
+        }
+    }
+    to_state.count = match to_state.count.checked_add(amount) {
+        Some(count) => count,
+        None => {
+            drop((to_state, from_state));
+            panic!(
+                "releasing {} resources would overflow the semaphore count",
+                amount
+            );
+        }
+    };
+    let acquired = from_state.may_take(amount, None);
+    if acquired {
+        from_state.count -= amount;
+    }
+    to.unlock(to_state);
+    from.unlock(from_state);
+    if !acquired {
+        from.acquire_many(amount);
+    }
+    SemaphoreGuard::new(from, amount)
+}
+
+/// Acquires an amount from each of several semaphores, returning their guards
+/// in the order requested.
+///
+/// The semaphores are acquired one after another, always in the same order
+/// whatever order they're requested in, so concurrent calls naming the same
+/// semaphores can't deadlock each other. Resources already acquired stay held
+/// while later ones are waited for. Nothing is allocated, so this suits a fixed
+/// set of semaphores on a hot path.
+///
+/// ```
+/// use multi_semaphore::{acquire_all_n, Semaphore};
+///
+/// let (cpu, memory) = (Semaphore::new(4), Semaphore::new(1024));
+/// let [cores, megabytes] = acquire_all_n([(&cpu, 2), (&memory, 512)]);
+/// assert_eq!((cores.amount(), megabytes.amount()), (2, 512));
+/// ```
+///
+/// # Panics
+///
+/// Panics under the same conditions as `Semaphore::access_many`, for any of
+/// the amounts, releasing whatever was already acquired.
+#[must_use = "the permits are released immediately if the guards are not bound to a variable"]
+pub fn acquire_all_n<const N: usize>(requests: [(&Semaphore, isize); N]) -> [SemaphoreGuard; N] {
+    let mut order = [0; N];
+    for (i, slot) in order.iter_mut().enumerate() {
+        *slot = i;
+    }
+    order.sort_unstable_by_key(|&i| &*requests[i].0.inner as *const RawSemaphore);
+    let mut guards = [(); N].map(|_| None);
+    for &i in &order {
+        let (sem, amount) = requests[i];
+        guards[i] = Some(sem.access_many(amount));
+    }
+    guards.map(|guard| guard.unwrap())
+}
+
+/// Access to a locked semaphore's count, passed to the closure given to
+/// `Semaphore::with_count`.
+pub struct CountHandle<'a> {
+    state: &'a mut SemaphoreState,
+}
+
+impl<'a> CountHandle<'a> {
+    /// Returns the count, which may be negative.
+    pub fn count(&self) -> isize {
+        self.state.count
+    }
+
+    /// Takes `amount` resources if they're available, returning whether it
+    /// did.
+    ///
+    /// This follows the same rules as `Semaphore::try_acquire_many`: it fails
+    /// if the semaphore is closed, paused or offline, or if it is fair and
+    /// acquirers are queued. The resources aren't tied to a guard; give them back with
+    /// `Semaphore::release_many`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative.
+    pub fn try_take(&mut self, amount: isize) -> bool {
+        check_amount(amount);
+        if self.state.closed || !self.state.may_take(amount, None) {
+            return false;
+        }
+        self.state.count -= amount;
+        true
+    }
+}
+
+/// A semaphore's count captured by `Semaphore::capture`, to be put back with
+/// `Semaphore::restore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StateCapture {
+    count: isize,
+    baseline: isize,
+}
+
+impl StateCapture {
+    /// Returns the captured count.
+    pub fn count(&self) -> isize {
+        self.count
+    }
+}
+
+/// A semaphore's count and generation, read together by
+/// `Semaphore::available_permits_atomic_snapshot`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PermitSnapshot {
+    /// The count, which may be negative like `Semaphore::available_permits`.
+    pub count: isize,
+    /// The generation the count belongs to; see `Semaphore::generation`.
+    pub generation: u64,
+}
+
+/// An RAII guard like `SemaphoreGuard` which also records how long acquiring its
+/// resource took.
+pub struct TimedGuard {
+    _guard: SemaphoreGuard,
+    wait: Duration,
+}
+
+impl TimedGuard {
+    /// Returns how long the thread was blocked acquiring this guard's resource.
+    pub fn wait_time(&self) -> Duration {
+        self.wait
+    }
+}
+
+/// An RAII guard which holds its resource for at least a minimum amount of time.
+///
+/// Created by `Semaphore::access_min_hold`.
+///
+/// **Dropping this guard may block.** If the guard is dropped before its
+/// minimum hold time has passed, the drop sleeps for the remainder and only
+/// then releases the resource. The same happens while unwinding from a panic,
+/// so the resource is always released, just never early.
+pub struct MinHoldGuard {
+    _guard: SemaphoreGuard,
+    release_at: Instant,
+}
+
+impl Drop for MinHoldGuard {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        if now < self.release_at {
+            thread::sleep(self.release_at - now);
+        }
+        // `_guard` releases the resource once this returns.
+    }
+}
+
+/// The releases gathered by `Semaphore::release_batch`, which are made all at
+/// once when its closure returns.
+pub struct BatchReleaser {
+    amount: isize,
+}
+
+impl BatchReleaser {
+    /// Adds `amount` to the resources to release.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the total would overflow the count.
+    pub fn release(&mut self, amount: isize) {
+        self.amount = match self.amount.checked_add(amount) {
+            Some(total) => total,
+            None => panic!(
+                "releasing {} resources would overflow the semaphore count",
+                amount
+            ),
+        };
+    }
+
+    /// Returns the total gathered so far.
+    pub fn amount(&self) -> isize {
+        self.amount
+    }
+}
+
+/// How an acquisition through `Semaphore::access_diagnostic` waited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AcquireDiag {
+    /// How long the acquirer waited behind others in a fair semaphore's
+    /// queue before reaching its head.
+    pub queue_wait: Duration,
+    /// How long the acquirer waited at the head of the queue, or in all on a
+    /// semaphore that isn't fair, for resources to be released.
+    pub head_wait: Duration,
+    /// How many resources went to acquirers ahead in the queue while this
+    /// one waited.
+    pub permits_passed_while_waiting: u64,
+}
+
+/// How long `Semaphore::acquire_or_recv` waits for a resource between checks
+/// for a message.
+pub const RECV_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// One of two outcomes, such as the resource or the message that
+/// `Semaphore::acquire_or_recv` returns with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// An iterator over guards for one resource each, created by
+/// `Semaphore::acquire_stream`.
+pub struct AcquireStream<'a> {
+    sem: &'a Semaphore,
+    /// The guards still to be acquired.
+    remaining: usize,
+    /// Resources acquired in a chunk that haven't been handed out in guards.
+    ready: usize,
+    /// The semaphore's generation and `resets` when the chunk was acquired,
+    /// which its guards are stamped with.
+    generation: u64,
+    resets: u64,
+}
+
+impl<'a> AcquireStream<'a> {
+    /// Takes as many of the remaining resources as are available, or waits
+    /// for one if none are.
+    fn refill(&mut self) {
+        let raw = &self.sem.inner;
+        let mut state = raw.lock.lock().unwrap();
+        let available = cmp::max(state.count, 0) as usize;
+        let chunk = cmp::min(self.remaining, available);
+        let taken = if chunk > 0 && state.may_take(chunk as isize, None) {
+            state.count -= chunk as isize;
+            self.stamp();
+            raw.unlock(state);
+            chunk
+        } else {
+            raw.take_or_wait(state, 1, None, |_| false);
+            self.stamp();
+            1
+        };
+        self.ready = taken;
+        self.remaining -= taken;
+    }
+
+    /// Records what the chunk just acquired has to be stamped with.
+    fn stamp(&mut self) {
+        let raw = &self.sem.inner;
+        self.generation = raw.generation.load(Ordering::Relaxed);
+        self.resets = raw.resets.load(Ordering::Relaxed);
+    }
+}
+
+impl<'a> Iterator for AcquireStream<'a> {
+    type Item = SemaphoreGuard;
+
+    fn next(&mut self) -> Option<SemaphoreGuard> {
+        if self.ready == 0 {
+            if self.remaining == 0 {
+                return None;
+            }
+            self.refill();
+        }
+        self.ready -= 1;
+        Some(SemaphoreGuard::stamped(
+            &self.sem.inner,
+            1,
+            self.generation,
+            self.resets,
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.ready + self.remaining;
+        (len, Some(len))
+    }
+}
+
+impl<'a> Drop for AcquireStream<'a> {
+    fn drop(&mut self) {
+        self.sem
+            .inner
+            .release_stamped(self.resets, self.ready as isize);
+    }
+}
+
+/// A watcher for changes to a semaphore's count, created by
+/// `Semaphore::subscribe`.
+pub struct CountWatcher<'a> {
+    sem: &'a Semaphore,
+    /// The version of the count last reported.
+    seen: Cell<u64>,
+}
+
+impl<'a> CountWatcher<'a> {
+    /// Blocks the current thread until the count has changed since the
+    /// watcher was created or this last returned, then returns the new
+    /// count.
+    pub fn wait_change(&self) -> isize {
+        let raw = &self.sem.inner;
+        let mut state = raw.lock.lock().unwrap();
+        state.watchers += 1;
+        let seen = self.seen.get();
+        let (mut state, _) = raw.wait_until(state, None, |state| state.version != seen);
+        state.watchers -= 1;
+        self.seen.set(state.version);
+        state.count
+    }
+}
+
+/// A region in which a semaphore's releases don't wake anyone until it ends.
+///
+/// Created by `Semaphore::defer_notifies`. When the last live scope of a
+/// semaphore is dropped, it wakes every waiter if any release was held back.
+#[must_use = "wakeups are only deferred while the scope is alive"]
+pub struct ReleaseScope<'a> {
+    sem: &'a Semaphore,
+}
+
+impl<'a> Drop for ReleaseScope<'a> {
+    fn drop(&mut self) {
+        let mut state = self.sem.inner.lock.lock().unwrap();
+        state.deferring -= 1;
+        if state.deferring == 0 && mem::replace(&mut state.deferred, false) {
+            self.sem.inner.wake_all(state);
+        }
+    }
+}
+
+/// A resource acquired from a semaphore that has to be given back explicitly.
+///
+/// Created by `Semaphore::take_permit`. Unlike a guard, a permit never
+/// releases its resource on its own: call `give_back` to return it. Dropping
+/// a permit that hasn't been given back is treated as a bug and panics, unless
+/// the thread is already panicking, so that no resource goes unaccounted for
+/// silently. Either way the resource stays acquired.
+#[must_use = "a permit must be given back with `give_back`"]
+pub struct Permit {
+    sem: Arc<RawSemaphore>,
+    given_back: bool,
+}
+
+impl Permit {
+    /// Releases the permit's resource to `sem`.
+    ///
+    /// # Panics
+    ///
+    /// Panics, without releasing anything, if the permit wasn't taken from
+    /// `sem`.
+    pub fn give_back(mut self, sem: &Semaphore) {
+        assert!(
+            Arc::ptr_eq(&self.sem, &sem.inner),
+            "permit given back to a semaphore it wasn't taken from"
+        );
+        self.given_back = true;
+        sem.inner.release_many(1);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.given_back && !thread::panicking() {
+            panic!("permit dropped without being given back");
+        }
+    }
+}
+
+/// An RAII guard over a block of resources which are given back one at a time.
+///
+/// Created by `Semaphore::access_batch`. Each `consume_one` releases a single
+/// resource, and whatever remains is released when the guard is dropped.
+pub struct BatchGuard {
+    guard: SemaphoreGuard,
+}
+
+impl BatchGuard {
+    /// Returns the number of resources still held.
+    pub fn remaining(&self) -> isize {
+        self.guard.amount
+    }
+
+    /// Releases one of the held resources.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no resources remain, since that would release a resource
+    /// this guard never acquired.
+    pub fn consume_one(&mut self) {
+        assert!(
+            self.guard.amount > 0,
+            "consume_one called on an exhausted BatchGuard"
+        );
+        self.guard.amount -= 1;
+        self.guard.give_back(1);
+    }
+}
+
+/// An RAII guard over resources which are given back in stages.
+///
+/// Created by `Semaphore::acquire_ledger`. Each `release` gives back part of
+/// the resources, and whatever is still held is released when the ledger is
+/// dropped.
+pub struct PermitLedger {
+    guard: SemaphoreGuard,
+}
+
+impl PermitLedger {
+    /// Returns the number of resources still held.
+    pub fn held(&self) -> isize {
+        self.guard.amount
+    }
+
+    /// Releases `n` of the held resources.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than the ledger still holds.
+    pub fn release(&mut self, n: isize) {
+        assert!(
+            0 <= n && n <= self.guard.amount,
+            "cannot release {} resources from a PermitLedger holding {}",
+            n,
+            self.guard.amount
+        );
+        self.guard.amount -= n;
+        self.guard.give_back(n);
+    }
+}
+
+/// Resources reserved from a semaphore with `Semaphore::reserve` before the
+/// exact amount needed is known.
+///
+/// Call `settle` once the real amount is known to turn the reservation into a
+/// guard for exactly that amount. A reservation that is dropped without being
+/// settled releases everything it reserved.
+pub struct Reservation {
+    guard: SemaphoreGuard,
+}
+
+impl Reservation {
+    /// Returns the number of resources currently reserved.
+    pub fn estimate(&self) -> isize {
+        self.guard.amount
+    }
+
+    /// Adjusts the reservation to `actual` resources and converts it into a
+    /// guard for that amount.
+    ///
+    /// If `actual` is less than the estimate, the difference is released
+    /// immediately. If it is greater, this blocks until the extra resources
+    /// can be acquired.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `actual` is negative, releasing the whole reservation.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn settle(self, actual: isize) -> SemaphoreGuard {
+        check_amount(actual);
+        let mut guard = self.guard;
+        let estimate = guard.amount;
+        if actual < estimate {
+            guard.give_back(estimate - actual);
+        } else {
+            guard.sem.acquire_many(actual - estimate);
+        }
+        guard.amount = actual;
+        guard
+    }
+}
+
+/// Dropping a guard as soon as it is returned is almost always a bug, so the
+/// guard-returning methods are `#[must_use]`:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let sem = multi_semaphore::Semaphore::new(1);
+/// sem.access();
+/// ```
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let sem = multi_semaphore::Semaphore::new(1);
+/// sem.try_access_many(1);
+/// ```
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// let sem = multi_semaphore::Semaphore::new(1);
+/// sem.access_timeout(std::time::Duration::from_secs(1));
+/// ```
+///
+/// ```
+/// #![deny(unused_must_use)]
+/// let sem = multi_semaphore::Semaphore::new(1);
+/// let _guard = sem.access();
+/// ```
+#[cfg(doctest)]
+pub struct MustUseGuards;
+
+/// Something a permit can be acquired from.
+///
+/// This trait is object safe, so code that only needs to limit concurrency can
+/// accept a `&dyn Acquirable` or store a `Box<dyn Acquirable>` without caring
+/// whether it is given a `Semaphore` or some wrapper around one. Because a
+/// trait object can't hand out a guard tied to its concrete type, scoped access
+/// is offered through `with_permit` instead.
+pub trait Acquirable {
+    /// Acquires a permit, blocking the current thread until it can do so.
+    fn acquire(&self);
+
+    /// Attempts to acquire a permit without blocking, returning whether it
+    /// was acquired.
+    fn try_acquire(&self) -> bool;
+
+    /// Releases a permit.
+    fn release(&self);
+
+    /// Returns the number of permits currently available.
+    fn available(&self) -> isize;
+
+    /// Acquires a permit, calls `f`, then releases the permit, even if `f`
+    /// panics.
+    fn with_permit(&self, f: &mut dyn FnMut()) {
+        struct Release<'a, A: ?Sized + Acquirable + 'a>(&'a A);
+
+        impl<'a, A: ?Sized + Acquirable> Drop for Release<'a, A> {
+            fn drop(&mut self) {
+                self.0.release();
+            }
+        }
+
+        self.acquire();
+        let _release = Release(self);
+        f();
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    /// Shows the name, if the semaphore has one, and the count from
+    /// `available_permits`. This doesn't take the lock, so it's safe to use
+    /// anywhere, including while the lock is held.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("Semaphore");
+        if let Some(name) = self.inner.name {
+            d.field("name", &name);
+        }
+        d.field("count", &self.available_permits()).finish()
+    }
+}
+
+impl Acquirable for Semaphore {
+    fn acquire(&self) {
+        Semaphore::acquire(self);
+    }
+
+    fn try_acquire(&self) -> bool {
+        Semaphore::try_acquire(self)
+    }
+
+    fn release(&self) {
+        Semaphore::release(self);
+    }
+
+    fn available(&self) -> isize {
+        self.available_permits()
+    }
+
+    /// Holds the permit in a guard, which, unlike `release`, may give it back
+    /// after the semaphore is closed.
+    fn with_permit(&self, f: &mut dyn FnMut()) {
+        let _guard = self.access();
+        f();
+    }
+}
+
+impl SemaphoreGuard {
+    fn new(sem: &Arc<RawSemaphore>, amount: isize) -> SemaphoreGuard {
+        SemaphoreGuard::stamped(
+            sem,
+            amount,
+            sem.generation.load(Ordering::Relaxed),
+            sem.resets.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Creates a guard for resources that were acquired in `generation`, when
+    /// `sem`'s `resets` was `resets`.
+    fn stamped(
+        sem: &Arc<RawSemaphore>,
+        amount: isize,
+        generation: u64,
+        resets: u64,
+    ) -> SemaphoreGuard {
+        SemaphoreGuard {
+            sem: Arc::clone(sem),
+            amount,
+            generation,
+            resets,
+            #[cfg(feature = "debug")]
+            acquirer: sem.acquirers.register(),
+        }
+    }
+
+    /// Releases `amount` of the guard's resources, unless the guard has been
+    /// invalidated by `Semaphore::bump_generation`.
+    fn give_back(&self, amount: isize) {
+        self.sem.release_stamped(self.resets, amount);
+    }
+
+    /// Bundles this guard with `value`, so the resources stay acquired for
+    /// exactly as long as the value is in use.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// let conn = sem.access().bundle(String::from("connection"));
+    /// assert_eq!(conn.len(), 10);
+    /// assert_eq!(sem.available_permits(), 0);
+    /// drop(conn);
+    /// assert_eq!(sem.available_permits(), 1);
+    /// ```
+    pub fn bundle<T>(self, value: T) -> Guarded<T> {
+        Guarded { value, guard: self }
+    }
+
+    /// Returns how many resources this guard holds.
+    pub fn amount(&self) -> isize {
+        self.amount
+    }
+
+    /// Moves `n` of this guard's resources into a new guard, which releases
+    /// them independently.
+    ///
+    /// Together with `release_partial` and `acquire_more`, this lets a guard
+    /// serve as a budget that code borrowing it mutably can adjust:
+    ///
+    /// ```
+    /// use multi_semaphore::{Semaphore, SemaphoreGuard};
+    ///
+    /// fn finish_early(budget: &mut SemaphoreGuard) {
+    ///     budget.release_partial(1);
+    /// }
+    ///
+    /// fn expand(budget: &mut SemaphoreGuard) {
+    ///     budget.acquire_more(2);
+    ///     finish_early(budget);
+    /// }
+    ///
+    /// let sem = Semaphore::new(5);
+    /// let mut budget = sem.access_many(2);
+    /// expand(&mut budget);
+    /// assert_eq!(budget.amount(), 3);
+    /// let handed_off = budget.split(2);
+    /// assert_eq!((budget.amount(), handed_off.amount()), (1, 2));
+    /// drop(budget);
+    /// assert_eq!(sem.available_permits(), 3);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than the guard holds.
+    pub fn split(&mut self, n: isize) -> SemaphoreGuard {
+        assert!(
+            n >= 0 && n <= self.amount,
+            "cannot split {} resources off a guard holding {}",
+            n,
+            self.amount
+        );
+        self.amount -= n;
+        let mut piece = SemaphoreGuard::new(&self.sem, n);
+        piece.generation = self.generation;
+        piece.resets = self.resets;
+        piece
+    }
+
+    /// Releases `n` of this guard's resources now, keeping the rest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than the guard holds.
+    pub fn release_partial(&mut self, n: isize) {
+        assert!(
+            n >= 0 && n <= self.amount,
+            "cannot release {} resources from a guard holding {}",
+            n,
+            self.amount
+        );
+        self.amount -= n;
+        self.give_back(n);
+    }
+
+    /// Acquires `n` more resources from the same semaphore into this guard,
+    /// blocking the current thread until it can do so.
+    ///
+    /// The guard's resources stay held while waiting, so this is subject to
+    /// the same deadlocks as calling `acquire` repeatedly; see
+    /// `Semaphore::acquire_many`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `Semaphore::acquire_many`, or if
+    /// the guard would grow past `Semaphore::set_max_guard_amount`'s cap.
+    pub fn acquire_more(&mut self, n: isize) {
+        check_amount(n);
+        let state = self.sem.lock_to_acquire(n);
+        if let Some(max) = state.max_guard.filter(|_| !self.may_grow(&state, n)) {
+            drop(state);
+            panic!(
+                "growing a guard holding {} by {} would exceed the maximum guard amount of {}",
+                self.amount, n, max
+            );
+        }
+        if n > 0 {
+            self.sem.take_or_wait(state, n, None, |_| false);
+        }
+        self.amount += n;
+    }
+
+    /// Acquires `n` more resources into this guard if they are available,
+    /// without blocking.
+    ///
+    /// Returns `false`, acquiring nothing, if they aren't, if the semaphore is
+    /// closed, or if the guard would grow past the cap set with
+    /// `Semaphore::set_max_guard_amount`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn try_expand(&mut self, n: isize) -> bool {
+        check_amount(n);
+        let mut state = self.sem.lock.lock().unwrap();
+        if state.closed || !self.may_grow(&state, n) {
+            return false;
+        }
+        if n == 0 {
+            return true;
+        }
+        if !state.may_take(n, None) {
+            return false;
+        }
+        state.count -= n;
+        self.sem.unlock(state);
+        self.amount += n;
+        true
+    }
+
+    /// Acquires `n` more resources into this guard, blocking for at most
+    /// `timeout`.
+    ///
+    /// Returns `false`, acquiring nothing, if they weren't available in time,
+    /// or right away if the guard would grow past the cap set with
+    /// `Semaphore::set_max_guard_amount`. The guard's resources stay held
+    /// while waiting, as with `acquire_more`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `Semaphore::acquire_many_timeout`.
+    pub fn expand_timeout(&mut self, n: isize, timeout: Duration) -> bool {
+        check_amount(n);
+        let state = self.sem.lock_to_acquire(n);
+        if !self.may_grow(&state, n) {
+            return false;
+        }
+        if n > 0 {
+            let deadline = self.sem.now().checked_add(timeout);
+            if self
+                .sem
+                .take_or_wait(state, n, deadline, |_| false)
+                .is_none()
+            {
+                return false;
+            }
+        }
+        self.amount += n;
+        true
+    }
+
+    /// Whether growing the guard by `n` keeps it within the semaphore's cap.
+    fn may_grow(&self, state: &SemaphoreState, n: isize) -> bool {
+        match (state.max_guard, self.amount.checked_add(n)) {
+            (None, _) => true,
+            (Some(max), Some(amount)) => amount <= max,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Returns the generation of the semaphore this guard was created in.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns whether the semaphore has not been reconfigured since this
+    /// guard was created, so its resources were acquired under the current
+    /// capacity.
+    ///
+    /// A guard created while another thread resizes the semaphore may be
+    /// tagged with either generation. Guards made stale by `resize` still
+    /// release correctly; those made stale by `set_count` or
+    /// `bump_generation` release nothing.
+    pub fn is_current(&self) -> bool {
+        self.generation == self.sem.generation.load(Ordering::Relaxed)
+    }
+
+    /// Releases the guard's resources right away, consuming it.
+    ///
+    /// This is the same as dropping the guard, but spells out where the
+    /// release happens, which makes the order relative to other resources
+    /// explicit rather than relying on scopes.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(1);
+    /// let guard = sem.access();
+    /// guard.release_now();
+    /// assert_eq!(sem.available_permits(), 1);
+    /// ```
+    pub fn release_now(mut self) {
+        // Leave nothing for the drop at the end of this call to release.
+        let amount = mem::replace(&mut self.amount, 0);
+        self.give_back(amount);
+    }
+
+    /// Consumes the guard without releasing its resources, returning a handle
+    /// to the semaphore they came from.
+    ///
+    /// The resources stay acquired for good, which suits permits that are
+    /// meant to be held for the rest of the program. Guards own a reference to
+    /// their semaphore rather than borrowing it, so the handle shares the
+    /// original semaphore instead of borrowing it too.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(2);
+    /// let handle = sem.access().leak();
+    /// handle.release();
+    /// assert_eq!(sem.available_permits(), 2);
+    /// ```
+    pub fn leak(mut self) -> Semaphore {
+        // Dropping a guard with nothing left to release is a no-op apart
+        // from the debug bookkeeping, which should forget it anyway.
+        self.amount = 0;
+        Semaphore {
+            inner: Arc::clone(&self.sem),
+        }
+    }
+
+    /// Consumes the guard without releasing its resources, returning a handle
+    /// to the semaphore they came from and how many there are.
+    ///
+    /// This hands the resources over to manual bookkeeping, for code that
+    /// counts acquisitions and releases itself. Nothing releases them any
+    /// more: unless the amount is given back with `release_many`, they are
+    /// leaked, and the semaphore is that much smaller for good. The release is
+    /// also no longer tied to the guard, so it counts even after
+    /// `Semaphore::bump_generation` would have made the guard release nothing.
+    /// As with `leak`, the handle shares the semaphore rather than borrowing
+    /// it.
+    ///
+    /// ```
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(3);
+    /// let (handle, amount) = sem.access_many(2).into_raw();
+    /// assert_eq!((amount, sem.available_permits()), (2, 1));
+    /// handle.release_many(amount);
+    /// assert_eq!(sem.available_permits(), 3);
+    /// ```
+    pub fn into_raw(mut self) -> (Semaphore, isize) {
+        let amount = mem::replace(&mut self.amount, 0);
+        (self.leak(), amount)
+    }
+}
+
+/// A value which holds semaphore resources for as long as it lives.
+///
+/// Created by `SemaphoreGuard::bundle`, it dereferences to the wrapped value
+/// and releases the resources when dropped.
+pub struct Guarded<T> {
+    value: T,
+    guard: SemaphoreGuard,
+}
+
+impl<T> Guarded<T> {
+    /// Releases the resources and returns the wrapped value.
+    pub fn into_value(self) -> T {
+        let Guarded { value, guard } = self;
+        drop(guard);
+        value
+    }
+}
+
+impl<T> Deref for Guarded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Guarded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// An RAII guard for resources acquired all at once that can be divided
+/// among several owners.
+///
+/// Created by `Semaphore::acquire_many_owned`. Splitting the guard yields
+/// pieces which each release their own share of the resources when dropped.
+pub struct OwnedSemaphoreGuard {
+    guard: SemaphoreGuard,
+}
+
+impl OwnedSemaphoreGuard {
+    /// Returns how many resources this guard holds.
+    pub fn amount(&self) -> isize {
+        self.guard.amount
+    }
+
+    /// Splits `n` resources off this guard, returning a guard for those and a
+    /// guard for the rest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than the guard holds.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use multi_semaphore::Semaphore;
+    ///
+    /// let sem = Arc::new(Semaphore::new(3));
+    /// let (one, two) = sem.acquire_many_owned(3).split(1);
+    /// drop(one);
+    /// assert_eq!(sem.available_permits(), 1);
+    /// drop(two);
+    /// assert_eq!(sem.available_permits(), 3);
+    /// ```
+    pub fn split(self, n: isize) -> (OwnedSemaphoreGuard, OwnedSemaphoreGuard) {
+        let mut rest = self.guard;
+        let piece = rest.split(n);
+        (
+            OwnedSemaphoreGuard { guard: piece },
+            OwnedSemaphoreGuard { guard: rest },
+        )
+    }
+}
+
+impl Drop for SemaphoreGuard {
+    fn drop(&mut self) {
+        self.give_back(self.amount);
+        // Only stop counting as a holder once the release has woken whoever
+        // was waiting, or deadlock detection could blame them for our wait.
+        #[cfg(feature = "debug")]
+        self.sem.acquirers.unregister(self.acquirer);
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use std::prelude::v1::*;
 
-    use super::Semaphore;
-    use std::sync::mpsc::channel;
-    use std::sync::Arc;
-    use std::thread;
+    use super::{
+        acquire_all_n, rotate, Acquirable, AcquireDiag, AmountError, Backend, CapacityExceeded,
+        CondvarParker, InvariantError, Parker, PermitSnapshot, QueueFull, Semaphore,
+        SemaphoreState, ThreadParker, TryAcquireError,
+    };
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, LockResult, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_waiters(s: &Semaphore, waiters: usize) {
+        while s.inner.lock.lock().unwrap().waiting.values().sum::<usize>() < waiters {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_sem_acquire_release() {
+        let s = Semaphore::new(1);
+        s.acquire();
+        s.release();
+        s.acquire();
+    }
+
+    #[test]
+    fn test_sem_basic() {
+        let s = Semaphore::new(1);
+        let _g = s.access();
+    }
+
+    #[test]
+    fn test_sem_as_mutex() {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        let _t = thread::spawn(move || {
+            let _g = s2.access();
+        });
+        let _g = s.access();
+    }
+
+    #[test]
+    fn test_sem_as_cvar() {
+        // Child waits and parent signals
+        let (tx, rx) = channel();
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let _t = thread::spawn(move || {
+            s2.acquire();
+            tx.send(()).unwrap();
+        });
+        s.release();
+        let _ = rx.recv();
+
+        // Parent waits and child signals
+        let (tx, rx) = channel();
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let _t = thread::spawn(move || {
+            s2.release();
+            let _ = rx.recv();
+        });
+        s.acquire();
+        tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_sem_multi_resource() {
+        // Parent and child both get in the critical section at the same
+        // time, and shake hands.
+        let s = Arc::new(Semaphore::new(2));
+        let s2 = s.clone();
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let _t = thread::spawn(move || {
+            let _g = s2.access();
+            let _ = rx2.recv();
+            tx1.send(()).unwrap();
+        });
+        let _g = s.access();
+        tx2.send(()).unwrap();
+        rx1.recv().unwrap();
+    }
+
+    #[test]
+    fn test_sem_runtime_friendly_blocking() {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        let (tx, rx) = channel();
+        {
+            let _g = s.access();
+            thread::spawn(move || {
+                tx.send(()).unwrap();
+                drop(s2.access());
+                tx.send(()).unwrap();
+            });
+            rx.recv().unwrap(); // wait for child to come alive
+        }
+        rx.recv().unwrap(); // wait for child to be done
+    }
+
+    #[test]
+    fn test_sem_try_acquire() {
+        let s = Semaphore::new(2);
+        assert!(s.try_acquire());
+        assert!(!s.try_acquire_many(2));
+        assert_eq!(s.try_acquire_checked(), Ok(()));
+        assert_eq!(s.try_acquire_checked(), Err(TryAcquireError::WouldBlock));
+        s.release_many(2);
+        {
+            let _g = s.try_access_many_checked(2).unwrap();
+            assert!(s.try_access_checked().is_err());
+        }
+        assert!(s.try_access_checked().is_ok());
+    }
+
+    #[test]
+    fn test_sem_access_zero_is_noop() {
+        let s = Semaphore::new(0);
+        let g = s.access_many(0);
+        assert_eq!(s.available_permits(), 0);
+        drop(g);
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "negative number of resources (-3)")]
+    fn test_sem_access_negative_panics() {
+        let s = Semaphore::new(0);
+        let _g = s.access_many(-3);
+    }
+
+    #[test]
+    #[should_panic(expected = "negative number of resources")]
+    fn test_sem_try_acquire_negative_panics() {
+        Semaphore::new(0).try_acquire_many(-1);
+    }
+
+    #[test]
+    fn test_sem_try_acquire_status() {
+        let s = Semaphore::new(3);
+        assert_eq!(s.try_acquire_many_status(5), Err(2));
+        assert_eq!(s.try_acquire_many_status(3), Ok(()));
+        s.release_many(-1);
+        assert_eq!(s.try_acquire_many_status(1), Err(2));
+        s.release_many(2);
+        s.close();
+        assert_eq!(s.try_acquire_many_status(1), Err(1));
+    }
+
+    #[test]
+    fn test_sem_soft_limit() {
+        let s = Semaphore::new(3);
+        let (tx, rx) = channel();
+        s.set_soft_limit(1, move |count| tx.send(count).unwrap());
+        let a = s.access();
+        assert!(rx.try_recv().is_err());
+        let b = s.access_many(2);
+        assert_eq!(rx.try_recv(), Ok(0));
+        drop(a);
+        assert!(rx.try_recv().is_err());
+        drop(b);
+        let _c = s.access_many(3);
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sem_fair_order() {
+        let s = Arc::new(Semaphore::new_fair(0));
+        let s2 = s.clone();
+        let big = thread::spawn(move || s2.acquire_many(2));
+        wait_for_waiters(&s, 1);
+        let s2 = s.clone();
+        let small = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 2);
+
+        // The small request could be served, but it queued later.
+        s.release();
+        assert!(!s.try_acquire());
+        assert_eq!(s.try_acquire_many_status(1), Err(3));
+        s.release();
+        big.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+        s.release();
+        small.join().unwrap();
+        assert!(s.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_sem_acquire_sla() {
+        let s = Arc::new(Semaphore::new_fair(0));
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        thread::sleep(Duration::from_millis(20));
+        s.release();
+        t.join().unwrap();
+
+        let err = s.acquire_sla(Duration::from_millis(1)).err().unwrap();
+        assert!(err.estimate.unwrap() >= Duration::from_millis(20));
+        let err = Semaphore::new(0)
+            .acquire_sla(Duration::from_millis(1))
+            .err()
+            .unwrap();
+        assert_eq!(err.estimate, None);
+        s.release();
+        assert!(s.acquire_sla(Duration::from_millis(1)).is_ok());
+    }
+
+    #[test]
+    fn test_sem_pause() {
+        let s = Arc::new(Semaphore::new(1));
+        s.pause();
+        assert!(!s.try_acquire());
+        assert!(!s.acquire_timeout(Duration::from_millis(1)));
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_many(2));
+        wait_for_waiters(&s, 1);
+        s.release();
+        assert_eq!(s.available_permits(), 2);
+        s.resume();
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_drain_and_close() {
+        let s = Arc::new(Semaphore::new(1));
+        let guard = s.access();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || s.acquire_checked())
+            })
+            .collect();
+        wait_for_waiters(&s, 2);
+
+        assert_eq!(s.drain_and_close(), 0);
+        for t in waiters {
+            assert_eq!(t.join().unwrap(), Err(TryAcquireError::Closed));
+        }
+        drop(guard);
+        // Fails at once, even with a resource available.
+        assert_eq!(s.acquire_checked(), Err(TryAcquireError::Closed));
+        assert_eq!(s.available_permits(), 1);
+        assert_eq!(s.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_sem_drain_and_close_takes_available() {
+        let s = Semaphore::new(3);
+        s.acquire();
+        assert_eq!(s.drain_and_close(), 2);
+        assert_eq!(s.available_permits(), 0);
+        assert!(s.is_closed());
+        assert_eq!(Semaphore::new(-2).drain_and_close(), 0);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "released 2 resources into a closed semaphore")]
+    fn test_sem_release_into_closed() {
+        let s = Semaphore::new(1);
+        let guard = s.access();
+        s.close();
+        // Guards may still give back what they hold.
+        drop(guard);
+        s.release_many(2);
+    }
+
+    #[test]
+    fn test_sem_try_acquire_closed() {
+        let s = Semaphore::new(1);
+        s.close();
+        assert!(s.is_closed());
+        assert!(!s.try_acquire());
+        assert_eq!(s.try_acquire_checked(), Err(TryAcquireError::Closed));
+        assert_eq!(
+            s.try_access_many_checked(0).err(),
+            Some(TryAcquireError::Closed)
+        );
+    }
+
+    #[test]
+    fn test_sem_check_invariants() {
+        let s = Arc::new(Semaphore::bounded(1, 2));
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_many(2));
+        wait_for_waiters(&s, 1);
+        assert_eq!(s.check_invariants(), Ok(()));
+
+        s.inner.lock.lock().unwrap().count = 3;
+        assert_eq!(
+            s.check_invariants(),
+            Err(InvariantError::CountAboveMax { count: 3, max: 2 })
+        );
+        s.inner.lock.lock().unwrap().count = 1;
+        s.release();
+        t.join().unwrap();
+        assert_eq!(s.check_invariants(), Ok(()));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_sem_deadlock_detected() {
+        let a = Arc::new(Semaphore::new(1));
+        let b = Arc::new(Semaphore::with_name(1, "B_POOL"));
+        a.enable_deadlock_detection();
+        b.enable_deadlock_detection();
+        let guard = a.access();
+        let (a2, b2) = (a.clone(), b.clone());
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            let _b = b2.access();
+            tx.send(thread::current().id()).unwrap();
+            let _a = a2.access();
+        });
+        let child = rx.recv().unwrap();
+        wait_for_waiters(&a, 1);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| b.access()));
+        let report = *result.err().unwrap().downcast::<String>().unwrap();
+        assert!(report.starts_with("deadlock detected:"), "{}", report);
+        let me = thread::current().id();
+        assert!(report.contains(&format!("{:?} waits on", me)), "{}", report);
+        assert!(
+            report.contains("waits on the B_POOL semaphore"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains(&format!("held by {:?}", child)),
+            "{}",
+            report
+        );
+        assert_eq!(b.check_invariants(), Ok(()));
+
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(a.available_permits(), 1);
+        assert_eq!(b.available_permits(), 1);
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_sem_deadlock_needs_every_holder_blocked() {
+        // Another holder that isn't blocked can still release.
+        let s = Arc::new(Semaphore::new(2));
+        s.enable_deadlock_detection();
+        let guard = s.access();
+        let s2 = s.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            let _g = s2.access();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        });
+        rx.recv().unwrap();
+        let _second = s.access();
+        drop(guard);
+        t.join().unwrap();
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_sem_outstanding_acquirers() {
+        let s = Semaphore::new(3);
+        let g = s.access();
+        let s = Arc::new(s);
+        let s2 = s.clone();
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let t = thread::spawn(move || {
+            let _g = s2.access_many(2);
+            tx.send(thread::current().id()).unwrap();
+            let _ = done_rx.recv();
+        });
+        let child = rx.recv().unwrap();
+
+        let threads: Vec<_> = s
+            .outstanding_acquirers()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(threads, vec![thread::current().id(), child]);
+
+        drop(done_tx);
+        t.join().unwrap();
+        drop(g);
+        assert!(s.outstanding_acquirers().is_empty());
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_sem_acquire_or_deadlock_err() {
+        use WouldDeadlock;
+
+        let s = Arc::new(Semaphore::new(2));
+        let mine = s.acquire_or_deadlock_err().unwrap();
+        let (tx, rx) = channel();
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            let g = s2.access();
+            rx.recv().unwrap();
+            drop(g);
+        });
+        while s.available_permits() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Another thread can still give its resource back, so this waits.
+        tx.send(()).unwrap();
+        let second = s.acquire_or_deadlock_err().unwrap();
+        t.join().unwrap();
+        // Now only this thread holds anything.
+        assert_eq!(s.acquire_or_deadlock_err().err(), Some(WouldDeadlock));
+        drop((mine, second));
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_sem_path_metrics() {
+        let s = Arc::new(Semaphore::new(1));
+        s.acquire();
+        assert!(!s.try_acquire());
+        assert_eq!((s.fast_path_hits(), s.slow_path_falls()), (1, 0));
+
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        s.release();
+        t.join().unwrap();
+        assert!(!s.acquire_timeout(Duration::from_millis(1)));
+        assert_eq!((s.fast_path_hits(), s.slow_path_falls()), (1, 2));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_sem_contention_stats() {
+        let s = Arc::new(Semaphore::new(2));
+        drop(s.access());
+        assert_eq!(s.contention_stats(), (1, 0));
+
+        let all = s.access_many(2);
+        let s2 = s.clone();
+        let t = thread::spawn(move || drop(s2.access()));
+        wait_for_waiters(&s, 1);
+        assert_eq!(s.contention_stats(), (2, 1));
+        drop(all);
+        t.join().unwrap();
+        assert_eq!(s.contention_stats(), (2, 1));
+    }
+
+    #[test]
+    fn test_sem_release_wakes_only_when_satisfiable() {
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_many(3));
+        wait_for_waiters(&s, 1);
+
+        s.release();
+        s.release();
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 0);
+
+        s.release();
+        t.join().unwrap();
+        let state = s.inner.lock.lock().unwrap();
+        assert!(state.wakeups >= 1);
+        assert!(state.waiting.is_empty());
+        assert_eq!(state.count, 0);
+    }
+
+    #[test]
+    fn test_sem_guard_expansion_cap() {
+        let s = Semaphore::new(10);
+        let mut guard = s.access();
+        assert!(guard.try_expand(2));
+        assert!(guard.expand_timeout(1, Duration::from_millis(1)));
+        s.set_max_guard_amount(Some(5));
+        assert_eq!(s.max_guard_amount(), Some(5));
+
+        // A loop expanding without bound stops at the cap.
+        let mut expansions = 0;
+        while guard.try_expand(1) {
+            expansions += 1;
+        }
+        assert_eq!((expansions, guard.amount()), (1, 5));
+        assert!(!guard.expand_timeout(1, Duration::from_millis(1)));
+        // The cap is per guard.
+        let other = s.access_many(5);
+        assert_eq!(s.available_permits(), 0);
+        drop((guard, other));
+
+        s.set_max_guard_amount(None);
+        let mut guard = s.access_many(5);
+        guard.acquire_more(5);
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "would exceed the maximum guard amount")]
+    fn test_sem_acquire_more_past_cap() {
+        let s = Semaphore::new(3);
+        s.set_max_guard_amount(Some(2));
+        let mut guard = s.access_many(2);
+        guard.acquire_more(1);
+    }
+
+    #[test]
+    fn test_sem_try_acquire_many_checked() {
+        let s = Semaphore::bounded(2, 3);
+        assert_eq!(
+            s.try_acquire_many_checked(-1),
+            Err(AmountError::Negative { amount: -1 })
+        );
+        assert_eq!(
+            s.try_acquire_many_checked(4),
+            Err(AmountError::ExceedsMax { amount: 4, max: 3 })
+        );
+        // Valid, but more than is available.
+        assert_eq!(s.try_acquire_many_checked(3), Ok(false));
+        assert_eq!(s.try_acquire_many_checked(2), Ok(true));
+        assert_eq!(s.available_permits(), 0);
+
+        // Without a maximum, any non-negative amount is valid.
+        let s = Semaphore::new(1);
+        assert_eq!(s.try_acquire_many_checked(isize::MAX), Ok(false));
+        s.close();
+        assert_eq!(s.try_acquire_many_checked(1), Ok(false));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_sem_yield_through_lock() {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            for _ in 0..100 {
+                s2.yield_through_lock();
+            }
+        });
+        for _ in 0..100 {
+            drop(s.access());
+        }
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_or_recv() {
+        use Either;
+
+        let s = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tx.send("shutdown").unwrap();
+        });
+        assert_eq!(s.acquire_or_recv(&rx), Either::Right("shutdown"));
+        sender.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+
+        // With every sender gone, only the resource can end the wait.
+        let s2 = s.clone();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            s2.release();
+        });
+        assert_eq!(s.acquire_or_recv(&rx), Either::Left(()));
+        releaser.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_offline() {
+        let s = Arc::new(Semaphore::new(1));
+        let held = s.access();
+        s.set_online(false);
+        assert!(!s.is_online());
+        drop(held);
+        assert_eq!(s.available_permits(), 1);
+        assert!(!s.try_acquire());
+
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        s.set_online(true);
+        t.join().unwrap();
+        assert!(s.is_online());
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_count_watcher() {
+        let s = Arc::new(Semaphore::new(0));
+        let watcher = s.subscribe();
+        let (tx, rx) = channel();
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            for &amount in &[3, -1, -2] {
+                rx.recv().unwrap();
+                match amount {
+                    n if n > 0 => s2.release_many(n),
+                    n => s2.acquire_many(-n),
+                }
+            }
+        });
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            tx.send(()).unwrap();
+            seen.push(watcher.wait_change());
+        }
+        t.join().unwrap();
+        assert_eq!(seen, [3, 2, 0]);
+
+        // A change made while nobody waits isn't missed.
+        s.release();
+        assert_eq!(watcher.wait_change(), 1);
+    }
+
+    #[test]
+    fn test_sem_access_diagnostic() {
+        let s = Arc::new(Semaphore::new_fair(0));
+        let spawn = || {
+            let s = s.clone();
+            thread::spawn(move || {
+                let (_guard, diag) = s.access_diagnostic();
+                diag
+            })
+        };
+        let first = spawn();
+        wait_for_waiters(&s, 1);
+        let second = spawn();
+        wait_for_waiters(&s, 2);
+
+        thread::sleep(Duration::from_millis(10));
+        s.release();
+        let first = first.join().unwrap();
+        assert_eq!(first.permits_passed_while_waiting, 0);
+        assert!(first.head_wait >= Duration::from_millis(10));
+        let second = second.join().unwrap();
+        assert_eq!(second.permits_passed_while_waiting, 1);
+        assert!(second.queue_wait >= Duration::from_millis(10));
+
+        let (_guard, diag) = s.access_diagnostic();
+        assert_eq!(diag, AcquireDiag::default());
+    }
+
+    #[test]
+    fn test_guard_into_raw() {
+        let s = Semaphore::new(4);
+        let (handle, amount) = s.access_many(3).into_raw();
+        assert_eq!(amount, 3);
+        assert_eq!(s.available_permits(), 1);
+        handle.release_many(amount);
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_sem_initial() {
+        let s = Semaphore::bounded(-2, 4);
+        s.release_many(3);
+        assert_eq!((s.initial(), s.available_permits()), (-2, 1));
+    }
+
+    #[test]
+    fn test_sem_wait_outstanding() {
+        let s = Arc::new(Semaphore::new(2));
+        assert!(s.wait_outstanding_timeout(0, Duration::from_millis(0)));
+        let guard = s.access();
+        assert!(!s.wait_outstanding_timeout(2, Duration::from_millis(10)));
+
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            let _guard = s2.access();
+            thread::sleep(Duration::from_millis(10));
+        });
+        s.wait_outstanding(2);
+        assert_eq!(s.available_permits(), 0);
+        t.join().unwrap();
+        drop(guard);
+        s.wait_outstanding(0);
+    }
+
+    #[test]
+    fn test_sem_wait_outstanding_reconfigured() {
+        let s = Semaphore::bounded(2, 2);
+        let guard = s.access();
+        s.resize(5);
+        assert!(s.wait_outstanding_timeout(1, Duration::from_millis(0)));
+        drop(guard);
+        assert!(s.wait_outstanding_timeout(0, Duration::from_millis(0)));
+
+        let checkpoint = s.capture();
+        let stale = s.access_many(2);
+        s.set_count(3);
+        assert!(s.wait_outstanding_timeout(0, Duration::from_millis(0)));
+        drop(stale);
+        s.acquire();
+        assert!(s.wait_outstanding_timeout(1, Duration::from_millis(0)));
+        s.restore(checkpoint);
+        assert!(s.wait_outstanding_timeout(0, Duration::from_millis(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "acquiring from the STUCK semaphore blocked for more than 10ms")]
+    fn test_sem_acquire_asserting() {
+        let s = Semaphore::with_name(1, "STUCK");
+        s.acquire_asserting(Duration::from_millis(10));
+        s.acquire_asserting(Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_sem_acquire_stream() {
+        let s = Arc::new(Semaphore::new(2));
+        let mut stream = s.acquire_stream(5);
+        let mut guards = vec![stream.next().unwrap()];
+        assert_eq!(
+            (s.available_permits(), stream.size_hint()),
+            (0, (4, Some(4)))
+        );
+        guards.push(stream.next().unwrap());
+
+        // Once the chunk is used up, it blocks for one resource at a time.
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            wait_for_waiters(&s2, 1);
+            s2.release_many(3);
+        });
+        guards.extend(stream.by_ref().take(2));
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+        assert_eq!(stream.size_hint(), (1, Some(1)));
+        drop(stream);
+        // The resource acquired but never handed out went back.
+        assert_eq!(s.available_permits(), 1);
+        drop(guards);
+        assert_eq!(s.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_acquire_stream_set_count() {
+        let s = Semaphore::new(4);
+        let mut stream = s.acquire_stream(4);
+        let first = stream.next().unwrap();
+        s.set_count(5);
+        // Neither the guards from the chunk nor what's left of it count
+        // toward the new count.
+        let second = stream.next().unwrap();
+        assert!(!second.is_current());
+        drop((first, second, stream));
+        assert_eq!(s.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_acquire_with_fallback() {
+        let primary = Semaphore::new(2);
+        let fallback = Semaphore::new(1);
+        let guards: Vec<_> = (0..3)
+            .map(|_| primary.acquire_with_fallback(&fallback))
+            .collect();
+        let spilled: Vec<_> = guards.iter().map(|&(spilled, _)| spilled).collect();
+        assert_eq!(spilled, [false, false, true]);
+        assert_eq!(
+            (primary.available_permits(), fallback.available_permits()),
+            (0, 0)
+        );
+
+        // Each guard goes back to the pool it came from.
+        drop(guards);
+        assert_eq!(
+            (primary.available_permits(), fallback.available_permits()),
+            (2, 1)
+        );
+    }
+
+    #[test]
+    fn test_sem_with_name() {
+        let s = Semaphore::new(2);
+        assert_eq!(s.name(), None);
+        assert_eq!(format!("{:?}", s), "Semaphore { count: 2 }");
+
+        let s = Semaphore::with_name(-1, "DB_POOL");
+        assert_eq!(
+            format!("{:?}", s),
+            r#"Semaphore { name: "DB_POOL", count: -1 }"#
+        );
+    }
+
+    #[test]
+    fn test_sem_with_permits() {
+        let s = Arc::new(Semaphore::new(4));
+        let doubled = with_permits!(s, 3, {
+            assert_eq!(s.available_permits(), 1);
+            21 * 2
+        });
+        assert_eq!(doubled, 42);
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_sem_checkpoint() {
+        let s = Arc::new(Semaphore::new(-1));
+        let locked = Arc::new(AtomicBool::new(false));
+        let data = Arc::new(AtomicUsize::new(0));
+        let (s2, locked2, data2) = (s.clone(), locked.clone(), data.clone());
+        let t = thread::spawn(move || {
+            s2.with_count(|_| {
+                locked2.store(true, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(10));
+                data2.store(1, Ordering::Relaxed);
+            })
+        });
+        while !locked.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        // Waits for the lock's holder, whatever the count.
+        s.checkpoint();
+        assert_eq!(data.load(Ordering::Relaxed), 1);
+        s.checkpoint_and_yield();
+        assert_eq!(s.available_permits(), -1);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_fair_release_wakes_only_the_head() {
+        let s = Arc::new(Semaphore::new_fair(0));
+        let threads: Vec<_> = [2, 1, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &amount)| {
+                let s2 = s.clone();
+                let t = thread::spawn(move || s2.acquire_many(amount));
+                wait_for_waiters(&s, i + 1);
+                t
+            })
+            .collect();
+
+        // The head can't proceed yet, and those behind it mustn't go first.
+        s.release();
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 0);
+
+        let mut threads = threads.into_iter();
+        s.release();
+        threads.next().unwrap().join().unwrap();
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 1);
+
+        s.release_many(2);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 3);
+    }
+
+    #[test]
+    fn test_sem_release_wakes_only_as_many_as_can_proceed() {
+        let s = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let threads: Vec<_> = (0..10)
+            .map(|_| {
+                let (s, tx) = (s.clone(), tx.clone());
+                thread::spawn(move || {
+                    s.acquire();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+        wait_for_waiters(&s, 10);
+
+        s.release();
+        rx.recv().unwrap();
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 1);
+
+        s.release_many(9);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.inner.lock.lock().unwrap().wakeups, 10);
+    }
+
+    #[test]
+    fn test_sem_budgeted_wakeups_mixed_amounts() {
+        // Whichever waiters get picked, every one of them has to finish.
+        let s = Arc::new(Semaphore::new(0));
+        let threads: Vec<_> = (0..16)
+            .map(|i| {
+                let s = s.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let _g = s.access_many(i % 4 + 1);
+                    }
+                })
+            })
+            .collect();
+        s.release_many(4);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_sem_acquirable_trait_object() {
+        struct Counted {
+            sem: Semaphore,
+            acquired: std::cell::Cell<usize>,
+        }
+
+        impl Acquirable for Counted {
+            fn acquire(&self) {
+                self.sem.acquire();
+                self.acquired.set(self.acquired.get() + 1);
+            }
+            fn try_acquire(&self) -> bool {
+                self.sem.try_acquire()
+            }
+            fn release(&self) {
+                self.sem.release();
+            }
+            fn available(&self) -> isize {
+                self.sem.available_permits()
+            }
+        }
+
+        let limiters: Vec<Box<dyn Acquirable>> = vec![
+            Box::new(Semaphore::new(2)),
+            Box::new(Counted {
+                sem: Semaphore::new(2),
+                acquired: std::cell::Cell::new(0),
+            }),
+        ];
+        for limiter in &limiters {
+            limiter.acquire();
+            assert_eq!(limiter.available(), 1);
+            let mut seen = None;
+            limiter.with_permit(&mut || seen = Some(limiter.available()));
+            assert_eq!(seen, Some(0));
+            assert_eq!(limiter.available(), 1);
+            assert!(limiter.try_acquire());
+            assert!(!limiter.try_acquire());
+            limiter.release();
+            limiter.release();
+            assert_eq!(limiter.available(), 2);
+        }
+    }
+
+    #[test]
+    fn test_sem_with_permit_closed() {
+        let s = Semaphore::new(1);
+        s.with_permit(&mut || s.close());
+        assert_eq!(s.available_permits(), 1);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            s.with_permit(&mut || panic!("failed while closed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_timed_access() {
+        let s = Arc::new(Semaphore::new(1));
+        let g = s.timed_access();
+        assert!(g.wait_time() < Duration::from_secs(1));
+
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.timed_access().wait_time());
+        wait_for_waiters(&s, 1);
+        thread::sleep(Duration::from_millis(20));
+        drop(g);
+        assert!(t.join().unwrap() >= Duration::from_millis(20));
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot acquire a negative number of resources (-1)")]
+    fn test_sem_reservation_settle_negative() {
+        let s = Semaphore::new(2);
+        let _guard = s.reserve(1).settle(-1);
+    }
+
+    #[test]
+    fn test_sem_reservation_settle_negative_releases() {
+        let s = Semaphore::new(2);
+        let r = s.reserve(2);
+        let settled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| r.settle(-1)));
+        assert!(settled.is_err());
+        assert_eq!(s.available_permits(), 2);
+        let reserved = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| s.reserve(-1)));
+        assert!(reserved.is_err());
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_reservation_settle() {
+        let s = Semaphore::new(5);
+
+        // Overestimate: the difference goes back immediately.
+        let r = s.reserve(4);
+        assert_eq!(r.estimate(), 4);
+        assert_eq!(s.available_permits(), 1);
+        let g = r.settle(2);
+        assert_eq!(s.available_permits(), 3);
+        drop(g);
+        assert_eq!(s.available_permits(), 5);
+
+        // Underestimate: the settle blocks until the rest is available.
+        let s = Arc::new(s);
+        let held = s.access_many(3);
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            let g = s2.reserve(1).settle(4);
+            assert_eq!(s2.available_permits(), 1);
+            drop(g);
+        });
+        wait_for_waiters(&s, 1);
+        drop(held);
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 5);
+
+        // Unsettled reservations release everything.
+        drop(s.reserve(5));
+        assert_eq!(s.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_timeout() {
+        let s = Arc::new(Semaphore::new(1));
+        assert!(!s.acquire_many_timeout(2, Duration::from_millis(10)));
+        assert_eq!(s.available_permits(), 1);
+        {
+            let _g = s.access_timeout(Duration::from_millis(10)).unwrap();
+            assert!(s.access_timeout(Duration::from_millis(10)).is_none());
+            assert!(s.try_access().is_none());
+        }
+        assert!(s.try_access_many(1).is_some());
+
+        let held = s.access();
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_timeout(Duration::from_secs(60)));
+        wait_for_waiters(&s, 1);
+        drop(held);
+        assert!(t.join().unwrap());
+        assert_eq!(s.available_permits(), 0);
+        assert!(s.inner.lock.lock().unwrap().waiting.is_empty());
+    }
+
+    #[test]
+    fn test_sem_min_hold() {
+        let s = Semaphore::new(1);
+        let start = Instant::now();
+        drop(s.access_min_hold(Duration::from_millis(30)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(s.available_permits(), 1);
+
+        // Guards outliving their minimum release without delay.
+        let g = s.access_min_hold(Duration::from_millis(0));
+        thread::sleep(Duration::from_millis(1));
+        drop(g);
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_min_hold_releases_on_unwind() {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        let result = thread::spawn(move || {
+            let _g = s2.access_min_hold(Duration::from_millis(10));
+            panic!("boom");
+        })
+        .join();
+        assert!(result.is_err());
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_bounded() {
+        let s = Semaphore::bounded(2, 5);
+        assert_eq!(s.max(), Some(5));
+        assert_eq!(Semaphore::new(2).max(), None);
+        s.release_many(3);
+        s.acquire_many(5);
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot acquire 6 resources from a semaphore with a maximum of 5")]
+    fn test_sem_bounded_acquire_over_max() {
+        let s = Semaphore::bounded(5, 5);
+        s.acquire_many(6);
+    }
+
+    #[test]
+    fn test_sem_guarded_into_value() {
+        let s = Semaphore::new(3);
+        let mut v = s.access_many(2).bundle(vec![1]);
+        v.push(2);
+        assert_eq!(s.available_permits(), 1);
+        assert_eq!(v.into_value(), vec![1, 2]);
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    /// Wraps `CondvarParker`, counting the notifications it sends.
+    struct CountingParker {
+        parker: CondvarParker,
+        notifies: Arc<AtomicUsize>,
+    }
+
+    impl Parker for CountingParker {
+        fn park<'a>(
+            &self,
+            lock: &'a Mutex<SemaphoreState>,
+            guard: MutexGuard<'a, SemaphoreState>,
+            timeout: Option<Duration>,
+        ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+            self.parker.park(lock, guard, timeout)
+        }
+
+        fn unpark_all(&self) {
+            self.notifies.fetch_add(1, Ordering::SeqCst);
+            self.parker.unpark_all();
+        }
+    }
+
+    #[test]
+    fn test_sem_uncontended_never_notifies() {
+        let notifies = Arc::new(AtomicUsize::new(0));
+        let s = Arc::new(Semaphore::with_parker(
+            2,
+            CountingParker {
+                parker: CondvarParker::new(),
+                notifies: notifies.clone(),
+            },
+        ));
+        s.acquire();
+        s.release();
+        drop(s.access_many(2));
+        assert!(s.try_acquire());
+        s.release();
+        assert!(s.acquire_timeout(Duration::from_millis(1)));
+        s.release();
+        assert_eq!(notifies.load(Ordering::SeqCst), 0);
+
+        // Only a release that lets a parked waiter proceed notifies.
+        let _g = s.access_many(2);
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        s.release();
+        t.join().unwrap();
+        assert_eq!(notifies.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_sem_defer_notifies_coalesces() {
+        use future::tests::poll_once;
+
+        let notifies = Arc::new(AtomicUsize::new(0));
+        let s = Semaphore::with_parker(
+            0,
+            CountingParker {
+                parker: CondvarParker::new(),
+                notifies: notifies.clone(),
+            },
+        );
+        // Pending tasks count as waiters, so each release would notify.
+        let mut tasks: Vec<_> = (0..3).map(|_| Box::pin(s.access_async())).collect();
+        for task in &mut tasks {
+            assert!(poll_once(task).is_pending());
+        }
+
+        let scope = s.defer_notifies();
+        let nested = s.defer_notifies();
+        for _ in 0..3 {
+            s.release();
+        }
+        drop(nested);
+        assert_eq!(notifies.load(Ordering::SeqCst), 0);
+        drop(scope);
+        assert_eq!(notifies.load(Ordering::SeqCst), 1);
+        let guards: Vec<_> = tasks.iter_mut().map(poll_once).collect();
+        assert!(guards.iter().all(|guard| guard.is_ready()));
+
+        // A scope with nothing held back doesn't notify.
+        let before = notifies.load(Ordering::SeqCst);
+        drop(s.defer_notifies());
+        assert_eq!(notifies.load(Ordering::SeqCst), before);
+    }
 
     #[test]
-    fn test_sem_acquire_release() {
-        let s = Semaphore::new(1);
-        s.acquire();
-        s.release();
-        s.acquire();
+    fn test_sem_release_batch_notifies_once() {
+        use future::tests::poll_once;
+
+        let notifies = Arc::new(AtomicUsize::new(0));
+        let s = Semaphore::with_parker(
+            0,
+            CountingParker {
+                parker: CondvarParker::new(),
+                notifies: notifies.clone(),
+            },
+        );
+        let mut tasks: Vec<_> = (0..3).map(|_| Box::pin(s.access_async())).collect();
+        for task in &mut tasks {
+            assert!(poll_once(task).is_pending());
+        }
+
+        s.release_batch(|releaser| {
+            for _ in 0..3 {
+                releaser.release(1);
+            }
+            assert_eq!(releaser.amount(), 3);
+            assert_eq!(s.available_permits(), 0);
+        });
+        assert_eq!(notifies.load(Ordering::SeqCst), 1);
+        let guards: Vec<_> = tasks.iter_mut().map(poll_once).collect();
+        assert!(guards.iter().all(|guard| guard.is_ready()));
     }
 
     #[test]
-    fn test_sem_basic() {
-        let s = Semaphore::new(1);
-        let _g = s.access();
+    fn test_sem_thread_parker() {
+        let s = Arc::new(Semaphore::with_parker(0, ThreadParker::new()));
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || s.acquire_many(2))
+            })
+            .collect();
+        wait_for_waiters(&s, 3);
+        s.release_many(4);
+        while s.available_permits() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            s.inner.lock.lock().unwrap().waiting.values().sum::<usize>(),
+            1
+        );
+        s.release_many(2);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 0);
     }
 
     #[test]
-    fn test_sem_as_mutex() {
+    fn test_sem_backends() {
+        let backends = [
+            Backend::Std,
+            #[cfg(feature = "parking_lot")]
+            Backend::ParkingLot,
+            Backend::Spin,
+        ];
+        for &backend in &backends {
+            let s = Arc::new(Semaphore::with_backend(0, backend));
+            let s2 = s.clone();
+            let t = thread::spawn(move || s2.acquire_many(2));
+            wait_for_waiters(&s, 1);
+            s.release();
+            assert!(!s.acquire_many_timeout(2, Duration::from_millis(5)));
+            s.release_many(2);
+            t.join().unwrap();
+            assert_eq!(s.available_permits(), 1, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn test_sem_rotate() {
+        let stage1 = Semaphore::new(1);
+        let stage2 = Arc::new(Semaphore::new(1));
+        stage1.acquire();
+        let g = rotate(&stage1, &stage2, 1);
+        assert_eq!(stage1.available_permits(), 1);
+        assert_eq!(stage2.available_permits(), 0);
+
+        // Without stage 2 credit, the release happens first and the rotation
+        // waits for the acquisition.
+        stage1.acquire();
+        let s2 = stage2.clone();
+        let stage1 = Arc::new(stage1);
+        let s1 = stage1.clone();
+        let t = thread::spawn(move || drop(rotate(&s1, &s2, 1)));
+        wait_for_waiters(&stage2, 1);
+        assert_eq!(stage1.available_permits(), 1);
+        drop(g);
+        t.join().unwrap();
+        assert_eq!(stage2.available_permits(), 1);
+
+        let _same = rotate(&stage1, &stage1, 1);
+        assert_eq!(stage1.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_all_n_opposite_orders() {
+        let sems = Arc::new([Semaphore::new(1), Semaphore::new(2), Semaphore::new(3)]);
+        let s = sems.clone();
+        let t = thread::spawn(move || {
+            for _ in 0..1000 {
+                let guards = acquire_all_n([(&s[2], 3), (&s[1], 2), (&s[0], 1)]);
+                assert_eq!(guards.map(|g| g.amount()), [3, 2, 1]);
+            }
+        });
+        for _ in 0..1000 {
+            let guards = acquire_all_n([(&sems[0], 1), (&sems[1], 2), (&sems[2], 3)]);
+            assert_eq!(guards.map(|g| g.amount()), [1, 2, 3]);
+        }
+        t.join().unwrap();
+        for (i, sem) in sems.iter().enumerate() {
+            assert_eq!(sem.available_permits(), i as isize + 1);
+        }
+    }
+
+    #[test]
+    fn test_sem_wait_checked_poisoned() {
         let s = Arc::new(Semaphore::new(1));
+        assert!(s.wait_checked().is_ok());
+        assert_eq!(s.available_permits(), 0);
+
+        // A producer panics while holding the lock.
         let s2 = s.clone();
-        let _t = thread::spawn(move || {
-            let _g = s2.access();
+        let producer = thread::spawn(move || {
+            let _state = s2.inner.lock.lock().unwrap();
+            panic!("producer failed");
         });
-        let _g = s.access();
+        assert!(producer.join().is_err());
+        assert!(s.wait_checked().is_err());
+        assert!(s.wait_checked().is_err());
     }
 
     #[test]
-    fn test_sem_as_cvar() {
-        // Child waits and parent signals
-        let (tx, rx) = channel();
+    fn test_sem_wait_checked_poisoned_while_parked() {
         let s = Arc::new(Semaphore::new(0));
         let s2 = s.clone();
-        let _t = thread::spawn(move || {
-            s2.acquire();
-            tx.send(()).unwrap();
+        let waiter = thread::spawn(move || s2.wait_checked());
+        wait_for_waiters(&s, 1);
+
+        let s2 = s.clone();
+        let producer = thread::spawn(move || {
+            let _state = s2.inner.lock.lock().unwrap();
+            panic!("producer failed");
         });
-        s.release();
-        let _ = rx.recv();
+        assert!(producer.join().is_err());
+        // Wake the waiter as a release would have, had it not panicked.
+        s.inner.parker.unpark_all();
+        assert!(waiter.join().unwrap().is_err());
+        // The waiter left nothing of its wait behind.
+        let state = s
+            .inner
+            .lock
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+        assert!(state.waiting.is_empty());
+    }
 
-        // Parent waits and child signals
-        let (tx, rx) = channel();
+    #[test]
+    fn test_sem_spawn_scoped_guarded() {
+        let s = Semaphore::new(2);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..6)
+                .map(|i| {
+                    let (running, peak) = (&running, &peak);
+                    s.spawn_scoped_guarded(scope, move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(5));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        i
+                    })
+                })
+                .collect();
+            let sum: i32 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+            assert_eq!(sum, 15);
+        });
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_wait_timeout_while() {
+        let s = Arc::new(Semaphore::new(2));
+        let s2 = s.clone();
+        let drained =
+            thread::spawn(move || s2.wait_timeout_while(Duration::from_secs(60), |c| c <= 0));
+        let s3 = s.clone();
+        let refilled =
+            thread::spawn(move || s3.wait_timeout_while(Duration::from_secs(60), |c| c >= 3));
+        while s.inner.lock.lock().unwrap().watchers < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Acquisitions wake watchers too, not just releases.
+        s.acquire();
+        s.acquire();
+        assert!(drained.join().unwrap());
+        s.release_many(3);
+        assert!(refilled.join().unwrap());
+        assert_eq!(s.available_permits(), 3);
+        assert_eq!(s.inner.lock.lock().unwrap().watchers, 0);
+    }
+
+    #[test]
+    fn test_sem_access_batch() {
+        let s = Semaphore::new(5);
+        let mut batch = s.access_batch(3);
+        batch.consume_one();
+        batch.consume_one();
+        assert_eq!(batch.remaining(), 1);
+        assert_eq!(s.available_permits(), 4);
+        drop(batch);
+        assert_eq!(s.available_permits(), 5);
+
+        let mut batch = s.access_batch(1);
+        batch.consume_one();
+        drop(batch);
+        assert_eq!(s.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_ledger_stages() {
+        let s = Semaphore::new(10);
+        let mut ledger = s.acquire_ledger(10);
+        for (stage, n) in [3, 5, 2].iter().enumerate() {
+            ledger.release(*n);
+            assert_eq!(s.available_permits(), [3, 8, 10][stage]);
+        }
+        assert_eq!(ledger.held(), 0);
+        drop(ledger);
+        assert_eq!(s.available_permits(), 10);
+
+        let mut ledger = s.acquire_ledger(4);
+        ledger.release(1);
+        drop(ledger);
+        assert_eq!(s.available_permits(), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot release 2 resources from a PermitLedger holding 1")]
+    fn test_sem_ledger_overrelease() {
+        let s = Semaphore::new(1);
+        let mut ledger = s.acquire_ledger(1);
+        ledger.release(2);
+    }
+
+    #[test]
+    fn test_sem_try_acquire_spin() {
         let s = Arc::new(Semaphore::new(0));
+        assert!(!s.try_acquire_spin(0));
+        assert!(!s.try_acquire_spin(100));
         let s2 = s.clone();
-        let _t = thread::spawn(move || {
-            s2.release();
-            let _ = rx.recv();
+        let t = thread::spawn(move || s2.release());
+        while !s.try_acquire_spin(1000) {}
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+
+        s.release();
+        s.close();
+        assert!(!s.try_acquire_spin(u32::MAX));
+    }
+
+    #[test]
+    fn test_sem_overcommit_and_repay() {
+        let s = Arc::new(Semaphore::new(2));
+        s.overcommit(3);
+        assert_eq!(s.available_permits(), -1);
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        s.repay(1);
+        // Back to zero, which still isn't enough for the waiter.
+        assert_eq!(
+            s.inner.lock.lock().unwrap().waiting.values().sum::<usize>(),
+            1
+        );
+        s.repay(2);
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot overcommit a negative number of resources (-1)")]
+    fn test_sem_overcommit_negative() {
+        Semaphore::new(0).overcommit(-1);
+    }
+
+    #[test]
+    fn test_sem_acquire_many_holds_nothing_while_waiting() {
+        let s = Arc::new(Semaphore::new(2));
+        let guard = s.access();
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_many(2));
+        wait_for_waiters(&s, 1);
+        // The waiter hasn't taken the one resource that is free.
+        assert_eq!(s.available_permits(), 1);
+        assert!(s.try_acquire());
+        s.release();
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_many_overlapping_never_deadlocks() {
+        let s = Arc::new(Semaphore::new(3));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let _g = s.access_many(2);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_acquire_each_holds_earlier_amounts() {
+        let s = Arc::new(Semaphore::new(2));
+        let guard = s.access();
+        let s2 = s.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            let guards = s2.acquire_each(&[1, 1]);
+            tx.send(guards.len()).unwrap();
+            let mut guards = guards.into_iter();
+            drop(guards.next());
+            // The second guard is still held.
+            assert_eq!(s2.available_permits(), 1);
         });
-        s.acquire();
-        tx.send(()).unwrap();
+        wait_for_waiters(&s, 1);
+        // The first amount was taken while the second is waited for.
+        assert_eq!(s.available_permits(), 0);
+        drop(guard);
+        assert_eq!(rx.recv().unwrap(), 2);
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 2);
     }
 
     #[test]
-    fn test_sem_multi_resource() {
-        // Parent and child both get in the critical section at the same
-        // time, and shake hands.
+    fn test_sem_guard_adjustments() {
+        let s = Semaphore::new(4);
+        let mut guard = s.access();
+        guard.acquire_more(3);
+        assert_eq!((guard.amount(), s.available_permits()), (4, 0));
+        let piece = guard.split(0);
+        drop(piece);
+        guard.release_partial(4);
+        assert_eq!((guard.amount(), s.available_permits()), (0, 4));
+        drop(guard);
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot release 2 resources from a guard holding 1")]
+    fn test_sem_guard_release_partial_too_many() {
+        let s = Semaphore::new(2);
+        s.access().release_partial(2);
+    }
+
+    #[test]
+    fn test_sem_owned_split_across_threads() {
+        let s = Arc::new(Semaphore::new(4));
+        let (mut piece, mut rest) = s.acquire_many_owned(4).split(1);
+        let mut handles = Vec::new();
+        while rest.amount() > 0 {
+            handles.push(thread::spawn(move || drop(piece)));
+            let next = rest.split(1);
+            piece = next.0;
+            rest = next.1;
+        }
+        handles.push(thread::spawn(move || drop(piece)));
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 4);
+        drop(rest);
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot split 3 resources off a guard holding 2")]
+    fn test_sem_owned_split_too_many() {
         let s = Arc::new(Semaphore::new(2));
+        let _ = s.acquire_many_owned(2).split(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "exhausted BatchGuard")]
+    fn test_sem_access_batch_overconsume() {
+        let s = Semaphore::new(1);
+        let mut batch = s.access_batch(1);
+        batch.consume_one();
+        batch.consume_one();
+    }
+
+    #[test]
+    fn test_sem_release_wakes_threads_and_tasks() {
+        let s = Arc::new(Semaphore::new(0));
         let s2 = s.clone();
-        let (tx1, rx1) = channel();
-        let (tx2, rx2) = channel();
-        let _t = thread::spawn(move || {
-            let _g = s2.access();
-            let _ = rx2.recv();
-            tx1.send(()).unwrap();
+        let blocking = thread::spawn(move || s2.acquire());
+        let s3 = s.clone();
+        let task = thread::spawn(move || {
+            let guard = ::future::tests::block_on(s3.access_many_async(2));
+            guard.amount
         });
-        let _g = s.access();
-        tx2.send(()).unwrap();
-        rx1.recv().unwrap();
+        wait_for_waiters(&s, 2);
+
+        s.release();
+        s.release();
+        s.release();
+        blocking.join().unwrap();
+        assert_eq!(task.join().unwrap(), 2);
+        // The task's guard was dropped with its thread.
+        assert_eq!(s.available_permits(), 2);
+        let state = s.inner.lock.lock().unwrap();
+        assert!(state.waiting.is_empty());
+        assert_eq!(state.wakers.len(), 0);
     }
 
     #[test]
-    fn test_sem_runtime_friendly_blocking() {
+    fn test_sem_access_async_dropped_while_pending() {
+        use future::tests::poll_once;
+        use std::task::Poll;
+
+        let s = Semaphore::new(1);
+        let mut pending = Box::pin(s.access_many_async(2));
+        assert!(poll_once(&mut pending).is_pending());
+        assert_eq!(s.inner.lock.lock().unwrap().wakers.len(), 1);
+        drop(pending);
+        let state = s.inner.lock.lock().unwrap();
+        assert!(state.waiting.is_empty() && state.wakers.len() == 0);
+        drop(state);
+        match poll_once(&mut Box::pin(s.access_async())) {
+            Poll::Ready(guard) => assert_eq!(guard.amount, 1),
+            Poll::Pending => panic!("permit should be available"),
+        }
+    }
+
+    #[test]
+    fn test_sem_acquire_until() {
+        use future::tests::poll_once;
+        use std::future;
+        use std::task::Poll;
+        use Cancelled;
+
+        let s = Semaphore::new_fair(1);
+        let mut cancelled = Box::pin(s.acquire_until(future::ready(())));
+        assert!(matches!(
+            poll_once(&mut cancelled),
+            Poll::Ready(Err(Cancelled))
+        ));
+        assert_eq!(s.available_permits(), 1);
+
+        let held = s.access();
+        let mut waiting = Box::pin(s.acquire_until(future::pending::<()>()));
+        assert!(poll_once(&mut waiting).is_pending());
+        let mut cancelled = Box::pin(s.acquire_until(future::ready(())));
+        assert!(matches!(
+            poll_once(&mut cancelled),
+            Poll::Ready(Err(Cancelled))
+        ));
+        // The cancelled acquisition isn't left in the queue.
+        assert_eq!(
+            s.inner.lock.lock().unwrap().fair.as_ref().unwrap().demand(),
+            1
+        );
+        drop(held);
+        match poll_once(&mut waiting) {
+            Poll::Ready(Ok(guard)) => assert_eq!(guard.amount, 1),
+            _ => panic!("the uncancelled acquisition should have the resource"),
+        }
+    }
+
+    #[test]
+    fn test_sem_access_async_cancelled_while_linked() {
+        use future::tests::poll_once;
+
+        let s = Semaphore::new(0);
+        let mut tasks: Vec<_> = (0..3).map(|_| Box::pin(s.access_async())).collect();
+        for task in &mut tasks {
+            assert!(poll_once(task).is_pending());
+        }
+        // Cancelling from the middle of the list leaves the others linked.
+        drop(tasks.remove(1));
+        assert_eq!(s.inner.lock.lock().unwrap().wakers.len(), 2);
+        s.release();
+        assert_eq!(s.inner.lock.lock().unwrap().wakers.len(), 0);
+        // A task woken and then cancelled has nothing left to unlink.
+        let woken = tasks.pop().unwrap();
+        assert!(poll_once(&mut tasks[0]).is_ready());
+        drop(woken);
+        assert!(s.inner.lock.lock().unwrap().waiting.is_empty());
+        assert!(s.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_sem_access_async_wakes_every_batch() {
+        use future::tests::poll_once;
+
+        let s = Semaphore::new(0);
+        let mut tasks: Vec<_> = (0..100).map(|_| Box::pin(s.access_async())).collect();
+        for task in &mut tasks {
+            assert!(poll_once(task).is_pending());
+        }
+        s.release_many(100);
+        assert_eq!(s.inner.lock.lock().unwrap().wakers.len(), 0);
+        assert!(tasks.iter_mut().all(|task| poll_once(task).is_ready()));
+    }
+
+    #[test]
+    fn test_sem_fair_async_queues() {
+        use future::tests::poll_once;
+
+        let s = Semaphore::new_fair(1);
+        let mut big = Box::pin(s.access_many_async(2));
+        assert!(poll_once(&mut big).is_pending());
+        let mut small = Box::pin(s.access_async());
+        assert!(poll_once(&mut small).is_pending());
+        // Giving up the head of the queue lets the next in line through.
+        drop(big);
+        assert!(poll_once(&mut small).is_ready());
+        assert!(s.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_sem_acquire_seq_fifo() {
+        let s = Arc::new(Semaphore::new_fair(0));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let s2 = s.clone();
+                let t = thread::spawn(move || s2.acquire_seq());
+                // Start waiting in order.
+                wait_for_waiters(&s, i + 1);
+                t
+            })
+            .collect();
+        s.release_many(4);
+        let seqs: Vec<u64> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sem_max_waiters_rejects_excess() {
+        let s = Arc::new(Semaphore::new_fair_with_max_waiters(0, 2));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || s.acquire_admitted().map(|g| g.leak()).is_ok())
+            })
+            .collect();
+        wait_for_waiters(&s, 2);
+
+        assert_eq!(s.acquire_admitted().err(), Some(QueueFull));
+        s.release_many(3);
+        for t in threads {
+            assert!(t.join().unwrap());
+        }
+        // With resources to spare, nobody has to queue.
+        assert!(s.acquire_admitted().is_ok());
+    }
+
+    #[test]
+    fn test_sem_stale_guards_release_nothing() {
+        let s = Semaphore::new(3);
+        let before = s.access_many(2);
+        let mut batch = s.access_batch(1);
+        s.set_count(4);
+        assert_eq!(s.generation(), 1);
+        assert!(!before.is_current());
+        batch.consume_one();
+        drop(before);
+        assert_eq!(s.available_permits(), 4);
+
+        let after = s.access();
+        s.bump_generation();
+        drop(after);
+        assert_eq!((s.available_permits(), s.generation()), (3, 2));
+        // Guards from the current generation release as usual.
+        drop(s.access());
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_set_count_over_max() {
+        let s = Semaphore::bounded(1, 2);
+        let stale = s.access();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| s.set_count(3)));
+        assert!(result.is_err());
+        // Nothing was reset, so the guard still releases.
+        assert!(stale.is_current());
+        drop(stale);
+        assert_eq!((s.available_permits(), s.generation()), (1, 0));
+        s.set_count(2);
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_try_release_many() {
+        let s = Arc::new(Semaphore::bounded(0, 2));
+        let s2 = s.clone();
+        let waiter = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        assert_eq!(s.try_release_many(1), Ok(()));
+        waiter.join().unwrap();
+
+        assert!(s.try_release_many(2).is_ok());
+        assert_eq!(
+            s.try_release_many(1),
+            Err(CapacityExceeded {
+                amount: 1,
+                count: 2,
+                max: 2
+            })
+        );
+        assert_eq!(s.available_permits(), 2);
+
+        let unbounded = Semaphore::new(isize::MAX - 1);
+        assert!(unbounded.try_release_many(1).is_ok());
+    }
+
+    #[test]
+    fn test_sem_release_handoff_no_barging() {
         let s = Arc::new(Semaphore::new(1));
+        let held = s.access();
         let s2 = s.clone();
-        let (tx, rx) = channel();
-        {
-            let _g = s.access();
-            thread::spawn(move || {
-                tx.send(()).unwrap();
-                drop(s2.access());
-                tx.send(()).unwrap();
-            });
-            rx.recv().unwrap(); // wait for child to come alive
+        let waiter = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+
+        // Newcomers that would compete for every release, and do so again
+        // as soon as they're done.
+        let stop = Arc::new(AtomicBool::new(false));
+        let churn: Vec<_> = (0..4)
+            .map(|_| {
+                let (s2, stop) = (s.clone(), stop.clone());
+                thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        drop(s2.access());
+                    }
+                })
+            })
+            .collect();
+        wait_for_waiters(&s, 5);
+
+        held.leak();
+        s.release_handoff();
+        waiter.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+
+        stop.store(true, Ordering::SeqCst);
+        s.release();
+        for t in churn {
+            t.join().unwrap();
         }
-        rx.recv().unwrap(); // wait for child to be done
+    }
+
+    #[test]
+    fn test_sem_release_handoff_passes_on() {
+        let s = Arc::new(Semaphore::new(0));
+        // Without a waiter, the resource goes to the count.
+        s.release_handoff();
+        assert_eq!(s.available_permits(), 1);
+        s.acquire();
+
+        let s2 = s.clone();
+        let timed_out =
+            thread::spawn(move || s2.acquire_many_timeout(2, Duration::from_millis(50)));
+        wait_for_waiters(&s, 1);
+        s.release_handoff();
+        assert_eq!(s.available_permits(), 0);
+        assert!(!timed_out.join().unwrap());
+        // The oldest waiter gave up with nobody behind it.
+        assert_eq!(s.available_permits(), 1);
+        assert!(s.check_invariants().is_ok());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "released 1 resources into a closed semaphore")]
+    fn test_sem_release_handoff_into_closed() {
+        let s = Semaphore::new(0);
+        s.close();
+        s.release_handoff();
+    }
+
+    #[test]
+    fn test_sem_with_count() {
+        let s = Semaphore::new(2);
+        assert!(s.with_count(|mut handle| handle.try_take(2)));
+        assert_eq!(s.available_permits(), 0);
+        assert!(!s.with_count(|mut handle| handle.try_take(1)));
+        assert_eq!(s.with_count(|handle| handle.count()), 0);
+
+        s.release_many(2);
+        s.close();
+        assert!(!s.with_count(|mut handle| handle.try_take(1)));
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "permit dropped without being given back")]
+    fn test_sem_permit_dropped() {
+        let s = Semaphore::new(1);
+        drop(s.take_permit());
+    }
+
+    #[test]
+    fn test_sem_permit_wrong_semaphore() {
+        let (s, other) = (Semaphore::new(1), Semaphore::new(1));
+        let permit = s.take_permit();
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| permit.give_back(&other)));
+        assert!(result.is_err());
+        // The failed give-back released nothing, and unwinding dropped the
+        // permit without a second panic.
+        assert_eq!((s.available_permits(), other.available_permits()), (0, 1));
+    }
+
+    #[test]
+    fn test_sem_capture_restore() {
+        let s = Arc::new(Semaphore::new(1));
+        let checkpoint = s.capture();
+        assert_eq!(checkpoint.count(), 1);
+        s.acquire();
+        let s2 = s.clone();
+        let waiter = thread::spawn(move || s2.acquire());
+        wait_for_waiters(&s, 1);
+        // Rolling back hands the waiter the resource taken since.
+        s.restore(checkpoint);
+        waiter.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+
+        // A guard from before the restore still releases, overshooting.
+        s.restore(checkpoint);
+        let guard = s.access();
+        s.restore(checkpoint);
+        drop(guard);
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "captured count 3 exceeds the current maximum of 2")]
+    fn test_sem_restore_over_max() {
+        let s = Semaphore::bounded(3, 3);
+        let checkpoint = s.capture();
+        s.resize(2);
+        s.restore(checkpoint);
+    }
+
+    #[test]
+    fn test_sem_resize_bumps_generation() {
+        let s = Semaphore::bounded(2, 2);
+        let old = s.access();
+        assert!(old.is_current());
+        s.resize(3);
+        assert_eq!(s.generation(), 1);
+        let new = s.access();
+        assert!(!old.is_current());
+        assert!(new.is_current());
+        assert_eq!((old.generation(), new.generation()), (0, 1));
+        assert_eq!(
+            s.available_permits_atomic_snapshot(),
+            PermitSnapshot {
+                count: 1,
+                generation: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_sem_resize_preserves_outstanding() {
+        let s = Arc::new(Semaphore::bounded(4, 4));
+        let g = s.access_many(3);
+        s.resize(2);
+        assert_eq!(s.max(), Some(2));
+        assert_eq!(s.available_permits(), -1);
+        drop(g);
+        assert_eq!(s.available_permits(), 2);
+
+        // Growing the capacity wakes waiters that now fit.
+        let g = s.access_many(2);
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_many(2));
+        wait_for_waiters(&s, 1);
+        s.resize(4);
+        t.join().unwrap();
+        drop(g);
+        assert_eq!(s.available_permits(), 2);
+        s.release_many(2);
+        assert_eq!(s.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_sem_guard_near_max_drops_cleanly() {
+        let s = Semaphore::new(isize::MAX);
+        let g = s.access_many(isize::MAX);
+        assert_eq!(s.available_permits(), 0);
+        drop(g);
+        assert_eq!(s.available_permits(), isize::MAX);
+
+        // A guard whose release would overflow panics rather than wrapping,
+        // and leaves the count and the lock intact.
+        let s = Arc::new(Semaphore::new(isize::MAX));
+        let s2 = s.clone();
+        let result = thread::spawn(move || {
+            let _g = s2.access();
+            s2.release();
+        })
+        .join();
+        assert!(result.is_err());
+        assert_eq!(s.available_permits(), isize::MAX);
     }
 }
@@ -0,0 +1,118 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use sync::Arc;
+use RawSemaphore;
+
+struct Link {
+    sem: Arc<RawSemaphore>,
+    /// Only set with the semaphore's lock held, so a parked acquirer can't
+    /// miss it between checking and parking.
+    broken: AtomicBool,
+}
+
+/// Breaks its link when dropped, waking any `acquire_linked` call blocked on
+/// the link so that it returns `false`.
+///
+/// Created by `Semaphore::link`.
+pub struct LinkOwner {
+    link: Arc<Link>,
+}
+
+/// A handle to a link that can be passed to `Semaphore::acquire_linked`.
+///
+/// Tokens can be cloned freely; they all break together when the
+/// `LinkOwner` goes away.
+#[derive(Clone)]
+pub struct LinkToken {
+    link: Arc<Link>,
+}
+
+pub fn new(sem: &Arc<RawSemaphore>) -> (LinkOwner, LinkToken) {
+    let link = Arc::new(Link {
+        sem: Arc::clone(sem),
+        broken: AtomicBool::new(false),
+    });
+    (
+        LinkOwner {
+            link: Arc::clone(&link),
+        },
+        LinkToken { link },
+    )
+}
+
+impl LinkOwner {
+    /// Breaks the link now rather than when the owner is dropped.
+    pub fn break_link(self) {}
+}
+
+impl Drop for LinkOwner {
+    fn drop(&mut self) {
+        let state = self.link.sem.lock.lock().unwrap();
+        self.link.broken.store(true, Ordering::Relaxed);
+        // We can't tell which of the parked threads are linked, so wake them
+        // all; the rest go back to sleep.
+        self.link.sem.wake_all(state);
+    }
+}
+
+impl LinkToken {
+    /// Returns whether the link's owner has gone away.
+    pub fn is_broken(&self) -> bool {
+        self.link.broken.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn belongs_to(&self, sem: &Arc<RawSemaphore>) -> bool {
+        Arc::ptr_eq(&self.link.sem, sem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use Semaphore;
+
+    #[test]
+    fn test_link_break_wakes_acquirer() {
+        let s = Arc::new(Semaphore::new(0));
+        let (owner, token) = s.link();
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire_linked(&token));
+        while s.inner.lock.lock().unwrap().waiting.is_empty() {
+            thread::yield_now();
+        }
+        owner.break_link();
+        assert!(!t.join().unwrap());
+        assert_eq!(s.available_permits(), 0);
+        assert!(s.inner.lock.lock().unwrap().waiting.is_empty());
+    }
+
+    #[test]
+    fn test_link_intact() {
+        let s = Semaphore::new(1);
+        let (owner, token) = s.link();
+        assert!(s.acquire_linked(&token));
+        assert_eq!(s.available_permits(), 0);
+        s.release();
+        drop(owner);
+        assert!(token.is_broken());
+        assert!(!s.acquire_linked(&token));
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "other than the one that created it")]
+    fn test_link_wrong_semaphore() {
+        let (_owner, token) = Semaphore::new(1).link();
+        Semaphore::new(1).acquire_linked(&token);
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::hint;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use {check_amount, Acquirable};
+
+/// How many times to spin before falling back to yielding the thread.
+const SPIN_LIMIT: u32 = 64;
+
+/// A counting semaphore backed only by an atomic counter.
+///
+/// Acquiring and releasing never take a lock, which makes this cheaper than
+/// `Semaphore` when there is little or no contention. The price is paid when a
+/// thread does have to wait: it spins, then repeatedly yields, instead of
+/// sleeping until it is notified. Prefer `Semaphore` unless acquisitions are
+/// known to almost always succeed immediately.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::Semaphore;
+///
+/// let sem = Semaphore::new_lockfree(2);
+/// {
+///     let _guard = sem.access_many(2);
+///     assert!(!sem.try_acquire());
+/// }
+/// assert_eq!(sem.available_permits(), 2);
+/// ```
+pub struct LockFreeSemaphore {
+    count: Arc<AtomicIsize>,
+}
+
+/// An RAII guard which will release one or more resources acquired from a
+/// `LockFreeSemaphore` when dropped.
+pub struct LockFreeGuard {
+    count: Arc<AtomicIsize>,
+    amount: isize,
+}
+
+impl LockFreeSemaphore {
+    /// Creates a new lock-free semaphore with the initial count specified.
+    ///
+    /// As with `Semaphore::new`, the count may be negative.
+    pub fn new(count: isize) -> LockFreeSemaphore {
+        LockFreeSemaphore {
+            count: Arc::new(AtomicIsize::new(count)),
+        }
+    }
+
+    /// Acquires a resource, spinning and yielding until it can do so.
+    pub fn acquire(&self) {
+        self.acquire_many(1);
+    }
+
+    /// Acquires one or more resources, spinning and yielding until the count is
+    /// at least `amount`.
+    pub fn acquire_many(&self, amount: isize) {
+        let mut spins = 0;
+        while !self.try_acquire_many(amount) {
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Attempts to acquire a resource without waiting.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_many(1)
+    }
+
+    /// Attempts to acquire one or more resources without waiting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative, like `Semaphore::try_acquire_many`.
+    pub fn try_acquire_many(&self, amount: isize) -> bool {
+        check_amount(amount);
+        if amount == 0 {
+            return true;
+        }
+        let mut current = self.count.load(Ordering::Relaxed);
+        loop {
+            if current < amount {
+                return false;
+            }
+            match self.count.compare_exchange_weak(
+                current,
+                current - amount,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Releases a resource.
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// Releases one or more resources.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative, or if the count would overflow,
+    /// leaving the count unchanged.
+    pub fn release_many(&self, amount: isize) {
+        assert!(
+            amount >= 0,
+            "cannot release a negative number of resources ({})",
+            amount
+        );
+        add(&self.count, amount);
+    }
+
+    /// Returns the current count.
+    pub fn available_permits(&self) -> isize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Acquires a resource, returning an RAII guard to release it when dropped.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access(&self) -> LockFreeGuard {
+        self.access_many(1)
+    }
+
+    /// Acquires one or more resources, returning an RAII guard to release them
+    /// when dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative, so that no guard holds a negative
+    /// amount.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn access_many(&self, amount: isize) -> LockFreeGuard {
+        self.acquire_many(amount);
+        LockFreeGuard {
+            count: Arc::clone(&self.count),
+            amount,
+        }
+    }
+}
+
+impl Acquirable for LockFreeSemaphore {
+    fn acquire(&self) {
+        LockFreeSemaphore::acquire(self);
+    }
+
+    fn try_acquire(&self) -> bool {
+        LockFreeSemaphore::try_acquire(self)
+    }
+
+    fn release(&self) {
+        LockFreeSemaphore::release(self);
+    }
+
+    fn available(&self) -> isize {
+        self.available_permits()
+    }
+}
+
+impl Drop for LockFreeGuard {
+    fn drop(&mut self) {
+        if self.amount == 0 {
+            return;
+        }
+        add(&self.count, self.amount);
+    }
+}
+
+/// Adds `amount` to `count`, panicking instead of wrapping around if that
+/// would overflow.
+fn add(count: &AtomicIsize, amount: isize) {
+    let mut current = count.load(Ordering::Relaxed);
+    loop {
+        let new = match current.checked_add(amount) {
+            Some(new) => new,
+            None => panic!(
+                "releasing {} resources would overflow the semaphore count",
+                amount
+            ),
+        };
+        match count.compare_exchange_weak(current, new, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LockFreeSemaphore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_lockfree_acquire_release() {
+        let s = LockFreeSemaphore::new(3);
+        assert!(s.try_acquire_many(2));
+        assert!(!s.try_acquire_many(2));
+        s.release_many(2);
+        {
+            let _g = s.access_many(3);
+            assert_eq!(s.available_permits(), 0);
+            assert!(!s.try_acquire());
+        }
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot acquire a negative number of resources (-2)")]
+    fn test_lockfree_negative_acquire() {
+        let s = LockFreeSemaphore::new(1);
+        let _g = s.access_many(-2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot release a negative number of resources (-1)")]
+    fn test_lockfree_negative_release() {
+        LockFreeSemaphore::new(1).release_many(-1);
+    }
+
+    #[test]
+    fn test_lockfree_release_overflow() {
+        let s = LockFreeSemaphore::new(isize::MAX);
+        let guard = s.access();
+        s.release();
+        let released = std::panic::catch_unwind(|| s.release());
+        assert!(released.is_err());
+        assert_eq!(s.available_permits(), isize::MAX);
+        // A guard's release is checked the same way.
+        assert!(std::panic::catch_unwind(move || drop(guard)).is_err());
+        assert_eq!(s.available_permits(), isize::MAX);
+    }
+
+    #[test]
+    fn test_lockfree_limits_concurrency() {
+        let s = Arc::new(LockFreeSemaphore::new(2));
+        let inside = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let s = s.clone();
+                let inside = inside.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let _g = s.access();
+                        assert!(inside.fetch_add(1, Ordering::SeqCst) < 2);
+                        inside.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 2);
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Runs a block while holding `amount` resources of a semaphore, releasing
+/// them when the block ends, and evaluates to the block's value.
+///
+/// This is `Semaphore::access_many` with the guard kept out of reach, so it
+/// can't be dropped early by mistake, and the region that holds the
+/// resources is plain to see. The semaphore can be given as a `Semaphore` or
+/// anything that dereferences to one.
+///
+/// ```
+/// #[macro_use]
+/// extern crate multi_semaphore;
+/// use multi_semaphore::Semaphore;
+///
+/// # fn main() {
+/// let sem = Semaphore::new(4);
+/// let left = with_permits!(sem, 3, { sem.available_permits() });
+/// assert_eq!(left, 1);
+/// assert_eq!(sem.available_permits(), 4);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! with_permits {
+    ($sem:expr, $amount:expr, $body:block) => {{
+        let _permits = $crate::Semaphore::access_many(&$sem, $amount);
+        $body
+    }};
+}
@@ -0,0 +1,43 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Cheap counters describing how acquisitions are being satisfied.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Counters {
+    fast_path_hits: AtomicUsize,
+    slow_path_falls: AtomicUsize,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            fast_path_hits: AtomicUsize::new(0),
+            slow_path_falls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts an acquisition that found enough resources without parking.
+    pub fn hit(&self) {
+        self.fast_path_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an acquisition that had to park waiting for resources.
+    pub fn fall(&self) {
+        self.slow_path_falls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fast_path_hits(&self) -> usize {
+        self.fast_path_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_path_falls(&self) -> usize {
+        self.slow_path_falls.load(Ordering::Relaxed)
+    }
+}
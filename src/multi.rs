@@ -0,0 +1,197 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use sync::{Arc, Condvar, Mutex};
+
+/// A semaphore with `N` independent counts, for resources with several axes
+/// that are held together, such as threads, memory and IO slots.
+///
+/// Acquisitions take an amount of every dimension at once, under one lock, so
+/// a thread never holds some dimensions while blocking on another.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::MultiSemaphore;
+///
+/// // 4 threads and 1024 MiB of memory.
+/// let sem = MultiSemaphore::new([4, 1024]);
+/// let mut guard = sem.access([1, 256]);
+/// // Done with most of the memory early.
+/// guard.release_dimension(1, 200);
+/// assert_eq!(sem.available(), [3, 968]);
+/// ```
+pub struct MultiSemaphore<const N: usize> {
+    inner: Arc<Inner<N>>,
+}
+
+struct Inner<const N: usize> {
+    counts: Mutex<[isize; N]>,
+    cvar: Condvar,
+}
+
+/// An RAII guard for amounts of every dimension of a `MultiSemaphore`, which
+/// releases whatever it still holds when dropped.
+#[must_use = "the resources are released immediately if the guard is not bound to a variable"]
+pub struct MultiGuard<const N: usize> {
+    inner: Arc<Inner<N>>,
+    amounts: [isize; N],
+}
+
+impl<const N: usize> MultiSemaphore<N> {
+    /// Creates a semaphore with the given initial count for each dimension.
+    pub fn new(counts: [isize; N]) -> MultiSemaphore<N> {
+        MultiSemaphore {
+            inner: Arc::new(Inner {
+                counts: Mutex::new(counts),
+                cvar: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns the current count of every dimension.
+    pub fn available(&self) -> [isize; N] {
+        *self.inner.counts.lock().unwrap()
+    }
+
+    /// Acquires `amounts[i]` of every dimension `i`, blocking the current
+    /// thread until all of them are available at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any amount is negative.
+    pub fn access(&self, amounts: [isize; N]) -> MultiGuard<N> {
+        check_amounts(&amounts);
+        let mut counts = self.inner.counts.lock().unwrap();
+        while !covers(&*counts, &amounts) {
+            counts = self.inner.cvar.wait(counts).unwrap();
+        }
+        self.take(&mut counts, amounts)
+    }
+
+    /// Acquires `amounts[i]` of every dimension `i` if they are all available,
+    /// without blocking.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any amount is negative.
+    pub fn try_access(&self, amounts: [isize; N]) -> Option<MultiGuard<N>> {
+        check_amounts(&amounts);
+        let mut counts = self.inner.counts.lock().unwrap();
+        if !covers(&*counts, &amounts) {
+            return None;
+        }
+        Some(self.take(&mut counts, amounts))
+    }
+
+    fn take(&self, counts: &mut [isize; N], amounts: [isize; N]) -> MultiGuard<N> {
+        for (count, amount) in counts.iter_mut().zip(&amounts) {
+            *count -= amount;
+        }
+        MultiGuard {
+            inner: Arc::clone(&self.inner),
+            amounts,
+        }
+    }
+}
+
+fn check_amounts(amounts: &[isize]) {
+    if let Some(amount) = amounts.iter().find(|&&amount| amount < 0) {
+        panic!("cannot acquire a negative number of resources ({})", amount);
+    }
+}
+
+fn covers(counts: &[isize], amounts: &[isize]) -> bool {
+    counts
+        .iter()
+        .zip(amounts)
+        .all(|(count, amount)| count >= amount)
+}
+
+impl<const N: usize> Inner<N> {
+    fn release(&self, amounts: &[isize; N]) {
+        let mut counts = self.counts.lock().unwrap();
+        for (count, amount) in counts.iter_mut().zip(amounts) {
+            *count += amount;
+        }
+        drop(counts);
+        self.cvar.notify_all();
+    }
+}
+
+impl<const N: usize> MultiGuard<N> {
+    /// Returns the amount of every dimension this guard still holds.
+    pub fn amounts(&self) -> [isize; N] {
+        self.amounts
+    }
+
+    /// Releases `n` of dimension `dim` early, keeping the rest of the guard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dim` is out of range, or if `n` is negative or more than
+    /// the guard holds of that dimension.
+    pub fn release_dimension(&mut self, dim: usize, n: isize) {
+        assert!(
+            dim < N,
+            "dimension {} out of range for {} dimensions",
+            dim,
+            N
+        );
+        assert!(
+            0 <= n && n <= self.amounts[dim],
+            "cannot release {} of dimension {} from a guard holding {}",
+            n,
+            dim,
+            self.amounts[dim]
+        );
+        let mut released = [0; N];
+        released[dim] = n;
+        self.amounts[dim] -= n;
+        self.inner.release(&released);
+    }
+}
+
+impl<const N: usize> Drop for MultiGuard<N> {
+    fn drop(&mut self) {
+        self.inner.release(&self.amounts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiSemaphore;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_multi_release_dimension_early() {
+        let sem = Arc::new(MultiSemaphore::new([2, 10]));
+        let mut guard = sem.access([1, 10]);
+        assert!(sem.try_access([1, 1]).is_none());
+
+        // A waiter needing both dimensions can proceed once the memory comes
+        // back, while the first guard still holds its thread.
+        let sem2 = sem.clone();
+        let t = thread::spawn(move || sem2.access([1, 4]).amounts());
+        guard.release_dimension(1, 6);
+        assert_eq!(t.join().unwrap(), [1, 4]);
+        assert_eq!(guard.amounts(), [1, 4]);
+        assert_eq!(sem.available(), [1, 6]);
+        drop(guard);
+        assert_eq!(sem.available(), [2, 10]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot release 3 of dimension 0")]
+    fn test_multi_release_more_than_held() {
+        let sem = MultiSemaphore::new([2]);
+        let mut guard = sem.access([2]);
+        guard.release_dimension(0, 3);
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::hint;
+use std::sync::{LockResult, PoisonError};
+use std::thread::{self, Thread};
+use std::time::Duration;
+#[cfg(feature = "parking_lot")]
+use std::time::Instant;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot_core::{self, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+
+use sync::{Condvar, Mutex, MutexGuard};
+use SemaphoreState;
+
+/// A strategy for blocking threads that wait on a semaphore, and for waking
+/// them when resources are released.
+///
+/// A semaphore calls `park` with its lock held whenever a thread has to wait,
+/// and `unpark_some` or `unpark_all` (without the lock held) after a release
+/// that may let a waiter proceed. Callers always re-check their condition
+/// after `park` returns, so spurious wakeups are harmless, but a parked thread
+/// must not miss an `unpark_all` that happens after `park` was called, nor may
+/// `unpark_some` leave fewer threads woken than asked while more are parked.
+///
+/// The default is `CondvarParker`. Use `Semaphore::with_parker` to choose
+/// another.
+pub trait Parker: Send + Sync {
+    /// Releases `guard`, blocks the current thread until it is unparked or
+    /// `timeout` elapses, then reacquires `lock`.
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>>;
+
+    /// Wakes every thread currently parked in `park`.
+    fn unpark_all(&self);
+
+    /// Wakes at least `n` of the threads currently parked in `park`, or all of
+    /// them if fewer are parked.
+    ///
+    /// The semaphore uses this when a release can only satisfy some of the
+    /// waiters, so the rest don't all contend for its lock just to park again.
+    /// The default wakes everyone, which is always correct.
+    fn unpark_some(&self, n: usize) {
+        let _ = n;
+        self.unpark_all();
+    }
+}
+
+/// The ways of waiting that can be picked at runtime with
+/// `Semaphore::with_backend`, for example from configuration.
+///
+/// Each backend is one of the provided `Parker`s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+    /// Park on a standard library condition variable, with `CondvarParker`.
+    /// This is what `Semaphore::new` uses.
+    Std,
+    /// Park with `parking_lot`'s thread parking, with `ParkingLotParker`.
+    #[cfg(feature = "parking_lot")]
+    ParkingLot,
+    /// Busy-wait, with `SpinParker`.
+    Spin,
+}
+
+impl Backend {
+    pub(crate) fn parker(self) -> Box<dyn Parker> {
+        match self {
+            Backend::Std => Box::new(CondvarParker::new()),
+            #[cfg(feature = "parking_lot")]
+            Backend::ParkingLot => Box::new(ParkingLotParker::new()),
+            Backend::Spin => Box::new(SpinParker::new()),
+        }
+    }
+}
+
+/// Parks threads on a condition variable. This is the default `Parker`.
+pub struct CondvarParker {
+    cvar: Condvar,
+}
+
+impl CondvarParker {
+    pub fn new() -> CondvarParker {
+        CondvarParker {
+            cvar: Condvar::new(),
+        }
+    }
+}
+
+impl Default for CondvarParker {
+    fn default() -> CondvarParker {
+        CondvarParker::new()
+    }
+}
+
+impl Parker for CondvarParker {
+    fn park<'a>(
+        &self,
+        _lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        match timeout {
+            None => self.cvar.wait(guard),
+            Some(timeout) => match self.cvar.wait_timeout(guard, timeout) {
+                Ok((guard, _)) => Ok(guard),
+                Err(err) => Err(PoisonError::new(err.into_inner().0)),
+            },
+        }
+    }
+
+    fn unpark_all(&self) {
+        self.cvar.notify_all();
+    }
+
+    fn unpark_some(&self, n: usize) {
+        for _ in 0..n {
+            self.cvar.notify_one();
+        }
+    }
+}
+
+/// Parks threads with `std::thread::park`, waking them with `Thread::unpark`.
+///
+/// This is useful when waiting threads also need to be woken by other
+/// machinery that uses `unpark`. Note that a parked thread may be woken early
+/// by any `unpark` call, and that a thread woken by this parker after it
+/// stopped waiting keeps the unpark token, so its next `thread::park` may
+/// return immediately; both are permitted by `thread::park`'s contract.
+pub struct ThreadParker {
+    parked: Mutex<Vec<Thread>>,
+}
+
+impl ThreadParker {
+    pub fn new() -> ThreadParker {
+        ThreadParker {
+            parked: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Default for ThreadParker {
+    fn default() -> ThreadParker {
+        ThreadParker::new()
+    }
+}
+
+impl Parker for ThreadParker {
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        // Register before giving up the semaphore lock: a release can only
+        // happen after that, and will find us in the list. If it unparks us
+        // before we manage to park, `park` returns immediately.
+        let me = thread::current();
+        self.parked.lock().unwrap().push(me.clone());
+        drop(guard);
+        match timeout {
+            None => thread::park(),
+            Some(timeout) => thread::park_timeout(timeout),
+        }
+        self.parked.lock().unwrap().retain(|t| t.id() != me.id());
+        lock.lock()
+    }
+
+    fn unpark_all(&self) {
+        let parked: Vec<Thread> = self.parked.lock().unwrap().drain(..).collect();
+        for thread in parked {
+            thread.unpark();
+        }
+    }
+
+    fn unpark_some(&self, n: usize) {
+        let picked: Vec<Thread> = {
+            let mut parked = self.parked.lock().unwrap();
+            let n = cmp::min(n, parked.len());
+            parked.drain(..n).collect()
+        };
+        for thread in picked {
+            thread.unpark();
+        }
+    }
+}
+
+/// How many times `SpinParker` spins before yielding its time slice.
+const SPIN_LIMIT: u32 = 64;
+
+/// Never blocks: a "parked" thread releases the lock, spins briefly, yields,
+/// and then returns to check again.
+///
+/// This trades CPU time for wakeup latency, and only makes sense when waits
+/// are expected to be very short and there are spare cores to spin on.
+pub struct SpinParker {
+    _private: (),
+}
+
+impl SpinParker {
+    pub fn new() -> SpinParker {
+        SpinParker { _private: () }
+    }
+}
+
+impl Default for SpinParker {
+    fn default() -> SpinParker {
+        SpinParker::new()
+    }
+}
+
+impl Parker for SpinParker {
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        _timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        // Callers check their deadline every time we return, so there's no
+        // need to look at the timeout.
+        drop(guard);
+        for _ in 0..SPIN_LIMIT {
+            hint::spin_loop();
+        }
+        thread::yield_now();
+        lock.lock()
+    }
+
+    fn unpark_all(&self) {}
+}
+
+/// Parks threads in `parking_lot`'s global parking lot, keyed by the address
+/// of this parker.
+///
+/// Only available with the `parking_lot` feature.
+#[cfg(feature = "parking_lot")]
+pub struct ParkingLotParker {
+    /// Gives each parker a distinct address to use as its key.
+    key: u8,
+}
+
+#[cfg(feature = "parking_lot")]
+impl ParkingLotParker {
+    pub fn new() -> ParkingLotParker {
+        ParkingLotParker { key: 0 }
+    }
+
+    fn key(&self) -> usize {
+        &self.key as *const u8 as usize
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl Default for ParkingLotParker {
+    fn default() -> ParkingLotParker {
+        ParkingLotParker::new()
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl Parker for ParkingLotParker {
+    fn park<'a>(
+        &self,
+        lock: &'a Mutex<SemaphoreState>,
+        guard: MutexGuard<'a, SemaphoreState>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, SemaphoreState>> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        // SAFETY: none of the callbacks panic or touch the parking lot.
+        // Releasing the semaphore lock only once we're queued means a release,
+        // which has to take that lock first, can't unpark the key before we
+        // are there to be woken.
+        unsafe {
+            parking_lot_core::park(
+                self.key(),
+                || true,
+                move || drop(guard),
+                |_, _| {},
+                DEFAULT_PARK_TOKEN,
+                deadline,
+            );
+        }
+        lock.lock()
+    }
+
+    fn unpark_all(&self) {
+        // SAFETY: `unpark_all` has no preconditions beyond those of `park`.
+        unsafe {
+            parking_lot_core::unpark_all(self.key(), DEFAULT_UNPARK_TOKEN);
+        }
+    }
+
+    fn unpark_some(&self, n: usize) {
+        for _ in 0..n {
+            // SAFETY: as for `unpark_all`; the callback doesn't panic or touch
+            // the parking lot.
+            let result =
+                unsafe { parking_lot_core::unpark_one(self.key(), |_| DEFAULT_UNPARK_TOKEN) };
+            if result.unparked_threads == 0 {
+                break;
+            }
+        }
+    }
+}
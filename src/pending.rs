@@ -0,0 +1,158 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use sync::Arc;
+use {RawSemaphore, SemaphoreGuard};
+
+/// A place in line for a resource of a semaphore, to be claimed later.
+///
+/// Created by `Semaphore::enqueue`. In a fair semaphore the reservation joins
+/// the queue straight away, and is granted in turn even while its owner is
+/// busy elsewhere, so it doesn't hold up the acquirers behind it. `claim`
+/// then blocks only until the grant has happened.
+///
+/// Dropping a pending permit cancels the reservation, giving up its place in
+/// the queue or releasing the resource if it was already granted.
+pub struct PendingPermit {
+    sem: Arc<RawSemaphore>,
+    state: Pending,
+}
+
+enum Pending {
+    /// The resource has been taken on our behalf.
+    Granted,
+    /// We're in a fair semaphore's queue with this ticket.
+    Queued(u64),
+    /// The semaphore isn't fair, so there is no line to wait in.
+    Unqueued,
+    /// Claimed or cancelled.
+    Done,
+}
+
+impl PendingPermit {
+    pub(crate) fn new(sem: &Arc<RawSemaphore>) -> PendingPermit {
+        let mut state = sem.lock.lock().unwrap();
+        let pending = if state.may_take(1, None) {
+            state.count -= 1;
+            Pending::Granted
+        } else {
+            match state.fair {
+                Some(ref mut queue) => Pending::Queued(queue.push_reservation(1)),
+                None => Pending::Unqueued,
+            }
+        };
+        sem.unlock(state);
+        PendingPermit {
+            sem: Arc::clone(sem),
+            state: pending,
+        }
+    }
+
+    /// Returns whether the resource has already been granted, so `claim`
+    /// would return immediately.
+    pub fn is_granted(&self) -> bool {
+        match self.state {
+            Pending::Granted => true,
+            Pending::Queued(ticket) => {
+                let state = self.sem.lock.lock().unwrap();
+                state.fair.as_ref().unwrap().is_granted(ticket)
+            }
+            Pending::Unqueued | Pending::Done => false,
+        }
+    }
+
+    /// Turns the reservation into a guard for its resource, blocking the
+    /// current thread until it has been granted.
+    ///
+    /// In a semaphore that isn't fair, a reservation that couldn't be granted
+    /// straight away has no place in line, and this acquires the resource
+    /// like `Semaphore::access`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn claim(mut self) -> SemaphoreGuard {
+        match self.state {
+            Pending::Granted => {}
+            Pending::Queued(ticket) => {
+                let state = self.sem.lock.lock().unwrap();
+                let (mut state, _) = self.sem.wait_until(state, None, |state| {
+                    state.fair.as_ref().unwrap().is_granted(ticket)
+                });
+                state.fair.as_mut().unwrap().take_grant(ticket);
+            }
+            Pending::Unqueued => self.sem.acquire_many(1),
+            Pending::Done => unreachable!(),
+        }
+        self.state = Pending::Done;
+        SemaphoreGuard::new(&self.sem, 1)
+    }
+}
+
+impl Drop for PendingPermit {
+    fn drop(&mut self) {
+        match self.state {
+            Pending::Granted => self.sem.release_many(1),
+            Pending::Queued(ticket) => {
+                let mut state = self.sem.lock.lock().unwrap();
+                let queue = state.fair.as_mut().unwrap();
+                if queue.take_grant(ticket) {
+                    state.count += 1;
+                } else {
+                    queue.remove(ticket, false);
+                }
+                // Either way, whoever is next in line may now proceed.
+                self.sem.unlock(state);
+            }
+            Pending::Unqueued | Pending::Done => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use Semaphore;
+
+    #[test]
+    fn test_pending_granted_in_turn() {
+        let s = Semaphore::new_fair(0);
+        let early = s.enqueue();
+        let late = s.enqueue();
+        assert!(!early.is_granted());
+
+        s.release();
+        assert!(early.is_granted() && !late.is_granted());
+        assert_eq!(s.available_permits(), 0);
+        let guard = early.claim();
+
+        let t = thread::spawn(move || drop(late.claim()));
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 1);
+        assert!(s.enqueue().is_granted());
+    }
+
+    #[test]
+    fn test_pending_cancelled() {
+        let s = Semaphore::new_fair(0);
+        let cancelled = s.enqueue();
+        let next = s.enqueue();
+        drop(cancelled);
+        s.release();
+        assert!(next.is_granted());
+        drop(next);
+        assert_eq!(s.available_permits(), 1);
+        assert!(s.check_invariants().is_ok());
+
+        // Not fair: no place in line, so claiming just acquires.
+        let s = Semaphore::new(1);
+        let pending = s.enqueue();
+        assert!(pending.is_granted());
+        drop(pending.claim());
+        assert_eq!(s.available_permits(), 1);
+    }
+}
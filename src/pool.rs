@@ -0,0 +1,163 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use {Semaphore, SemaphoreGuard};
+
+/// A pool of reusable objects, with a semaphore gating access to them.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::SemaphorePool;
+///
+/// let pool = SemaphorePool::new(vec![Vec::<u8>::new(), Vec::new()]);
+/// {
+///     let mut buf = pool.checkout();
+///     buf.push(1);
+///     // ...
+/// } // the buffer goes back into the pool here
+/// ```
+pub struct SemaphorePool<T> {
+    sem: Semaphore,
+    objects: Mutex<Vec<T>>,
+}
+
+/// An object checked out of a `SemaphorePool`, which is returned to the pool
+/// when dropped.
+pub struct PooledObject<'a, T: 'a> {
+    pool: &'a SemaphorePool<T>,
+    object: Option<T>,
+    _guard: SemaphoreGuard,
+}
+
+impl<T> SemaphorePool<T> {
+    /// Creates a pool that hands out the given objects.
+    pub fn new(objects: Vec<T>) -> SemaphorePool<T> {
+        let len = objects.len() as isize;
+        SemaphorePool {
+            sem: Semaphore::bounded(len, len),
+            objects: Mutex::new(objects),
+        }
+    }
+
+    /// Returns the number of objects currently in the pool.
+    pub fn available(&self) -> isize {
+        self.sem.available_permits()
+    }
+
+    /// Takes an object out of the pool, blocking the current thread until one
+    /// is available.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool was created empty, since no object would ever
+    /// become available.
+    #[must_use = "the object is returned immediately if it is not bound to a variable"]
+    pub fn checkout(&self) -> PooledObject<'_, T> {
+        let guard = self.sem.access();
+        self.take(guard)
+    }
+
+    /// Takes an object out of the pool if one is available, without blocking.
+    #[must_use = "the object is returned immediately if it is not bound to a variable"]
+    pub fn try_checkout(&self) -> Option<PooledObject<'_, T>> {
+        self.sem.try_access().map(|guard| self.take(guard))
+    }
+
+    /// Takes an object out of the pool, blocking for at most `timeout`.
+    ///
+    /// Returns `None` if no object became available in time.
+    #[must_use = "the object is returned immediately if it is not bound to a variable"]
+    pub fn checkout_timeout(&self, timeout: Duration) -> Option<PooledObject<'_, T>> {
+        self.sem
+            .access_timeout(timeout)
+            .map(|guard| self.take(guard))
+    }
+
+    fn take(&self, guard: SemaphoreGuard) -> PooledObject<'_, T> {
+        // Holding a permit guarantees there is an object to pop.
+        let object = self.objects.lock().unwrap().pop();
+        PooledObject {
+            pool: self,
+            object: Some(object.expect("pool permit held with no object left")),
+            _guard: guard,
+        }
+    }
+}
+
+impl<'a, T> Drop for PooledObject<'a, T> {
+    fn drop(&mut self) {
+        // Put the object back before `_guard` releases its permit, so whoever
+        // is woken by the release finds it.
+        if let Some(object) = self.object.take() {
+            self.pool.objects.lock().unwrap().push(object);
+        }
+    }
+}
+
+impl<'a, T> Deref for PooledObject<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.object.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for PooledObject<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.object.as_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SemaphorePool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool_checkout_returns_objects() {
+        let pool = SemaphorePool::new(vec![1, 2]);
+        {
+            let mut a = pool.checkout();
+            let b = pool.try_checkout().unwrap();
+            assert!(pool.try_checkout().is_none());
+            assert!(pool.checkout_timeout(Duration::from_millis(10)).is_none());
+            *a += 10;
+            assert_eq!(*a + *b, 13);
+        }
+        assert_eq!(pool.available(), 2);
+        let mut seen = vec![*pool.checkout(), *pool.checkout()];
+        seen.sort();
+        assert_eq!(seen, vec![1, 12]);
+    }
+
+    #[test]
+    fn test_pool_shared_between_threads() {
+        let pool = Arc::new(SemaphorePool::new(vec![0]));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        *pool.checkout() += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*pool.checkout(), 400);
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use sync::{Condvar, Mutex};
+
+/// A semaphore that serves waiting acquirers by priority, highest first.
+///
+/// Each acquisition names its priority. When a resource is free, the waiter
+/// with the highest priority gets it, and among equals the one that has
+/// waited longest. Acquirers only take a resource past the waiters when none
+/// of them outranks them.
+///
+/// Strict priorities starve low-priority waiters under sustained
+/// high-priority load. Created with `with_aging`, a waiter's priority rises
+/// the longer it waits, so every waiter is served eventually.
+///
+/// A low-priority holder can keep a high-priority waiter waiting, a priority
+/// inversion that the semaphore can't undo for arbitrary code. What it can do
+/// is make sure the high-priority waiter is next: once a waiter has started
+/// waiting while a guard was held at a lower priority, waiters are served by
+/// the priority they asked with, and what they gained by aging is put aside,
+/// until one at that waiter's priority has been served.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::PrioritySemaphore;
+///
+/// let sem = PrioritySemaphore::new(1);
+/// let guard = sem.acquire(0);
+/// assert!(sem.try_acquire(10).is_none());
+/// drop(guard);
+/// assert!(sem.try_acquire(10).is_some());
+/// ```
+pub struct PrioritySemaphore {
+    state: Mutex<Queue>,
+    cvar: Condvar,
+    /// How long a waiter waits to rise by one priority, if waiters age.
+    aging: Option<Duration>,
+}
+
+struct Queue {
+    count: isize,
+    next_ticket: u64,
+    waiting: Vec<Waiter>,
+    /// The number of guards held at each priority.
+    held: BTreeMap<u32, usize>,
+    /// The highest priority of a waiter that started waiting behind a
+    /// lower-priority holder and hasn't been served.
+    boost: Option<u32>,
+}
+
+struct Waiter {
+    ticket: u64,
+    priority: u32,
+    since: Instant,
+}
+
+/// An RAII guard for a resource of a `PrioritySemaphore`, which releases it
+/// when dropped.
+#[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+pub struct PriorityGuard<'a> {
+    sem: &'a PrioritySemaphore,
+    priority: u32,
+}
+
+impl PrioritySemaphore {
+    /// Creates a semaphore with `count` resources, which serves waiters by
+    /// the priority they asked with, however long they have waited.
+    pub fn new(count: isize) -> PrioritySemaphore {
+        PrioritySemaphore {
+            state: Mutex::new(Queue {
+                count,
+                next_ticket: 0,
+                waiting: Vec::new(),
+                held: BTreeMap::new(),
+                boost: None,
+            }),
+            cvar: Condvar::new(),
+            aging: None,
+        }
+    }
+
+    /// Creates a semaphore with `count` resources whose waiters rise by one
+    /// priority for every `step` they have waited.
+    ///
+    /// A waiter that has waited `n` steps outranks every newcomer with a
+    /// priority up to `n` above its own. The shorter the step, the sooner
+    /// low priorities are served and the less priorities matter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `step` is zero.
+    pub fn with_aging(count: isize, step: Duration) -> PrioritySemaphore {
+        assert!(
+            step > Duration::from_secs(0),
+            "the aging step must not be zero"
+        );
+        PrioritySemaphore {
+            aging: Some(step),
+            ..PrioritySemaphore::new(count)
+        }
+    }
+
+    /// Returns the number of resources currently available.
+    pub fn available_permits(&self) -> isize {
+        self.state.lock().unwrap().count
+    }
+
+    /// Acquires a resource at `priority`, blocking the current thread until
+    /// one is available and no waiter outranks it.
+    pub fn acquire(&self, priority: u32) -> PriorityGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push(Waiter {
+            ticket,
+            priority,
+            since: Instant::now(),
+        });
+        if let Some(&lowest) = state.held.keys().next() {
+            if lowest < priority && state.boost < Some(priority) {
+                state.boost = Some(priority);
+            }
+        }
+        while state.count <= 0 || self.first(&state, Instant::now()) != Some(ticket) {
+            state = match self.aging {
+                // Wake up to let our rising priority count even if nobody
+                // releases anything.
+                Some(step) => self.cvar.wait_timeout(state, step).unwrap().0,
+                None => self.cvar.wait(state).unwrap(),
+            };
+        }
+        state.waiting.retain(|w| w.ticket != ticket);
+        state.take(priority);
+        let more = state.count > 0 && !state.waiting.is_empty();
+        drop(state);
+        if more {
+            self.cvar.notify_all();
+        }
+        PriorityGuard {
+            sem: self,
+            priority,
+        }
+    }
+
+    /// Acquires a resource at `priority` if one is available and no waiter
+    /// outranks it, without blocking.
+    pub fn try_acquire(&self, priority: u32) -> Option<PriorityGuard<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.count <= 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let outranked = state
+            .waiting
+            .iter()
+            .any(|w| self.effective(w, now) >= u64::from(priority));
+        if outranked {
+            return None;
+        }
+        state.take(priority);
+        Some(PriorityGuard {
+            sem: self,
+            priority,
+        })
+    }
+
+    fn release(&self, priority: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        if let Some(held) = state.held.get_mut(&priority) {
+            *held -= 1;
+            if *held == 0 {
+                state.held.remove(&priority);
+            }
+        }
+        drop(state);
+        // Whoever is first has to see the change; the rest go back to sleep.
+        self.cvar.notify_all();
+    }
+
+    /// The priority `waiter` has at `now`, counting what it gained by aging.
+    fn effective(&self, waiter: &Waiter, now: Instant) -> u64 {
+        let aged = match self.aging {
+            Some(step) => {
+                let waited = now.saturating_duration_since(waiter.since);
+                (waited.as_nanos() / step.as_nanos()) as u64
+            }
+            None => 0,
+        };
+        u64::from(waiter.priority).saturating_add(aged)
+    }
+
+    /// The ticket of the waiter to serve next: the highest priority, and the
+    /// oldest among equals. Aging doesn't count while a waiter held up by a
+    /// lower-priority holder is still waiting.
+    fn first(&self, state: &Queue, now: Instant) -> Option<u64> {
+        let inverted = state.boost.is_some();
+        state
+            .waiting
+            .iter()
+            .max_by_key(|w| {
+                let priority = if inverted {
+                    u64::from(w.priority)
+                } else {
+                    self.effective(w, now)
+                };
+                (priority, Reverse(w.ticket))
+            })
+            .map(|w| w.ticket)
+    }
+}
+
+impl Queue {
+    /// Takes a resource for a guard held at `priority`.
+    fn take(&mut self, priority: u32) {
+        self.count -= 1;
+        if self.boost <= Some(priority) {
+            self.boost = None;
+        }
+        *self.held.entry(priority).or_insert(0) += 1;
+    }
+}
+
+impl<'a> Drop for PriorityGuard<'a> {
+    fn drop(&mut self) {
+        self.sem.release(self.priority);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrioritySemaphore;
+    use std::mem;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_priority_order() {
+        let sem = Arc::new(PrioritySemaphore::new(0));
+        let (tx, rx) = channel();
+        let threads: Vec<_> = [1, 3, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, &priority)| {
+                let (sem2, tx) = (sem.clone(), tx.clone());
+                let t = thread::spawn(move || {
+                    // Keep the resource, so each release serves one waiter.
+                    mem::forget(sem2.acquire(priority));
+                    tx.send(priority).unwrap();
+                });
+                while sem.state.lock().unwrap().waiting.len() <= i {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+        for &expected in &[3, 2, 1] {
+            sem.state.lock().unwrap().count += 1;
+            sem.cvar.notify_all();
+            assert_eq!(rx.recv().unwrap(), expected);
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(sem.try_acquire(0).is_none());
+    }
+
+    #[test]
+    fn test_priority_aging_prevents_starvation() {
+        let sem = Arc::new(PrioritySemaphore::with_aging(1, Duration::from_millis(1)));
+        let stop = Arc::new(AtomicBool::new(false));
+        // Keep high-priority acquirers waiting all the time.
+        let churn: Vec<_> = (0..3)
+            .map(|_| {
+                let (sem, stop) = (sem.clone(), stop.clone());
+                thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        let _guard = sem.acquire(10);
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(10));
+
+        drop(sem.acquire(0));
+        stop.store(true, Ordering::SeqCst);
+        for t in churn {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_priority_boost_over_aging() {
+        let sem = Arc::new(PrioritySemaphore::with_aging(1, Duration::from_millis(1)));
+        let holder = sem.acquire(0);
+        let (tx, rx) = channel();
+        let spawn = |priority| {
+            let (sem, tx) = (sem.clone(), tx.clone());
+            thread::spawn(move || {
+                let _guard = sem.acquire(priority);
+                tx.send(priority).unwrap();
+            })
+        };
+        let low: Vec<_> = (0..2).map(|_| spawn(1)).collect();
+        // Long enough for the low-priority waiters to age past the newcomer.
+        thread::sleep(Duration::from_millis(50));
+        let high = spawn(10);
+        while sem.state.lock().unwrap().waiting.len() < 3 {
+            thread::yield_now();
+        }
+
+        // The holder's priority is below the newcomer's, so it goes first.
+        drop(holder);
+        assert_eq!(rx.recv().unwrap(), 10);
+        for t in low.into_iter().chain(Some(high)) {
+            t.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use sync::{Condvar, Mutex};
+
+/// A semaphore whose resources belong to numbered rounds, for phased
+/// algorithms that must not let one round's releases leak into the next.
+///
+/// Every round starts with the semaphore's initial count. Acquisitions and
+/// releases name the round they're for: a release for a round that has
+/// already ended is ignored, an acquisition for a round that has ended fails,
+/// and an acquisition for a later round waits until that round begins.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::RoundSemaphore;
+///
+/// let sem = RoundSemaphore::new(1);
+/// assert!(sem.acquire(0));
+/// let round = sem.next_round();
+/// // Too late to give anything back to round 0, and round 1 is full anyway.
+/// assert!(!sem.release(0));
+/// assert!(sem.acquire(round));
+/// ```
+pub struct RoundSemaphore {
+    initial: isize,
+    state: Mutex<Round>,
+    cvar: Condvar,
+}
+
+struct Round {
+    round: u64,
+    count: isize,
+}
+
+impl RoundSemaphore {
+    /// Creates a semaphore in round 0, with `count` resources in every round.
+    pub fn new(count: isize) -> RoundSemaphore {
+        RoundSemaphore {
+            initial: count,
+            state: Mutex::new(Round { round: 0, count }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Returns the current round.
+    pub fn round(&self) -> u64 {
+        self.state.lock().unwrap().round
+    }
+
+    /// Returns the number of resources left in the current round.
+    pub fn available_permits(&self) -> isize {
+        self.state.lock().unwrap().count
+    }
+
+    /// Ends the current round and starts the next with the initial count,
+    /// returning its number.
+    ///
+    /// Threads blocked acquiring for the round that ended give up, and those
+    /// waiting for the new round may proceed.
+    pub fn next_round(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.round += 1;
+        state.count = self.initial;
+        let round = state.round;
+        drop(state);
+        self.cvar.notify_all();
+        round
+    }
+
+    /// Acquires a resource in `round`, blocking the current thread until one
+    /// is available in it.
+    ///
+    /// Waits for `round` to begin if it is still to come. Returns `false`
+    /// without acquiring anything if `round` has ended, or ends while waiting.
+    pub fn acquire(&self, round: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.round > round {
+                return false;
+            }
+            if state.round == round && state.count > 0 {
+                state.count -= 1;
+                return true;
+            }
+            state = self.cvar.wait(state).unwrap();
+        }
+    }
+
+    /// Releases a resource in `round`.
+    ///
+    /// Returns `false`, without changing anything, unless `round` is the
+    /// current round.
+    pub fn release(&self, round: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.round != round {
+            return false;
+        }
+        state.count += 1;
+        drop(state);
+        self.cvar.notify_all();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoundSemaphore;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_round_isolation() {
+        let s = Arc::new(RoundSemaphore::new(1));
+        assert!(s.acquire(0));
+
+        // A waiter for round 0 gives up when the round ends, rather than
+        // taking round 1's resource.
+        let s2 = s.clone();
+        let stale = thread::spawn(move || s2.acquire(0));
+        // A waiter for round 1 waits for it to begin.
+        let s3 = s.clone();
+        let early = thread::spawn(move || s3.acquire(1));
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(s.next_round(), 1);
+        assert!(!stale.join().unwrap());
+        assert!(early.join().unwrap());
+
+        // Round 0's holder finishing doesn't free anything in round 1.
+        assert!(!s.release(0));
+        assert_eq!(s.available_permits(), 0);
+        assert!(s.release(1));
+        assert_eq!(s.available_permits(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use {Semaphore, SemaphoreGuard};
+
+/// A semaphore for resources that are handed out in fractions, such as half
+/// of a GPU.
+///
+/// Underneath, it is a `Semaphore` counting in fixed-point units: with a
+/// scale of 100, each whole resource is 100 units. Fractional amounts are
+/// converted to units by `to_units`, which rounds up, so an acquisition
+/// never takes less than it asked for.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::ScaledSemaphore;
+///
+/// // Two GPUs, accounted in hundredths.
+/// let gpus = ScaledSemaphore::new(2, 100);
+/// let _half = gpus.acquire_fractional(0.5);
+/// let _third = gpus.acquire_fractional(1.0 / 3.0); // rounded up to 0.34
+/// assert_eq!(gpus.available(), 1.16);
+/// assert!(gpus.try_acquire_fractional(1.2).is_none());
+/// ```
+pub struct ScaledSemaphore {
+    sem: Semaphore,
+    scale: isize,
+}
+
+impl ScaledSemaphore {
+    /// Creates a semaphore with `count` whole resources, each divisible into
+    /// `scale` units.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` isn't positive, or if `count` resources are too many
+    /// units to count.
+    pub fn new(count: isize, scale: isize) -> ScaledSemaphore {
+        assert!(scale > 0, "the scale must be positive, not {}", scale);
+        let units = match count.checked_mul(scale) {
+            Some(units) => units,
+            None => panic!(
+                "{} resources at a scale of {} overflow the count",
+                count, scale
+            ),
+        };
+        ScaledSemaphore {
+            sem: Semaphore::new(units),
+            scale,
+        }
+    }
+
+    /// Returns how many units each whole resource is divided into.
+    pub fn scale(&self) -> isize {
+        self.scale
+    }
+
+    /// Returns the underlying semaphore, which counts units.
+    pub fn as_semaphore(&self) -> &Semaphore {
+        &self.sem
+    }
+
+    /// Returns how much is available, in whole resources.
+    pub fn available(&self) -> f64 {
+        self.sem.available_permits() as f64 / self.scale as f64
+    }
+
+    /// Converts `amount` of a resource to units, rounding up to the next
+    /// whole unit.
+    ///
+    /// Amounts that are a whole number of units apart from floating-point
+    /// error, such as 1.1 at a scale of 100, which is a hair over 110 units
+    /// as an `f64`, count as that many units rather than one more.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `amount` is negative or not a number, or if it is too many
+    /// units to count.
+    pub fn to_units(&self, amount: f64) -> isize {
+        assert!(
+            amount >= 0.0,
+            "cannot acquire a negative or undefined amount ({})",
+            amount
+        );
+        let scaled = amount * self.scale as f64;
+        let nearest = scaled.round();
+        let units = if (scaled - nearest).abs() <= nearest * 4.0 * f64::EPSILON {
+            nearest
+        } else {
+            scaled.ceil()
+        };
+        // `isize::MAX` isn't exactly representable; as an `f64` it rounds up
+        // to a power of two that is already out of range.
+        if units >= isize::MAX as f64 {
+            panic!(
+                "{} at a scale of {} overflows the count",
+                amount, self.scale
+            );
+        }
+        units as isize
+    }
+
+    /// Acquires `amount` of a resource, rounded up to whole units, blocking
+    /// the current thread until that much is available.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `to_units`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_fractional(&self, amount: f64) -> SemaphoreGuard {
+        self.sem.access_many(self.to_units(amount))
+    }
+
+    /// Acquires `amount` of a resource, rounded up to whole units, if that
+    /// much is available, without blocking.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `to_units`.
+    #[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+    pub fn try_acquire_fractional(&self, amount: f64) -> Option<SemaphoreGuard> {
+        self.sem.try_access_many(self.to_units(amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScaledSemaphore;
+
+    #[test]
+    fn test_scaled_rounding() {
+        let sem = ScaledSemaphore::new(1, 100);
+        assert_eq!(sem.to_units(0.0), 0);
+        assert_eq!(sem.to_units(0.001), 1);
+        assert_eq!(sem.to_units(0.005), 1);
+        assert_eq!(sem.to_units(0.0101), 2);
+        // Floating-point error doesn't cost an extra unit.
+        assert_eq!(sem.to_units(1.1), 110);
+        assert_eq!(sem.to_units(0.57), 57);
+        assert_eq!(sem.to_units(1.0 / 3.0), 34);
+
+        let guard = sem.acquire_fractional(0.255);
+        assert_eq!(sem.as_semaphore().available_permits(), 74);
+        assert!(sem.try_acquire_fractional(0.75).is_none());
+        drop(guard);
+        assert_eq!(sem.available(), 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows the count")]
+    fn test_scaled_overflow() {
+        let sem = ScaledSemaphore::new(1, 1000);
+        sem.to_units(isize::MAX as f64 / 1000.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "negative or undefined")]
+    fn test_scaled_nan() {
+        ScaledSemaphore::new(1, 100).to_units(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflow the count")]
+    fn test_scaled_capacity_overflow() {
+        ScaledSemaphore::new(isize::MAX / 10, 100);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A fixed-size histogram of how long blocking acquisitions waited.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// One bucket for zero, then one per power of two of nanoseconds.
+const BUCKETS: usize = 65;
+
+/// A summary of the waits recorded by a semaphore, from
+/// `Semaphore::wait_histogram`.
+///
+/// Percentiles are rounded up to the bucket they fall in, so they may
+/// overestimate a wait by up to a factor of two, but never exceed `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WaitStats {
+    /// The number of acquisitions recorded.
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// The longest wait recorded, exactly.
+    pub max: Duration,
+}
+
+pub struct Histogram {
+    /// `buckets[i]` counts waits of less than `2^i` nanoseconds that didn't
+    /// fit in a lower bucket.
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::from_secs(0),
+        }
+    }
+
+    pub fn record(&mut self, wait: Duration) {
+        // Waits of 584 years or more share the last bucket.
+        let nanos = u64::try_from(wait.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[(64 - nanos.leading_zeros()) as usize] += 1;
+        self.count += 1;
+        if wait > self.max {
+            self.max = wait;
+        }
+    }
+
+    pub fn snapshot(&self) -> WaitStats {
+        WaitStats {
+            count: self.count,
+            p50: self.percentile(50),
+            p90: self.percentile(90),
+            p99: self.percentile(99),
+            max: self.max,
+        }
+    }
+
+    fn percentile(&self, percent: u64) -> Duration {
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen * 100 >= self.count * percent {
+                let upper = if i == 64 { u64::MAX } else { (1 << i) - 1 };
+                return Duration::from_nanos(upper).min(self.max);
+            }
+        }
+        unreachable!("every recorded wait is in a bucket")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+    use std::time::Duration;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut h = Histogram::new();
+        assert_eq!(h.snapshot().p99, Duration::from_secs(0));
+        for _ in 0..90 {
+            h.record(Duration::from_nanos(0));
+        }
+        for _ in 0..9 {
+            h.record(Duration::from_micros(1));
+        }
+        h.record(Duration::from_millis(5));
+
+        let stats = h.snapshot();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.p50, Duration::from_secs(0));
+        assert_eq!(stats.p90, Duration::from_secs(0));
+        // 1000ns lands in the bucket below 1024ns.
+        assert_eq!(stats.p99, Duration::from_nanos(1023));
+        assert_eq!(stats.max, Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ops::Deref;
+
+use Semaphore;
+
+/// A share of a semaphore's resources, carved out by `Semaphore::sub_pool`
+/// and usable as a semaphore of its own.
+///
+/// Acquisitions from the sub-pool and releases to it never touch the parent.
+/// When it is dropped, its whole capacity goes back to the parent, so between
+/// them the two always account for the same resources.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::Semaphore;
+///
+/// let sem = Semaphore::new(4);
+/// {
+///     let sub = sem.sub_pool(3);
+///     assert_eq!(sem.available_permits(), 1);
+///     let _guard = sub.access_many(2);
+///     assert_eq!(sub.available_permits(), 1);
+/// } // the sub-pool's 3 resources go back here
+/// assert_eq!(sem.available_permits(), 4);
+/// ```
+pub struct SubSemaphore<'a> {
+    parent: &'a Semaphore,
+    pool: Semaphore,
+    capacity: isize,
+}
+
+impl<'a> SubSemaphore<'a> {
+    pub(crate) fn new(parent: &'a Semaphore, capacity: isize) -> SubSemaphore<'a> {
+        parent.acquire_many(capacity);
+        SubSemaphore {
+            parent,
+            pool: Semaphore::bounded(capacity, capacity),
+            capacity,
+        }
+    }
+
+    /// Returns how many of the parent's resources the sub-pool holds.
+    pub fn capacity(&self) -> isize {
+        self.capacity
+    }
+}
+
+impl<'a> Deref for SubSemaphore<'a> {
+    type Target = Semaphore;
+
+    fn deref(&self) -> &Semaphore {
+        &self.pool
+    }
+}
+
+impl<'a> Drop for SubSemaphore<'a> {
+    /// Waits for everything acquired from the sub-pool to be released, then
+    /// gives its capacity back to the parent.
+    fn drop(&mut self) {
+        self.pool.acquire_many(self.capacity);
+        // Give the capacity back the way a guard would, even if the parent
+        // has been closed.
+        self.parent.inner.release_many(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use Semaphore;
+
+    #[test]
+    fn test_sub_pool_conserves_resources() {
+        let sem = Arc::new(Semaphore::new(5));
+        let sub = sem.sub_pool(3);
+        assert_eq!(sub.capacity(), 3);
+        let nested = sub.sub_pool(2);
+        assert_eq!((sem.available_permits(), sub.available_permits()), (2, 1));
+
+        // Dropping a sub-pool waits for what was acquired from it.
+        let guard = nested.access_many(2);
+        let sem2 = sem.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(sem2.available_permits(), 2);
+            drop(guard);
+        });
+        drop(nested);
+        t.join().unwrap();
+        assert_eq!(sub.available_permits(), 3);
+        drop(sub);
+        assert_eq!(sem.available_permits(), 5);
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The synchronization primitives behind `Semaphore`.
+//!
+//! Building with `RUSTFLAGS="--cfg loom"` swaps them for loom's, so the model
+//! tests in `tests/loom.rs` can explore every interleaving of the acquire and
+//! release paths.
+
+#[cfg(loom)]
+pub use loom::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(not(loom))]
+pub use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+#[cfg(all(loom, feature = "testing"))]
+pub use loom::thread::yield_now;
+#[cfg(all(not(loom), feature = "testing"))]
+pub use std::thread::yield_now;
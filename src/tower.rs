@@ -0,0 +1,190 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Concurrency limiting middleware for `tower` service stacks.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tower_service::Service;
+
+use sync::Arc;
+use {Access, RawSemaphore, Semaphore, SemaphoreGuard};
+
+/// A `tower` service which holds a resource of a semaphore for every call to
+/// the service it wraps, from `poll_ready` until the response future
+/// completes or is dropped.
+///
+/// Every clone of the service shares the semaphore, so the semaphore's count
+/// bounds the number of requests in flight across all of them. A permit
+/// acquired by `poll_ready` is held until the next `call`, as `tower`
+/// expects, so a service that is made ready but never called keeps its
+/// permit.
+pub struct ConcurrencyLimit<S> {
+    inner: S,
+    sem: Arc<RawSemaphore>,
+    /// The acquisition in progress, if `poll_ready` had to wait. It's boxed
+    /// to stay pinned while the service moves.
+    acquiring: Option<Pin<Box<Access>>>,
+    /// The permit for the next call, once `poll_ready` has acquired it.
+    permit: Option<SemaphoreGuard>,
+}
+
+/// The response future of a `ConcurrencyLimit` service, which releases its
+/// permit when it completes or is dropped.
+pub struct ResponseFuture<F> {
+    inner: F,
+    /// The permit for the call, until the response is ready.
+    permit: Option<SemaphoreGuard>,
+}
+
+impl<S> ConcurrencyLimit<S> {
+    /// Wraps `inner` so that every call holds a resource of `sem`.
+    pub fn new(inner: S, sem: &Semaphore) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit {
+            inner,
+            sem: Arc::clone(&sem.inner),
+            acquiring: None,
+            permit: None,
+        }
+    }
+
+    /// Unwraps the inner service, releasing any permit held for it.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Clone> Clone for ConcurrencyLimit<S> {
+    /// Clones the inner service; the clone shares the semaphore but has to
+    /// acquire its own permit.
+    fn clone(&self) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit {
+            inner: self.inner.clone(),
+            sem: Arc::clone(&self.sem),
+            acquiring: None,
+            permit: None,
+        }
+    }
+}
+
+impl<S, Request> Service<Request> for ConcurrencyLimit<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), S::Error>> {
+        if self.permit.is_none() {
+            let sem = &self.sem;
+            let access = self
+                .acquiring
+                .get_or_insert_with(|| Box::pin(Access::new(sem, 1)));
+            match access.as_mut().poll(cx) {
+                Poll::Ready(permit) => {
+                    self.acquiring = None;
+                    self.permit = Some(permit);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> ResponseFuture<S::Future> {
+        let permit = self
+            .permit
+            .take()
+            .expect("ConcurrencyLimit called before poll_ready returned Ready");
+        ResponseFuture {
+            inner: self.inner.call(request),
+            permit: Some(permit),
+        }
+    }
+}
+
+impl<F: Future> Future for ResponseFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // SAFETY: `inner` is pinned whenever `self` is: it's never moved out
+        // of, and `ResponseFuture` has no `Drop` impl that could move it.
+        // `permit` isn't pinned, so it may be taken out.
+        let this = unsafe { self.get_unchecked_mut() };
+        let output = unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx);
+        if output.is_ready() {
+            this.permit = None;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{self, Ready};
+    use std::task::{Context, Poll};
+
+    use tower_service::Service;
+
+    use super::ConcurrencyLimit;
+    use future::tests::{block_on, noop_waker, poll_once};
+    use Semaphore;
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = ();
+        type Future = Ready<Result<u32, ()>>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: u32) -> Ready<Result<u32, ()>> {
+            future::ready(Ok(request))
+        }
+    }
+
+    fn poll_ready(service: &mut ConcurrencyLimit<Echo>) -> Poll<Result<(), ()>> {
+        service.poll_ready(&mut Context::from_waker(&noop_waker()))
+    }
+
+    #[test]
+    fn test_concurrency_limit_holds_permit_per_call() {
+        let sem = Semaphore::new(1);
+        let mut a = ConcurrencyLimit::new(Echo, &sem);
+        let mut b = a.clone();
+
+        assert_eq!(poll_ready(&mut a), Poll::Ready(Ok(())));
+        assert_eq!(sem.available_permits(), 0);
+        let mut response = a.call(7);
+        assert_eq!(poll_ready(&mut b), Poll::Pending);
+        assert_eq!(poll_once(&mut response), Poll::Ready(Ok(7)));
+        // The permit is released as soon as the response is ready.
+        assert_eq!(sem.available_permits(), 1);
+        drop(response);
+
+        assert_eq!(poll_ready(&mut b), Poll::Ready(Ok(())));
+        assert_eq!(block_on(b.call(8)), Ok(8));
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_concurrency_limit_dropped_response_releases() {
+        let sem = Semaphore::new(1);
+        let mut service = ConcurrencyLimit::new(Echo, &sem);
+        assert_eq!(poll_ready(&mut service), Poll::Ready(Ok(())));
+        drop(service.call(1));
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
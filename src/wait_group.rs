@@ -0,0 +1,169 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use sync::Arc;
+use Semaphore;
+
+/// Tracks outstanding work, so a thread can wait for all of it to be done.
+///
+/// This is a semaphore whose count is the negated amount of outstanding work:
+/// `add` takes from it, finishing a piece of work gives back, and `wait`
+/// blocks until the count is back to zero. Work is either counted with `add`
+/// and marked done with `done`, or each piece is given a guard by `worker`
+/// that marks it done when dropped, even if the worker panics.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::WaitGroup;
+/// use std::thread;
+///
+/// let group = WaitGroup::new();
+/// for _ in 0..4 {
+///     let guard = group.worker();
+///     thread::spawn(move || {
+///         // ... do the work ...
+///         drop(guard);
+///     });
+/// }
+/// group.wait();
+/// assert_eq!(group.outstanding(), 0);
+/// ```
+pub struct WaitGroup {
+    sem: Semaphore,
+}
+
+/// Marks a piece of a `WaitGroup`'s work done when dropped.
+///
+/// Created by `WaitGroup::worker`. The guard doesn't borrow the group, so it
+/// can be moved to the thread doing the work.
+#[must_use = "the work is marked done immediately if the guard is not bound to a variable"]
+pub struct WaitGroupGuard {
+    sem: Semaphore,
+}
+
+impl WaitGroup {
+    /// Creates a wait group with no outstanding work.
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            sem: Semaphore::new(0),
+        }
+    }
+
+    /// Adds `n` pieces of outstanding work.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative, or if the outstanding work would overflow.
+    pub fn add(&self, n: isize) {
+        assert!(n >= 0, "cannot add a negative amount of work ({})", n);
+        let mut state = self.sem.inner.lock.lock().unwrap();
+        state.count = match state.count.checked_sub(n) {
+            Some(count) => count,
+            None => {
+                drop(state);
+                panic!("adding {} pieces of work would overflow the wait group", n);
+            }
+        };
+        self.sem.inner.unlock(state);
+    }
+
+    /// Marks a piece of work done.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no outstanding work.
+    pub fn done(&self) {
+        done(&self.sem);
+    }
+
+    /// Adds a piece of outstanding work, returning a guard that marks it done
+    /// when dropped.
+    pub fn worker(&self) -> WaitGroupGuard {
+        self.add(1);
+        WaitGroupGuard {
+            sem: Semaphore {
+                inner: Arc::clone(&self.sem.inner),
+            },
+        }
+    }
+
+    /// Returns how much work is outstanding.
+    pub fn outstanding(&self) -> isize {
+        -self.sem.available_permits()
+    }
+
+    /// Blocks the current thread until no work is outstanding.
+    pub fn wait(&self) {
+        let raw = &self.sem.inner;
+        let mut state = raw.lock.lock().unwrap();
+        // Be woken by every change to the count, not just those an acquirer
+        // could use.
+        state.watchers += 1;
+        let (mut state, _) = raw.wait_until(state, None, |state| state.count >= 0);
+        state.watchers -= 1;
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+impl Drop for WaitGroupGuard {
+    fn drop(&mut self) {
+        done(&self.sem);
+    }
+}
+
+/// Marks a piece of the work counted by `sem` done.
+fn done(sem: &Semaphore) {
+    let mut state = sem.inner.lock.lock().unwrap();
+    if state.count >= 0 {
+        drop(state);
+        panic!("done called with no outstanding work");
+    }
+    state.count += 1;
+    sem.inner.unlock(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WaitGroup;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_group_waits_for_workers() {
+        let group = WaitGroup::new();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..5)
+            .map(|i| {
+                let (guard, finished) = (group.worker(), finished.clone());
+                thread::spawn(move || {
+                    let _guard = guard;
+                    thread::sleep(Duration::from_millis(i * 2));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        group.add(1);
+        assert_eq!(group.outstanding(), 6);
+        group.done();
+
+        group.wait();
+        assert_eq!(finished.load(Ordering::SeqCst), 5);
+        assert_eq!(group.outstanding(), 0);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
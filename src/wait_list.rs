@@ -0,0 +1,281 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The intrusive list of wakers behind async acquisitions.
+//!
+//! Every `Access` future owns a `Node`, which is linked into its semaphore's
+//! `List` while the future waits, so that waiting doesn't allocate. A list
+//! and its nodes are only ever touched with the semaphore locked.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomPinned;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::task::Waker;
+
+/// How many wakers `List::take_wakers` takes at a time. They are woken with
+/// the semaphore unlocked, and holding them in a `WakeList` of fixed size
+/// keeps that from allocating.
+///
+/// The slots are left uninitialized until used, as a wakeup mostly wakes only
+/// a task or two and clearing every slot of the batch each time would cost
+/// more than the wakeup itself.
+const BATCH: usize = 32;
+
+/// A waiting acquisition's entry in a `List`, stored in the acquisition's
+/// pinned future.
+pub struct Node {
+    links: UnsafeCell<Links>,
+    _pinned: PhantomPinned,
+}
+
+struct Links {
+    /// The task to wake, which is `Some` exactly while the node is linked.
+    waker: Option<Waker>,
+    prev: *const Node,
+    next: *const Node,
+}
+
+// SAFETY: the links are only accessed through the `List` the node is in,
+// with the semaphore locked.
+unsafe impl Send for Node {}
+unsafe impl Sync for Node {}
+
+impl Node {
+    pub fn new() -> Node {
+        Node {
+            links: UnsafeCell::new(Links {
+                waker: None,
+                prev: ptr::null(),
+                next: ptr::null(),
+            }),
+            _pinned: PhantomPinned,
+        }
+    }
+}
+
+/// The linked nodes, oldest first.
+pub struct List {
+    head: *const Node,
+    tail: *const Node,
+    len: usize,
+}
+
+// SAFETY: the nodes are only accessed through the list, and `Node` is `Send`.
+unsafe impl Send for List {}
+
+impl List {
+    pub fn new() -> List {
+        List {
+            head: ptr::null(),
+            tail: ptr::null(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Links `node` at the back of the list to be woken with `waker`, or, if
+    /// it's linked already, replaces its waker unless they wake the same
+    /// task.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be pinned and not linked into any other list, and it must
+    /// be removed with `remove` before it is dropped.
+    pub unsafe fn register(&mut self, node: &Node, waker: &Waker) {
+        let links = &mut *node.links.get();
+        if let Some(ref mut current) = links.waker {
+            if !current.will_wake(waker) {
+                *current = waker.clone();
+            }
+            return;
+        }
+        links.waker = Some(waker.clone());
+        links.prev = self.tail;
+        links.next = ptr::null();
+        match self.tail.as_ref() {
+            Some(tail) => (*tail.links.get()).next = node,
+            None => self.head = node,
+        }
+        self.tail = node;
+        self.len += 1;
+    }
+
+    /// Unlinks `node`, if it's linked.
+    ///
+    /// # Safety
+    ///
+    /// `node` must not be linked into any other list.
+    pub unsafe fn remove(&mut self, node: &Node) {
+        let links = &mut *node.links.get();
+        if links.waker.take().is_none() {
+            return;
+        }
+        match links.prev.as_ref() {
+            Some(prev) => (*prev.links.get()).next = links.next,
+            None => self.head = links.next,
+        }
+        match links.next.as_ref() {
+            Some(next) => (*next.links.get()).prev = links.prev,
+            None => self.tail = links.prev,
+        }
+        links.prev = ptr::null();
+        links.next = ptr::null();
+        self.len -= 1;
+    }
+
+    /// Unlinks nodes from the front of the list into `batch` until either is
+    /// exhausted, taking their wakers.
+    pub fn take_wakers(&mut self, batch: &mut WakeList) {
+        while batch.len < BATCH {
+            // SAFETY: linked nodes are alive, as `register` requires them to
+            // be removed before they are dropped.
+            let links = match unsafe { self.head.as_ref() } {
+                Some(head) => unsafe { &mut *head.links.get() },
+                None => return,
+            };
+            if let Some(waker) = links.waker.take() {
+                batch.wakers[batch.len] = MaybeUninit::new(waker);
+                batch.len += 1;
+            }
+            self.head = links.next;
+            match unsafe { self.head.as_ref() } {
+                Some(next) => unsafe { (*next.links.get()).prev = ptr::null() },
+                None => self.tail = ptr::null(),
+            }
+            links.next = ptr::null();
+            self.len -= 1;
+        }
+    }
+}
+
+/// Wakers taken from a `List` by `List::take_wakers`, to be woken once the
+/// semaphore is unlocked.
+pub struct WakeList {
+    /// The wakers taken, of which the first `len` are initialized.
+    wakers: [MaybeUninit<Waker>; BATCH],
+    len: usize,
+}
+
+impl WakeList {
+    pub fn new() -> WakeList {
+        WakeList {
+            // SAFETY: an array of `MaybeUninit` needs no initialization.
+            wakers: unsafe { MaybeUninit::uninit().assume_init() },
+            len: 0,
+        }
+    }
+
+    /// Whether the batch is full, in which case the list may have more
+    /// wakers left to take.
+    pub fn is_full(&self) -> bool {
+        self.len == BATCH
+    }
+
+    /// Wakes every task taken, emptying the batch.
+    pub fn wake_all(&mut self) {
+        let len = self.len;
+        // Should a wakeup panic, the wakers after it are leaked, not read
+        // again.
+        self.len = 0;
+        for waker in &self.wakers[..len] {
+            // SAFETY: the first `len` wakers are initialized, and each is read
+            // only once.
+            unsafe { waker.as_ptr().read() }.wake();
+        }
+    }
+}
+
+impl Drop for WakeList {
+    fn drop(&mut self) {
+        for waker in &mut self.wakers[..self.len] {
+            // SAFETY: the first `len` wakers are initialized.
+            unsafe { waker.as_mut_ptr().drop_in_place() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    use super::{List, Node, WakeList, BATCH};
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counting_waker() -> (Arc<CountingWaker>, Waker) {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        (counter.clone(), Waker::from(counter))
+    }
+
+    fn nodes(n: usize) -> Vec<Pin<Box<Node>>> {
+        (0..n).map(|_| Box::pin(Node::new())).collect()
+    }
+
+    #[test]
+    fn test_wait_list_remove_anywhere() {
+        let (counter, waker) = counting_waker();
+        let nodes = nodes(3);
+        let mut list = List::new();
+        unsafe {
+            for node in &nodes {
+                list.register(node, &waker);
+            }
+            // Registering again only replaces the waker.
+            list.register(&nodes[0], &waker);
+            assert_eq!(list.len(), 3);
+            list.remove(&nodes[1]);
+            list.remove(&nodes[1]);
+            assert_eq!(list.len(), 2);
+        }
+
+        let mut batch = WakeList::new();
+        list.take_wakers(&mut batch);
+        assert!(list.len() == 0 && !batch.is_full());
+        batch.wake_all();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+        unsafe {
+            // Taken nodes may be linked again, or removed to no effect.
+            list.remove(&nodes[0]);
+            list.register(&nodes[2], &waker);
+            list.remove(&nodes[2]);
+        }
+        assert_eq!(list.len(), 0);
+    }
+
+    #[test]
+    fn test_wait_list_takes_in_batches() {
+        let (counter, waker) = counting_waker();
+        let nodes = nodes(BATCH + 1);
+        let mut list = List::new();
+        for node in &nodes {
+            unsafe { list.register(node, &waker) };
+        }
+        let mut batch = WakeList::new();
+        list.take_wakers(&mut batch);
+        assert!(batch.is_full());
+        assert_eq!(list.len(), 1);
+        batch.wake_all();
+        list.take_wakers(&mut batch);
+        batch.wake_all();
+        assert_eq!(list.len(), 0);
+        assert_eq!(counter.0.load(Ordering::SeqCst), BATCH + 1);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::Mutex;
+
+use {Semaphore, SemaphoreGuard};
+
+/// A semaphore whose permits are numbered slots, handed out most recently
+/// released first.
+///
+/// When each slot stands for something that stays warm while in use, such as
+/// a buffer in a cache-sensitive pool, reusing the slot that was just freed
+/// keeps the working set small.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::WarmSemaphore;
+///
+/// let sem = WarmSemaphore::new(4);
+/// let (slot, guard) = sem.acquire_warm();
+/// drop(guard);
+/// // The slot that was just released is reused first.
+/// assert_eq!(sem.acquire_warm().0, slot);
+/// ```
+pub struct WarmSemaphore {
+    sem: Semaphore,
+    /// Free slots, with the most recently released on top.
+    free: Mutex<Vec<usize>>,
+}
+
+/// An RAII guard for a slot of a `WarmSemaphore`, which frees the slot when
+/// dropped.
+pub struct SlotGuard<'a> {
+    owner: &'a WarmSemaphore,
+    slot: usize,
+    _guard: SemaphoreGuard,
+}
+
+impl WarmSemaphore {
+    /// Creates a semaphore with `slots` slots, numbered from 0.
+    pub fn new(slots: usize) -> WarmSemaphore {
+        let len = slots as isize;
+        WarmSemaphore {
+            sem: Semaphore::bounded(len, len),
+            // Reversed, so that a fresh semaphore hands out slot 0 first.
+            free: Mutex::new((0..slots).rev().collect()),
+        }
+    }
+
+    /// Returns the number of free slots.
+    pub fn available(&self) -> isize {
+        self.sem.available_permits()
+    }
+
+    /// Acquires the most recently released slot, blocking the current thread
+    /// until one is free.
+    ///
+    /// Returns the slot's index along with the guard holding it.
+    #[must_use = "the slot is released immediately if the guard is not bound to a variable"]
+    pub fn acquire_warm(&self) -> (usize, SlotGuard<'_>) {
+        let guard = self.take(self.sem.access());
+        (guard.slot, guard)
+    }
+
+    /// Acquires the most recently released slot if one is free, without
+    /// blocking.
+    #[must_use = "the slot is released immediately if the guard is not bound to a variable"]
+    pub fn try_acquire_warm(&self) -> Option<(usize, SlotGuard<'_>)> {
+        self.sem.try_access().map(|guard| {
+            let guard = self.take(guard);
+            (guard.slot, guard)
+        })
+    }
+
+    fn take(&self, guard: SemaphoreGuard) -> SlotGuard<'_> {
+        // Holding a permit guarantees there is a free slot to pop.
+        let slot = self.free.lock().unwrap().pop();
+        SlotGuard {
+            owner: self,
+            slot: slot.expect("slot permit held with no slot free"),
+            _guard: guard,
+        }
+    }
+}
+
+impl<'a> SlotGuard<'a> {
+    /// Returns the index of the slot this guard holds.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+impl<'a> Drop for SlotGuard<'a> {
+    fn drop(&mut self) {
+        // Free the slot before `_guard` releases its permit, so whoever is
+        // woken by the release finds it.
+        self.owner.free.lock().unwrap().push(self.slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WarmSemaphore;
+
+    #[test]
+    fn test_warm_reuses_latest_slot() {
+        let sem = WarmSemaphore::new(3);
+        let (a, ga) = sem.acquire_warm();
+        let (b, gb) = sem.acquire_warm();
+        assert_eq!((a, b), (0, 1));
+        drop(ga);
+        drop(gb);
+        assert_eq!(sem.acquire_warm().0, 1);
+
+        let held: Vec<_> = (0..3).map(|_| sem.acquire_warm().1).collect();
+        assert!(sem.try_acquire_warm().is_none());
+        let mut slots: Vec<_> = held.iter().map(|g| g.slot()).collect();
+        slots.sort();
+        assert_eq!(slots, vec![0, 1, 2]);
+        drop(held);
+        assert_eq!(sem.available(), 3);
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use {acquire_all_n, Semaphore, SemaphoreGuard};
+
+/// A semaphore for tasks of different weights, which limits both their total
+/// weight and how many run at once.
+///
+/// Each task takes its weight from a weight budget, like `Semaphore`, and
+/// one of a fixed number of task slots, however light it is. It is made of
+/// two semaphores, acquired in the same order by every task so that tasks
+/// can't deadlock each other.
+///
+/// # Examples
+///
+/// ```
+/// use multi_semaphore::WeightedSemaphore;
+///
+/// // A weight budget of 4, and at most 2 tasks at a time.
+/// let sem = WeightedSemaphore::new(4, 2);
+/// let _a = sem.acquire(1);
+/// let _b = sem.acquire(1);
+/// // There's weight to spare but no slot, so another task has to wait.
+/// assert_eq!((sem.available_weight(), sem.available_slots()), (2, 0));
+/// assert!(sem.try_acquire(1).is_none());
+/// ```
+pub struct WeightedSemaphore {
+    slots: Semaphore,
+    weight: Semaphore,
+}
+
+/// An RAII guard for a task's weight and slot in a `WeightedSemaphore`,
+/// which releases both when dropped.
+#[must_use = "the permit is released immediately if the guard is not bound to a variable"]
+pub struct WeightedGuard {
+    // Released in declaration order: the weight, then the slot.
+    weight: SemaphoreGuard,
+    _slot: SemaphoreGuard,
+}
+
+impl WeightedSemaphore {
+    /// Creates a semaphore with a budget of `weight` and `tasks` task slots.
+    pub fn new(weight: isize, tasks: isize) -> WeightedSemaphore {
+        WeightedSemaphore {
+            slots: Semaphore::new(tasks),
+            weight: Semaphore::new(weight),
+        }
+    }
+
+    /// Returns how much of the weight budget is available.
+    pub fn available_weight(&self) -> isize {
+        self.weight.available_permits()
+    }
+
+    /// Returns how many task slots are available.
+    pub fn available_slots(&self) -> isize {
+        self.slots.available_permits()
+    }
+
+    /// Acquires `weight` of the budget and a task slot, blocking the current
+    /// thread until both are available.
+    ///
+    /// A task slot stays held while waiting for the weight, and so counts
+    /// against the limit on tasks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is negative.
+    pub fn acquire(&self, weight: isize) -> WeightedGuard {
+        let [slot, weight] = acquire_all_n([(&self.slots, 1), (&self.weight, weight)]);
+        WeightedGuard {
+            weight,
+            _slot: slot,
+        }
+    }
+
+    /// Acquires `weight` of the budget and a task slot if both are available,
+    /// without blocking.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is negative.
+    pub fn try_acquire(&self, weight: isize) -> Option<WeightedGuard> {
+        let slot = self.slots.try_access()?;
+        let weight = self.weight.try_access_many(weight)?;
+        Some(WeightedGuard {
+            weight,
+            _slot: slot,
+        })
+    }
+}
+
+impl WeightedGuard {
+    /// Returns the weight this guard holds.
+    pub fn weight(&self) -> isize {
+        self.weight.amount()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WeightedSemaphore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_weighted_slots_cap_light_tasks() {
+        let sem = Arc::new(WeightedSemaphore::new(100, 3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..10)
+            .map(|_| {
+                let (sem, running, most) = (sem.clone(), running.clone(), most.clone());
+                thread::spawn(move || {
+                    let guard = sem.acquire(1);
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    guard.weight()
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), 1);
+        }
+        assert!(most.load(Ordering::SeqCst) <= 3);
+        assert_eq!((sem.available_weight(), sem.available_slots()), (100, 3));
+    }
+
+    #[test]
+    fn test_weighted_try_acquire() {
+        let sem = WeightedSemaphore::new(3, 2);
+        let heavy = sem.try_acquire(3).unwrap();
+        // A slot is free but no weight, and the slot isn't kept.
+        assert!(sem.try_acquire(1).is_none());
+        assert_eq!(sem.available_slots(), 1);
+        drop(heavy);
+        assert!(sem.try_acquire(1).is_some());
+    }
+}
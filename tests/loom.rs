@@ -0,0 +1,100 @@
+// Model tests exploring every interleaving of the acquire and release paths.
+//
+// Run with `RUSTFLAGS="--cfg loom" cargo test --release --test loom`.
+#![cfg(loom)]
+
+extern crate loom;
+extern crate multi_semaphore;
+
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+use multi_semaphore::Semaphore;
+
+#[test]
+fn release_wakes_blocked_acquire() {
+    loom::model(|| {
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.release());
+        s.acquire();
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    });
+}
+
+#[test]
+fn guards_exclude_each_other() {
+    loom::model(|| {
+        let s = Arc::new(Semaphore::new(1));
+        let inside = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let (s, inside) = (s.clone(), inside.clone());
+                thread::spawn(move || {
+                    let _g = s.access();
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 1);
+    });
+}
+
+#[test]
+fn acquire_many_waits_for_every_release() {
+    loom::model(|| {
+        let s = Arc::new(Semaphore::new(0));
+        let releasers: Vec<_> = (0..2)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || s.release())
+            })
+            .collect();
+        s.acquire_many(2);
+        for t in releasers {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), 0);
+    });
+}
+
+#[test]
+fn competing_acquirers_both_finish() {
+    loom::model(|| {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            s2.acquire();
+            s2.release();
+        });
+        s.acquire_many(1);
+        s.release();
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 1);
+    });
+}
+
+#[test]
+fn budgeted_wakeup_reaches_a_waiter_that_fits() {
+    loom::model(|| {
+        let s = Arc::new(Semaphore::new(0));
+        let big = {
+            let s = s.clone();
+            thread::spawn(move || s.acquire_many(2))
+        };
+        let small = {
+            let s = s.clone();
+            thread::spawn(move || s.acquire())
+        };
+        s.release();
+        small.join().unwrap();
+        s.release_many(2);
+        big.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    });
+}
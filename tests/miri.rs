@@ -0,0 +1,99 @@
+// Small, fast tests of the atomic and unsafe code paths, sized to run under
+// Miri so it can check them for undefined behavior and data races:
+//
+//     cargo +nightly miri test --test miri
+//     cargo +nightly miri test --all-features --test miri
+//
+// The pin projection in `ConcurrencyLimit`'s response future is covered by
+// its own tests: `cargo +nightly miri test --all-features --lib tower`. All of
+// these pass as ordinary tests too.
+
+extern crate multi_semaphore;
+
+use std::sync::Arc;
+use std::thread;
+
+use multi_semaphore::{Backend, Semaphore};
+
+const THREADS: usize = 3;
+const ROUNDS: usize = 20;
+
+#[test]
+fn lockfree_acquire_release_across_threads() {
+    let sem = Arc::new(Semaphore::new_lockfree(1));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let sem = sem.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    let _guard = sem.access();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(sem.available_permits(), 1);
+}
+
+#[test]
+fn lockfree_try_acquire_never_overdraws() {
+    let sem = Arc::new(Semaphore::new_lockfree(2));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let sem = sem.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    if sem.try_acquire_many(2) {
+                        assert!(sem.available_permits() >= 0);
+                        sem.release_many(2);
+                    }
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(sem.available_permits(), 2);
+}
+
+#[test]
+fn count_hint_settles_after_contention() {
+    let sem = Arc::new(Semaphore::new(2));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let sem = sem.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    let _guard = sem.access();
+                    assert!(sem.available_permits() <= 1);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(sem.available_permits(), 2);
+}
+
+#[test]
+fn backends_hand_off() {
+    let backends = [
+        Backend::Std,
+        #[cfg(feature = "parking_lot")]
+        Backend::ParkingLot,
+        Backend::Spin,
+    ];
+    for &backend in &backends {
+        let sem = Arc::new(Semaphore::with_backend(0, backend));
+        let sem2 = sem.clone();
+        let t = thread::spawn(move || sem2.acquire_many(2));
+        sem.release();
+        sem.release();
+        t.join().unwrap();
+        assert_eq!(sem.available_permits(), 0);
+    }
+}